@@ -0,0 +1,265 @@
+#![feature(allocator_api)]
+#![feature(slice_ptr_get)]
+
+#![deny(warnings)]
+
+use composable_allocators::{Counters, Stats, System, TraceOp, TraceRecord, freelist_allocator_128_KiB_align_8};
+use composable_allocators::chained_stacked::ChainedStacked;
+use composable_allocators::stacked;
+use std::alloc::{Allocator, Layout};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::mem::MaybeUninit;
+use std::process::ExitCode;
+use std::ptr::NonNull;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "\
+Usage: replay <composition> <trace>
+
+Replays a trace written by `Tracing` against an allocator composition.
+
+Compositions:
+    system           System
+    freelist         freelist_allocator_128_KiB_align_8!
+    stacked          Stacked over a 16 MiB buffer
+    chained_stacked  ChainedStacked<System> with 64 KiB chunks
+";
+
+#[derive(Debug, Default)]
+struct Report {
+    records: usize,
+    failures: usize,
+    skipped: usize,
+    counters: Counters,
+    time: Duration,
+}
+
+#[derive(Default)]
+struct Blocks(HashMap<u64, Vec<(NonNull<u8>, Layout)>>);
+
+impl Blocks {
+    fn insert(&mut self, id: u64, ptr: NonNull<u8>, layout: Layout) {
+        self.0.entry(id).or_default().push((ptr, layout));
+    }
+
+    fn remove(&mut self, id: u64) -> Option<(NonNull<u8>, Layout)> {
+        let blocks = self.0.get_mut(&id)?;
+        let block = blocks.pop();
+        if blocks.is_empty() {
+            self.0.remove(&id);
+        }
+        block
+    }
+}
+
+fn replay<A: Allocator>(allocator: A, records: &[TraceRecord]) -> Report {
+    let allocator = Stats::new(allocator);
+    let mut blocks = Blocks::default();
+    let mut report = Report { records: records.len(), ..Report::default() };
+    for record in records {
+        match record.op {
+            TraceOp::Allocate | TraceOp::AllocateZeroed => {
+                let start = Instant::now();
+                let res = if record.op == TraceOp::AllocateZeroed {
+                    allocator.allocate_zeroed(record.layout)
+                } else {
+                    allocator.allocate(record.layout)
+                };
+                report.time += start.elapsed();
+                match (res, record.result) {
+                    (Ok(block), Some(Ok(id))) => blocks.insert(id, block.as_non_null_ptr(), record.layout),
+                    // The traced allocation failed, so the trace never frees the block.
+                    (Ok(block), _) => unsafe { allocator.deallocate(block.as_non_null_ptr(), record.layout) },
+                    (Err(_), _) => report.failures += 1,
+                }
+            },
+            TraceOp::Deallocate => {
+                let Some((ptr, layout)) = blocks.remove(record.block) else {
+                    report.skipped += 1;
+                    continue;
+                };
+                let start = Instant::now();
+                unsafe { allocator.deallocate(ptr, layout); }
+                report.time += start.elapsed();
+            },
+            TraceOp::Grow | TraceOp::GrowZeroed | TraceOp::Shrink => {
+                let Some((ptr, old_layout)) = blocks.remove(record.block) else {
+                    report.skipped += 1;
+                    continue;
+                };
+                let start = Instant::now();
+                let res = match record.op {
+                    TraceOp::Grow if record.layout.size() >= old_layout.size() =>
+                        unsafe { allocator.grow(ptr, old_layout, record.layout) },
+                    TraceOp::GrowZeroed if record.layout.size() >= old_layout.size() =>
+                        unsafe { allocator.grow_zeroed(ptr, old_layout, record.layout) },
+                    TraceOp::Shrink if record.layout.size() <= old_layout.size() =>
+                        unsafe { allocator.shrink(ptr, old_layout, record.layout) },
+                    _ => {
+                        blocks.insert(record.block, ptr, old_layout);
+                        report.skipped += 1;
+                        continue;
+                    },
+                };
+                report.time += start.elapsed();
+                let id = match record.result {
+                    Some(Ok(id)) => id,
+                    _ => record.block,
+                };
+                match res {
+                    Ok(block) => blocks.insert(id, block.as_non_null_ptr(), record.layout),
+                    Err(_) => {
+                        report.failures += 1;
+                        blocks.insert(id, ptr, old_layout);
+                    },
+                }
+            },
+        }
+    }
+    for (ptr, layout) in blocks.0.into_values().flatten() {
+        unsafe { allocator.deallocate(ptr, layout); }
+    }
+    report.counters = allocator.snapshot();
+    report
+}
+
+fn parse_trace(bytes: &[u8]) -> Result<Vec<TraceRecord>, String> {
+    let chunks = bytes.chunks_exact(TraceRecord::SIZE);
+    if !chunks.remainder().is_empty() {
+        return Err("truncated".to_string());
+    }
+    chunks.enumerate().map(|(i, chunk)|
+        TraceRecord::from_bytes(chunk.try_into().unwrap()).ok_or_else(|| format!("invalid record {i}"))
+    ).collect()
+}
+
+fn read_trace(path: &str) -> Result<Vec<TraceRecord>, String> {
+    let bytes = fs::read(path).map_err(|e| format!("cannot read '{path}': {e}"))?;
+    parse_trace(&bytes).map_err(|e| format!("'{path}': {e}"))
+}
+
+fn run(composition: &str, records: &[TraceRecord]) -> Option<Report> {
+    match composition {
+        "system" => Some(replay(System, records)),
+        "freelist" => {
+            freelist_allocator_128_KiB_align_8!(FREELIST: Freelist);
+            Some(replay(&FREELIST, records))
+        },
+        "stacked" => {
+            let mut buf = vec![MaybeUninit::uninit(); 16 << 20];
+            Some(stacked::with_buf(&mut buf, |stacked| replay(stacked, records)))
+        },
+        "chained_stacked" => Some(replay(ChainedStacked::new(64 << 10, System), records)),
+        _ => None,
+    }
+}
+
+fn main() -> ExitCode {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let [composition, path] = &args[..] else {
+        eprint!("{USAGE}");
+        return ExitCode::FAILURE;
+    };
+    let records = match read_trace(path) {
+        Ok(records) => records,
+        Err(e) => {
+            eprintln!("replay: {e}");
+            return ExitCode::FAILURE;
+        },
+    };
+    let Some(report) = run(composition, &records) else {
+        eprintln!("replay: unknown composition '{composition}'");
+        eprint!("{USAGE}");
+        return ExitCode::FAILURE;
+    };
+    println!("records:  {}", report.records);
+    println!("failures: {}", report.failures);
+    println!("skipped:  {}", report.skipped);
+    println!("peak:     {} bytes", report.counters.peak_bytes);
+    println!("time:     {:?}", report.time);
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use composable_allocators::{TraceSink, Tracing};
+    use std::cell::{Cell, RefCell};
+
+    #[derive(Default)]
+    struct VecSink {
+        time: Cell<u64>,
+        bytes: RefCell<Vec<u8>>,
+    }
+
+    impl TraceSink for VecSink {
+        fn timestamp(&self) -> u64 {
+            self.time.set(self.time.get() + 1);
+            self.time.get()
+        }
+
+        fn write(&self, record: &[u8; TraceRecord::SIZE]) {
+            self.bytes.borrow_mut().extend_from_slice(record);
+        }
+    }
+
+    fn capture() -> (Counters, Vec<u8>) {
+        let tracing = Tracing(Stats::new(System), VecSink::default());
+        let layout = |size| Layout::from_size_align(size, 8).unwrap();
+        let mut blocks = Vec::new();
+        for i in 1 ..= 16 {
+            let block = if i % 3 == 0 {
+                tracing.allocate_zeroed(layout(i * 8))
+            } else {
+                tracing.allocate(layout(i * 8))
+            };
+            blocks.push((block.unwrap().as_non_null_ptr(), layout(i * 8)));
+        }
+        assert!(tracing.allocate(layout(isize::MAX as usize - 7)).is_err());
+        for (i, (ptr, old_layout)) in blocks.iter_mut().enumerate() {
+            let new_layout = layout(old_layout.size() * 2);
+            let block = match i % 4 {
+                0 => unsafe { tracing.grow(*ptr, *old_layout, new_layout) }.unwrap(),
+                1 => unsafe { tracing.grow_zeroed(*ptr, *old_layout, new_layout) }.unwrap(),
+                2 => unsafe { tracing.shrink(*ptr, *old_layout, layout(8)) }.unwrap(),
+                _ => continue,
+            };
+            *ptr = block.as_non_null_ptr();
+            *old_layout = if i % 4 == 2 { layout(8) } else { new_layout };
+        }
+        for (i, (ptr, layout)) in blocks.into_iter().enumerate() {
+            if i % 2 == 0 {
+                unsafe { tracing.deallocate(ptr, layout); }
+            } else {
+                // Replay frees blocks left live at the end of the trace.
+                unsafe { tracing.0.deallocate(ptr, layout); }
+            }
+        }
+        let counters = tracing.0.snapshot();
+        (counters, tracing.1.bytes.into_inner())
+    }
+
+    #[test]
+    fn replays_captured_trace() {
+        let (counters, bytes) = capture();
+        let records = parse_trace(&bytes).unwrap();
+        assert_eq!(records.len(), 16 + 1 + 12 + 8);
+        let report = replay(System, &records);
+        assert_eq!(report.records, records.len());
+        assert_eq!(report.failures, 1);
+        assert_eq!(report.skipped, 0);
+        assert_eq!(report.counters, counters);
+    }
+
+    #[test]
+    fn rejects_malformed_traces() {
+        let (_, mut bytes) = capture();
+        assert!(parse_trace(&bytes).is_ok());
+        assert_eq!(parse_trace(&bytes[.. bytes.len() - 1]), Err("truncated".to_string()));
+        assert_eq!(parse_trace(&bytes[.. TraceRecord::SIZE + 1]), Err("truncated".to_string()));
+        bytes[2 * TraceRecord::SIZE] = 6;
+        assert_eq!(parse_trace(&bytes), Err("invalid record 2".to_string()));
+    }
+}
@@ -0,0 +1,14 @@
+use core::fmt::Display;
+
+/// Aborts the process, printing the `reason` message, regardless of the panic strategy.
+///
+/// The crate is `no_std`, so there is no `std::process::abort`.
+/// Instead, the function panics inside an `extern "C"` function,
+/// and a panic cannot unwind out of it, so the process is aborted after the panic message is printed.
+pub(crate) fn abort_with<T: Display>(reason: T) -> ! {
+    extern "C" fn abort<T: Display>(reason: &T) -> ! {
+        panic!("{reason}");
+    }
+
+    abort(&reason)
+}
@@ -1,5 +1,6 @@
-use core::alloc::{self, Allocator};
-use core::ptr::NonNull;
+use core::alloc::{self, AllocError, Allocator};
+use core::cmp::min;
+use core::ptr::{self, NonNull};
 
 /// # Safety
 ///
@@ -49,3 +50,27 @@ unsafe impl<'a, T: Fallbackable + ?Sized> Fallbackable for &'a T {
 pub unsafe trait NonUnwinding: Allocator { }
 
 unsafe impl<'a, T: NonUnwinding + ?Sized> NonUnwinding for &'a T { }
+
+/// Shortens a returned block to `size` bytes, if it is longer.
+///
+/// A wrapper clamps blocks, if it should be called only with layouts it has seen,
+/// because the caller is allowed to deallocate a block with any layout fitting the block length.
+pub(crate) fn clamp(block: NonNull<[u8]>, size: usize) -> NonNull<[u8]> {
+    NonNull::slice_from_raw_parts(block.as_non_null_ptr(), min(block.len(), size))
+}
+
+/// Moves a block into a new one, allocated by `allocator`, and deallocates the old block.
+///
+/// Allocators resizing blocks in place fall back to it, when there is no room around the block.
+pub(crate) unsafe fn move_block<A: Allocator + ?Sized>(
+    allocator: &A,
+    ptr: NonNull<u8>,
+    old_layout: alloc::Layout,
+    new_layout: alloc::Layout,
+    zeroed: bool,
+) -> Result<NonNull<[u8]>, AllocError> {
+    let block = if zeroed { allocator.allocate_zeroed(new_layout)? } else { allocator.allocate(new_layout)? };
+    ptr::copy_nonoverlapping(ptr.as_ptr(), block.as_mut_ptr(), min(old_layout.size(), new_layout.size()));
+    allocator.deallocate(ptr, old_layout);
+    Ok(block)
+}
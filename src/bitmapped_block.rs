@@ -0,0 +1,396 @@
+use crate::base::*;
+use crate::buffer::{self, Arena, ArenaState, Buf};
+use core::alloc::{self, AllocError, Allocator};
+use core::cmp::{max, min};
+use core::mem::{MaybeUninit, size_of};
+use core::ptr::NonNull;
+
+const WORD: usize = size_of::<usize>();
+
+const BITS: usize = usize::BITS as usize;
+
+/// The bitmap of used blocks.
+const USED: usize = 0;
+
+/// The bitmap of allocations first blocks.
+const STARTS: usize = 1;
+
+struct State<const BLOCK: usize> {
+    blocks: usize,
+    /// The bitmaps offset from the arena start, bitmaps are placed right after blocks.
+    bitmaps: usize,
+}
+
+impl<const BLOCK: usize> State<BLOCK> {
+    fn words(blocks: usize) -> usize {
+        blocks.div_ceil(BITS)
+    }
+
+    fn bitmaps_offset(arena: *mut u8, blocks: usize) -> usize {
+        let end = blocks * BLOCK;
+        end + (arena as usize).wrapping_add(end).wrapping_neg() % WORD
+    }
+
+    unsafe fn word(&self, arena: *mut u8, bitmap: usize, index: usize) -> *mut usize {
+        (arena.add(self.bitmaps) as *mut usize).add(bitmap * Self::words(self.blocks) + index / BITS)
+    }
+
+    unsafe fn bit(&self, arena: *mut u8, bitmap: usize, index: usize) -> bool {
+        *self.word(arena, bitmap, index) & (1 << (index % BITS)) != 0
+    }
+
+    unsafe fn set_bit(&self, arena: *mut u8, bitmap: usize, index: usize, value: bool) {
+        let word = self.word(arena, bitmap, index);
+        if value {
+            *word |= 1 << (index % BITS);
+        } else {
+            *word &= !(1 << (index % BITS));
+        }
+    }
+
+    /// Splits blocks `start .. start + count` into bitmap words parts.
+    ///
+    /// Yields the first block index of every word, and the mask of range bits in the word.
+    fn parts(start: usize, count: usize) -> impl Iterator<Item=(usize, usize)> {
+        let end = start + count;
+        let mut index = start;
+        core::iter::from_fn(move || {
+            if index >= end { return None; }
+            let base = index - index % BITS;
+            let low = index - base;
+            let high = min(end - base, BITS);
+            let mask = (!0 >> (BITS - (high - low))) << low;
+            index = base + high;
+            Some((base, mask))
+        })
+    }
+
+    unsafe fn set_used(&self, arena: *mut u8, start: usize, count: usize, value: bool) {
+        for (base, mask) in Self::parts(start, count) {
+            let word = self.word(arena, USED, base);
+            if value {
+                *word |= mask;
+            } else {
+                *word &= !mask;
+            }
+        }
+    }
+
+    /// Returns the number of set bits in a range.
+    unsafe fn count_set(&self, arena: *mut u8, bitmap: usize, start: usize, count: usize) -> usize {
+        Self::parts(start, count).map(|(base, mask)| (*self.word(arena, bitmap, base) & mask).count_ones() as usize).sum()
+    }
+
+    /// Returns the first used block index in a range.
+    unsafe fn find_used(&self, arena: *mut u8, start: usize, count: usize) -> Option<usize> {
+        Self::parts(start, count).find_map(|(base, mask)| {
+            let used = *self.word(arena, USED, base) & mask;
+            (used != 0).then(|| base + used.trailing_zeros() as usize)
+        })
+    }
+
+    unsafe fn find(&self, arena: *mut u8, count: usize, align: usize) -> Option<usize> {
+        // Block addresses repeat their alignment with the `stride` period.
+        let stride = max(1, align >> BLOCK.trailing_zeros());
+        let aligned = |index: usize| (arena as usize).wrapping_add(index * BLOCK) % align == 0;
+        let mut index = (0 .. min(stride, self.blocks)).find(|&index| aligned(index))?;
+        while index < self.blocks && count <= self.blocks - index {
+            match self.find_used(arena, index, count) {
+                None => return Some(index),
+                Some(used) => index += (used + 1 - index).div_ceil(stride) * stride,
+            }
+        }
+        None
+    }
+
+    /// Checks, that blocks `index .. index + count` form a whole allocation.
+    unsafe fn is_allocation(&self, arena: *mut u8, index: usize, count: usize) -> bool {
+        if index >= self.blocks || count > self.blocks - index { return false; }
+        if !self.bit(arena, STARTS, index) { return false; }
+        if self.count_set(arena, USED, index, count) != count || self.count_set(arena, STARTS, index, count) != 1 {
+            return false;
+        }
+        let end = index + count;
+        end == self.blocks || !self.bit(arena, USED, end) || self.bit(arena, STARTS, end)
+    }
+}
+
+impl<const BLOCK: usize> ArenaState for State<BLOCK> {
+    unsafe fn init(buf_ptr: *mut u8, buf_len: usize) -> (usize, Self) {
+        let arena = buf_ptr.align_offset(1 << BLOCK.trailing_zeros());
+        if arena >= buf_len { return (0, State { blocks: 0, bitmaps: 0 }); }
+        let len = buf_len - arena;
+        let arena_ptr = buf_ptr.add(arena);
+        let mut blocks = (len as u128 * 8 / (BLOCK as u128 * 8 + 2)) as usize;
+        while blocks != 0 && Self::bitmaps_offset(arena_ptr, blocks) + 2 * Self::words(blocks) * WORD > len {
+            blocks -= 1;
+        }
+        let bitmaps = Self::bitmaps_offset(arena_ptr, blocks);
+        arena_ptr.add(bitmaps).write_bytes(0, 2 * Self::words(blocks) * WORD);
+        (arena, State { blocks, bitmaps })
+    }
+}
+
+/// An allocator over a buffer, which divides it into `BLOCK`-bytes blocks,
+/// and allocates runs of adjacent blocks.
+///
+/// Used blocks and allocations starts are kept in bitmaps placed after blocks,
+/// so nothing is written into free blocks,
+/// and [`has_allocated`](Fallbackable::has_allocated) is exact.
+/// Free blocks are searched linearly, a bitmap word at a time.
+pub struct BitmappedBlock<const BLOCK: usize> {
+    arena: Arena<State<BLOCK>>,
+}
+
+unsafe impl<const BLOCK: usize> NonUnwinding for BitmappedBlock<BLOCK> { }
+
+impl<const BLOCK: usize> BitmappedBlock<BLOCK> {
+    const fn new(buf: Buf) -> Self {
+        const { assert!(BLOCK != 0) };
+        BitmappedBlock { arena: Arena::new(buf) }
+    }
+
+    pub const fn from_static_slice(
+        buf: &'static mut [MaybeUninit<u8>],
+    ) -> Self {
+        Self::new(Buf::from_static_slice(buf))
+    }
+
+    pub const fn from_static_array<const BUF_LEN: usize>(
+        buf: &'static mut [MaybeUninit<u8>; BUF_LEN],
+    ) -> Self {
+        Self::new(Buf::from_static_array(buf))
+    }
+
+    /// # Safety
+    ///
+    /// Arguments should satisfy [`Stacked::with_buf_raw`](crate::stacked::Stacked::with_buf_raw) requirements.
+    pub unsafe fn with_buf_raw<T>(
+        buf_ptr: NonNull<MaybeUninit<u8>>,
+        buf_len: usize,
+        f: impl for<'a> FnOnce(&'a BitmappedBlock<BLOCK>) -> T
+    ) -> T {
+        f(&BitmappedBlock::new(Buf::new(buf_ptr, buf_len)))
+    }
+
+    /// Returns the number of blocks fitted into the buffer.
+    pub fn blocks(&self) -> usize {
+        self.arena.with_state(|state, _| state.blocks)
+    }
+
+    fn count(layout: alloc::Layout) -> usize {
+        max(1, layout.size().div_ceil(BLOCK))
+    }
+
+    fn index(arena: *mut u8, ptr: NonNull<u8>) -> Option<usize> {
+        let offset = (ptr.as_ptr() as usize).checked_sub(arena as usize)?;
+        if offset % BLOCK != 0 { return None; }
+        Some(offset / BLOCK)
+    }
+
+    unsafe fn block(arena: *mut u8, index: usize, count: usize) -> NonNull<[u8]> {
+        let ptr = NonNull::new_unchecked(arena.add(index * BLOCK));
+        NonNull::slice_from_raw_parts(ptr, count * BLOCK)
+    }
+
+    unsafe fn resize(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout,
+        zeroed: bool,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let old_count = Self::count(old_layout);
+        let count = Self::count(new_layout);
+        let in_place = (ptr.as_ptr() as usize) % new_layout.align() == 0 && self.arena.with_state(|state, arena| {
+            let index = Self::index(arena, ptr).unwrap();
+            if count <= old_count {
+                state.set_used(arena, index + count, old_count - count, false);
+                true
+            } else if
+                count - old_count <= state.blocks - index - old_count
+                && state.find_used(arena, index + old_count, count - old_count).is_none()
+            {
+                state.set_used(arena, index + old_count, count - old_count, true);
+                true
+            } else {
+                false
+            }
+        });
+        if in_place {
+            if zeroed {
+                ptr.add(old_layout.size()).write_bytes(0, count * BLOCK - old_layout.size());
+            }
+            return Ok(NonNull::slice_from_raw_parts(ptr, count * BLOCK));
+        }
+        move_block(self, ptr, old_layout, new_layout, zeroed)
+    }
+}
+
+pub fn with_size<const BLOCK: usize, const BUF_LEN: usize, T>(
+    f: impl for<'a> FnOnce(&'a BitmappedBlock<BLOCK>) -> T
+) -> T {
+    buffer::with_size::<BUF_LEN, _>(|buf| f(&BitmappedBlock::new(buf)))
+}
+
+pub fn with_buf<const BLOCK: usize, T>(
+    buf: &mut [MaybeUninit<u8>],
+    f: impl for<'a> FnOnce(&'a BitmappedBlock<BLOCK>) -> T
+) -> T {
+    buffer::with_buf(buf, |buf| f(&BitmappedBlock::new(buf)))
+}
+
+unsafe impl<const BLOCK: usize> Fallbackable for BitmappedBlock<BLOCK> {
+    unsafe fn has_allocated(&self, ptr: NonNull<u8>, layout: alloc::Layout) -> bool {
+        self.arena.with_state(|state, arena| {
+            Self::index(arena, ptr).is_some_and(|index| state.is_allocation(arena, index, Self::count(layout)))
+        })
+    }
+
+    fn allows_fallback(&self, _layout: alloc::Layout) -> bool {
+        true
+    }
+}
+
+unsafe impl<const BLOCK: usize> Allocator for BitmappedBlock<BLOCK> {
+    fn allocate(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let count = Self::count(layout);
+        self.arena.with_state(|state, arena| unsafe {
+            let index = state.find(arena, count, layout.align())?;
+            state.set_used(arena, index, count, true);
+            state.set_bit(arena, STARTS, index, true);
+            Some(Self::block(arena, index, count))
+        }).ok_or(AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: alloc::Layout) {
+        self.arena.with_state(|state, arena| {
+            let index = Self::index(arena, ptr).unwrap();
+            state.set_used(arena, index, Self::count(layout), false);
+            state.set_bit(arena, STARTS, index, false);
+        });
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old_layout, new_layout, false)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old_layout, new_layout, true)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old_layout, new_layout, false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::base::Fallbackable;
+    use crate::bitmapped_block::{self, BitmappedBlock};
+    use core::alloc::{self, Allocator};
+    use core::mem::MaybeUninit;
+    use core::ptr::NonNull;
+
+    const BLOCK: usize = 16;
+
+    #[repr(align(256))]
+    struct Buf([MaybeUninit<u8>; 4096]);
+
+    fn with_bitmapped<T>(f: impl FnOnce(&BitmappedBlock<BLOCK>, usize) -> T) -> T {
+        let mut buf = Buf([MaybeUninit::uninit(); 4096]);
+        let start = buf.0.as_mut_ptr() as usize;
+        bitmapped_block::with_buf(&mut buf.0, |bitmapped| {
+            assert!(bitmapped.blocks() > 2 * usize::BITS as usize);
+            f(bitmapped, start)
+        })
+    }
+
+    fn blocks(count: usize) -> alloc::Layout {
+        alloc::Layout::from_size_align(count * BLOCK, 1).unwrap()
+    }
+
+    fn allocate(bitmapped: &BitmappedBlock<BLOCK>, layout: alloc::Layout) -> NonNull<u8> {
+        bitmapped.allocate(layout).unwrap().as_non_null_ptr()
+    }
+
+    #[test]
+    fn multi_block_runs_across_words() {
+        with_bitmapped(|bitmapped, start| {
+            let a = allocate(bitmapped, blocks(60));
+            let b = allocate(bitmapped, blocks(10));
+            assert_eq!(a.as_ptr() as usize, start);
+            assert_eq!(b.as_ptr() as usize, start + 60 * BLOCK);
+            unsafe { bitmapped.deallocate(a, blocks(60)); }
+            let c = allocate(bitmapped, blocks(70));
+            assert_eq!(c.as_ptr() as usize, start + 70 * BLOCK);
+            let d = bitmapped.allocate(blocks(60)).unwrap();
+            assert_eq!(d.as_mut_ptr() as usize, start);
+            assert_eq!(d.len(), 60 * BLOCK);
+            unsafe {
+                bitmapped.deallocate(b, blocks(10));
+                bitmapped.deallocate(c, blocks(70));
+                bitmapped.deallocate(d.as_non_null_ptr(), blocks(60));
+            }
+            let all = bitmapped.blocks();
+            let e = allocate(bitmapped, blocks(all));
+            assert_eq!(e.as_ptr() as usize, start);
+            assert!(bitmapped.allocate(blocks(1)).is_err());
+            unsafe { bitmapped.deallocate(e, blocks(all)); }
+        });
+    }
+
+    #[test]
+    fn has_allocated_is_exact() {
+        with_bitmapped(|bitmapped, _| unsafe {
+            let a = allocate(bitmapped, blocks(3));
+            let b = allocate(bitmapped, blocks(2));
+            let interior = a.add(BLOCK);
+            assert!(bitmapped.has_allocated(a, blocks(3)));
+            assert!(!bitmapped.has_allocated(a, blocks(2)));
+            assert!(!bitmapped.has_allocated(a, blocks(4)));
+            assert!(!bitmapped.has_allocated(interior, blocks(2)));
+            assert!(!bitmapped.has_allocated(interior, blocks(1)));
+            assert!(!bitmapped.has_allocated(a.add(1), blocks(3)));
+            assert!(bitmapped.has_allocated(b, blocks(2)));
+            bitmapped.deallocate(a, blocks(3));
+            assert!(!bitmapped.has_allocated(a, blocks(3)));
+            assert!(bitmapped.has_allocated(b, blocks(2)));
+            bitmapped.deallocate(b, blocks(2));
+        });
+    }
+
+    #[test]
+    fn aligns_above_block() {
+        with_bitmapped(|bitmapped, start| unsafe {
+            let a = allocate(bitmapped, blocks(1));
+            let aligned = alloc::Layout::from_size_align(BLOCK, 8 * BLOCK).unwrap();
+            let b = allocate(bitmapped, aligned);
+            assert_eq!(b.as_ptr() as usize, start + 8 * BLOCK);
+            let c = allocate(bitmapped, blocks(1));
+            assert_eq!(c.as_ptr() as usize, start + BLOCK);
+            let run = alloc::Layout::from_size_align(70 * BLOCK, 16 * BLOCK).unwrap();
+            let d = allocate(bitmapped, run);
+            assert_eq!(d.as_ptr() as usize, start + 16 * BLOCK);
+            assert!(bitmapped.has_allocated(d, run));
+            for (ptr, layout) in [(a, blocks(1)), (b, aligned), (c, blocks(1)), (d, run)] {
+                bitmapped.deallocate(ptr, layout);
+            }
+        });
+    }
+}
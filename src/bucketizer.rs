@@ -0,0 +1,269 @@
+use crate::base::*;
+use crate::freelist::{Freelist, LimitParam, NoLimit};
+use core::alloc::{self, AllocError, Allocator};
+use core::cmp::min;
+use core::mem::MaybeUninit;
+use core::ptr::{self, NonNull};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SizeClasses {
+    /// Classes `min`, `2 * min`, `4 * min`, and so on.
+    PowerOfTwo { min: usize },
+    /// Classes `min`, `min + step`, `min + 2 * step`, and so on.
+    Linear { min: usize, step: usize },
+}
+
+impl SizeClasses {
+    pub(crate) const fn first(self) -> usize {
+        match self {
+            SizeClasses::PowerOfTwo { min } => min,
+            SizeClasses::Linear { min, .. } => min,
+        }
+    }
+
+    pub(crate) const fn next(self, size: usize) -> usize {
+        let next = match self {
+            SizeClasses::PowerOfTwo { .. } => size.checked_mul(2),
+            SizeClasses::Linear { step, .. } => size.checked_add(step),
+        };
+        match next {
+            Some(next) if next > size => next,
+            _ => panic!("invalid size classes"),
+        }
+    }
+}
+
+/// Routes every layout to the smallest fitting size class [`Freelist`],
+/// and layouts which do not fit any class to the `Fallback` allocator.
+///
+/// The fallback is also used when a class freelist fails to allocate and its base
+/// [allows](Fallbackable::allows_fallback) it.
+///
+/// Every class freelist caches free blocks up to the `Limit`,
+/// blocks exceeding it are returned to the shared base allocator.
+pub struct Bucketizer<A: Fallbackable + Copy, Fallback: Allocator, const N: usize, Limit: LimitParam = NoLimit> {
+    buckets: [Freelist<Limit, A>; N],
+    align: usize,
+    fallback: Fallback,
+}
+
+unsafe impl<
+    A: NonUnwinding + Fallbackable + Copy,
+    Fallback: NonUnwinding,
+    const N: usize,
+    Limit: LimitParam
+> NonUnwinding for Bucketizer<A, Fallback, N, Limit> { }
+
+impl<A: Fallbackable + Copy, Fallback: Allocator, const N: usize> Bucketizer<A, Fallback, N> {
+    /// Creates `N` size classes with the `align` alignment, all sharing the `base` allocator.
+    ///
+    /// The first class size should be not less than [`MIN_LAYOUT_SIZE`](crate::freelist::MIN_LAYOUT_SIZE),
+    /// and the `align` should be not less than [`MIN_LAYOUT_ALIGN`](crate::freelist::MIN_LAYOUT_ALIGN).
+    pub const fn new(classes: SizeClasses, align: usize, base: A, fallback: Fallback) -> Self {
+        Self::with_limit(classes, align, NoLimit, base, fallback)
+    }
+}
+
+impl<A: Fallbackable + Copy, Fallback: Allocator, const N: usize, Limit: LimitParam + Copy> Bucketizer<A, Fallback, N, Limit> {
+    /// Creates `N` size classes with the `align` alignment, all sharing the `base` allocator,
+    /// every class caching free blocks up to the `limit`.
+    ///
+    /// The first class size should be not less than [`MIN_LAYOUT_SIZE`](crate::freelist::MIN_LAYOUT_SIZE),
+    /// and the `align` should be not less than [`MIN_LAYOUT_ALIGN`](crate::freelist::MIN_LAYOUT_ALIGN).
+    pub const fn with_limit(classes: SizeClasses, align: usize, limit: Limit, base: A, fallback: Fallback) -> Self {
+        let mut buckets: [MaybeUninit<Freelist<Limit, A>>; N] = [const { MaybeUninit::uninit() }; N];
+        let mut tolerance = 0;
+        let mut size = classes.first();
+        let mut i = 0;
+        while i < N {
+            let layout = match alloc::Layout::from_size_align(size, align) {
+                Ok(layout) => layout,
+                Err(_) => panic!("invalid size class layout"),
+            };
+            let tolerance_layout = unsafe { alloc::Layout::from_size_align_unchecked(tolerance, 1) };
+            buckets[i] = MaybeUninit::new(Freelist::new(layout, tolerance_layout, limit, base));
+            i += 1;
+            if i < N {
+                tolerance = size + 1;
+                size = classes.next(size);
+            }
+        }
+        let buckets_ptr = &buckets as *const [MaybeUninit<Freelist<Limit, A>>; N];
+        Bucketizer {
+            buckets: unsafe { ptr::read(buckets_ptr as *const [Freelist<Limit, A>; N]) },
+            align,
+            fallback,
+        }
+    }
+}
+
+impl<A: Fallbackable + Copy, Fallback: Allocator, const N: usize, Limit: LimitParam> Bucketizer<A, Fallback, N, Limit> {
+    fn bucket(&self, layout: alloc::Layout) -> Option<&Freelist<Limit, A>> {
+        if layout.align() > self.align { return None; }
+        self.buckets.get(self.buckets.partition_point(|x| x.layout().size() < layout.size()))
+    }
+
+    unsafe fn owner(&self, ptr: NonNull<u8>, layout: alloc::Layout) -> Option<&Freelist<Limit, A>> {
+        self.bucket(layout).filter(|x| x.has_allocated(ptr, layout))
+    }
+
+    pub fn buckets(&self) -> &[Freelist<Limit, A>; N] { &self.buckets }
+
+    pub fn fallback(&self) -> &Fallback { &self.fallback }
+}
+
+unsafe impl<
+    A: Fallbackable + Copy,
+    Fallback: Fallbackable,
+    const N: usize,
+    Limit: LimitParam
+> Fallbackable for Bucketizer<A, Fallback, N, Limit> {
+    unsafe fn has_allocated(&self, ptr: NonNull<u8>, layout: alloc::Layout) -> bool {
+        if let Some(bucket) = self.bucket(layout) {
+            bucket.has_allocated(ptr, layout) || self.fallback.has_allocated(ptr, layout)
+        } else {
+            self.fallback.has_allocated(ptr, layout)
+        }
+    }
+
+    fn allows_fallback(&self, layout: alloc::Layout) -> bool {
+        if let Some(bucket) = self.bucket(layout) {
+            bucket.allows_fallback(layout) && self.fallback.allows_fallback(layout)
+        } else {
+            self.fallback.allows_fallback(layout)
+        }
+    }
+}
+
+unsafe impl<
+    A: Fallbackable + Copy,
+    Fallback: Allocator,
+    const N: usize,
+    Limit: LimitParam
+> Allocator for Bucketizer<A, Fallback, N, Limit> {
+    fn allocate(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if let Some(bucket) = self.bucket(layout) {
+            if let Ok(block) = bucket.allocate(layout) {
+                return Ok(block);
+            } else if !bucket.allows_fallback(layout) {
+                return Err(AllocError);
+            }
+        }
+        self.fallback.allocate(layout)
+    }
+
+    fn allocate_zeroed(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if let Some(bucket) = self.bucket(layout) {
+            if let Ok(block) = bucket.allocate_zeroed(layout) {
+                return Ok(block);
+            } else if !bucket.allows_fallback(layout) {
+                return Err(AllocError);
+            }
+        }
+        self.fallback.allocate_zeroed(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: alloc::Layout) {
+        if let Some(bucket) = self.owner(ptr, layout) {
+            bucket.deallocate(ptr, layout);
+        } else {
+            self.fallback.deallocate(ptr, layout);
+        }
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if let Some(bucket) = self.owner(ptr, old_layout) {
+            if new_layout.size() <= bucket.layout().size() && new_layout.align() <= bucket.layout().align() {
+                return Ok(NonNull::slice_from_raw_parts(ptr, bucket.layout().size()));
+            }
+        } else if self.bucket(new_layout).is_none() {
+            return self.fallback.grow(ptr, old_layout, new_layout);
+        }
+        let block = self.allocate(new_layout)?;
+        ptr::copy_nonoverlapping(ptr.as_ptr(), block.as_mut_ptr(), old_layout.size());
+        self.deallocate(ptr, old_layout);
+        Ok(block)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if let Some(bucket) = self.owner(ptr, old_layout) {
+            if new_layout.size() <= bucket.layout().size() && new_layout.align() <= bucket.layout().align() {
+                ptr.as_ptr().add(old_layout.size()).write_bytes(0, bucket.layout().size() - old_layout.size());
+                return Ok(NonNull::slice_from_raw_parts(ptr, bucket.layout().size()));
+            }
+        } else if self.bucket(new_layout).is_none() {
+            return self.fallback.grow_zeroed(ptr, old_layout, new_layout);
+        }
+        let block = self.allocate_zeroed(new_layout)?;
+        ptr::copy_nonoverlapping(ptr.as_ptr(), block.as_mut_ptr(), old_layout.size());
+        self.deallocate(ptr, old_layout);
+        Ok(block)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if let Some(bucket) = self.owner(ptr, old_layout) {
+            if self.bucket(new_layout).is_some_and(|x| ptr::eq(x, bucket)) {
+                return Ok(NonNull::slice_from_raw_parts(ptr, bucket.layout().size()));
+            }
+        } else if self.bucket(new_layout).is_none() {
+            return self.fallback.shrink(ptr, old_layout, new_layout);
+        }
+        let block = self.allocate(new_layout)?;
+        ptr::copy_nonoverlapping(ptr.as_ptr(), block.as_mut_ptr(), min(old_layout.size(), new_layout.size()));
+        self.deallocate(ptr, old_layout);
+        Ok(block)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::NonWorking;
+    use crate::bucketizer::{Bucketizer, SizeClasses};
+    use crate::freelist::FixedLimit;
+    use crate::heap::{self, Fit};
+    use core::alloc::{self, Allocator};
+
+    #[test]
+    fn blocks_over_limit_are_reused_by_other_classes() {
+        heap::with_size::<4096, _>(Fit::First, |heap| {
+            let classes = SizeClasses::Linear { min: 16, step: 16 };
+            let bucketizer: Bucketizer<_, _, 4, _> = Bucketizer::with_limit(classes, 8, FixedLimit::new(0), heap, NonWorking);
+            // Freelists mutexes could allocate from the heap on first lock.
+            for bucket in bucketizer.buckets() {
+                bucket.cached_len();
+            }
+            let small = alloc::Layout::from_size_align(16, 8).unwrap();
+            let large = alloc::Layout::from_size_align(48, 8).unwrap();
+            let block = bucketizer.allocate(small).unwrap().as_non_null_ptr();
+            unsafe { bucketizer.deallocate(block, small); }
+            assert_eq!(bucketizer.buckets()[0].cached_len(), 0);
+            let reused = bucketizer.allocate(large).unwrap().as_non_null_ptr();
+            assert_eq!(reused, block);
+            let larger = alloc::Layout::from_size_align(64, 8).unwrap();
+            let moved = unsafe { bucketizer.grow(reused, large, larger) }.unwrap().as_non_null_ptr();
+            assert_ne!(moved, reused);
+            assert_eq!(bucketizer.buckets()[2].cached_len(), 0);
+            let block = bucketizer.allocate(small).unwrap().as_non_null_ptr();
+            assert_eq!(block, reused);
+            unsafe {
+                bucketizer.deallocate(block, small);
+                bucketizer.deallocate(moved, larger);
+            }
+        });
+    }
+}
@@ -0,0 +1,444 @@
+use crate::base::*;
+use crate::buffer::{self, Arena, ArenaState, Buf};
+use core::alloc::{self, AllocError, Allocator};
+use core::cmp::{max, min};
+use core::mem::{MaybeUninit, size_of};
+use core::ptr::NonNull;
+
+/// The smallest block size.
+///
+/// Bigger blocks have `MIN_BLOCK_SIZE * 2^order` sizes.
+pub const MIN_BLOCK_SIZE: usize = 2 * size_of::<usize>();
+
+const ORDERS: usize = usize::BITS as usize;
+
+const NIL: usize = usize::MAX;
+
+const fn block_size(order: usize) -> usize {
+    MIN_BLOCK_SIZE << order
+}
+
+fn levels(blocks: usize) -> usize {
+    if blocks == 0 { 0 } else { blocks.ilog2() as usize + 1 }
+}
+
+fn bitmap_bits(blocks: usize) -> usize {
+    (0 .. levels(blocks)).map(|order| blocks.div_ceil(1 << order)).sum()
+}
+
+struct State {
+    /// The number of `MIN_BLOCK_SIZE` blocks in the arena.
+    blocks: usize,
+    levels: usize,
+    max_align: usize,
+    /// Bitmap bits for every level start, the bitmap is placed right after the arena.
+    level_bits: [usize; ORDERS],
+    /// Free lists heads, as offsets from the arena start.
+    heads: [usize; ORDERS],
+}
+
+/// Free list node, placed at a free block start.
+struct Node {
+    next: usize,
+    prev: usize,
+}
+
+impl State {
+    const fn new() -> Self {
+        State {
+            blocks: 0,
+            levels: 0,
+            max_align: 0,
+            level_bits: [0; ORDERS],
+            heads: [NIL; ORDERS],
+        }
+    }
+
+    unsafe fn bitmap(&self, arena: *mut u8) -> *mut u8 {
+        arena.add(self.blocks * MIN_BLOCK_SIZE)
+    }
+
+    unsafe fn node(&self, arena: *mut u8, offset: usize) -> *mut Node {
+        arena.add(offset) as *mut Node
+    }
+
+    fn bit(&self, offset: usize, order: usize) -> (usize, u8) {
+        let bit = self.level_bits[order] + (offset >> (order + MIN_BLOCK_SIZE.trailing_zeros() as usize));
+        (bit / 8, 1 << (bit % 8))
+    }
+
+    unsafe fn is_free(&self, arena: *mut u8, offset: usize, order: usize) -> bool {
+        let (byte, mask) = self.bit(offset, order);
+        *self.bitmap(arena).add(byte) & mask != 0
+    }
+
+    unsafe fn push(&mut self, arena: *mut u8, offset: usize, order: usize) {
+        let (byte, mask) = self.bit(offset, order);
+        *self.bitmap(arena).add(byte) |= mask;
+        let head = self.heads[order];
+        self.node(arena, offset).write(Node { next: head, prev: NIL });
+        if head != NIL {
+            (*self.node(arena, head)).prev = offset;
+        }
+        self.heads[order] = offset;
+    }
+
+    unsafe fn remove(&mut self, arena: *mut u8, offset: usize, order: usize) {
+        let (byte, mask) = self.bit(offset, order);
+        *self.bitmap(arena).add(byte) &= !mask;
+        let Node { next, prev } = self.node(arena, offset).read();
+        if prev == NIL {
+            self.heads[order] = next;
+        } else {
+            (*self.node(arena, prev)).next = next;
+        }
+        if next != NIL {
+            (*self.node(arena, next)).prev = prev;
+        }
+    }
+
+    /// Returns the buddy offset, if the buddy is a free block with the same order.
+    unsafe fn free_buddy(&self, arena: *mut u8, offset: usize, order: usize) -> Option<usize> {
+        if order + 1 >= self.levels { return None; }
+        let buddy = offset ^ block_size(order);
+        if buddy + block_size(order) > self.blocks * MIN_BLOCK_SIZE { return None; }
+        if !self.is_free(arena, buddy, order) { return None; }
+        Some(buddy)
+    }
+
+    fn order(&self, layout: alloc::Layout) -> Option<usize> {
+        if layout.align() > self.max_align { return None; }
+        let size = max(max(layout.size(), layout.align()), MIN_BLOCK_SIZE).checked_next_power_of_two()?;
+        let order = (size / MIN_BLOCK_SIZE).trailing_zeros() as usize;
+        if order >= self.levels { return None; }
+        Some(order)
+    }
+
+    unsafe fn allocate(&mut self, arena: *mut u8, order: usize) -> Option<usize> {
+        let mut level = (order .. self.levels).find(|&x| self.heads[x] != NIL)?;
+        let offset = self.heads[level];
+        self.remove(arena, offset, level);
+        while level > order {
+            level -= 1;
+            self.push(arena, offset + block_size(level), level);
+        }
+        Some(offset)
+    }
+
+    unsafe fn deallocate(&mut self, arena: *mut u8, mut offset: usize, mut order: usize) {
+        while let Some(buddy) = self.free_buddy(arena, offset, order) {
+            self.remove(arena, buddy, order);
+            offset = min(offset, buddy);
+            order += 1;
+        }
+        self.push(arena, offset, order);
+    }
+
+    unsafe fn grow_in_place(&mut self, arena: *mut u8, offset: usize, old_order: usize, new_order: usize) -> bool {
+        if new_order >= self.levels || offset % block_size(new_order) != 0 { return false; }
+        let all_free = (old_order .. new_order).all(|order|
+            self.free_buddy(arena, offset, order) == Some(offset + block_size(order))
+        );
+        if !all_free { return false; }
+        for order in old_order .. new_order {
+            self.remove(arena, offset + block_size(order), order);
+        }
+        true
+    }
+
+    unsafe fn shrink_in_place(&mut self, arena: *mut u8, offset: usize, old_order: usize, new_order: usize) {
+        for order in (new_order .. old_order).rev() {
+            self.push(arena, offset + block_size(order), order);
+        }
+    }
+}
+
+impl ArenaState for State {
+    unsafe fn init(buf_ptr: *mut u8, buf_len: usize) -> (usize, Self) {
+        let mut state = State::new();
+        let arena = buf_ptr.align_offset(MIN_BLOCK_SIZE);
+        if arena >= buf_len { return (0, state); }
+        let len = buf_len - arena;
+        let mut blocks = len / MIN_BLOCK_SIZE;
+        while blocks != 0 && blocks * MIN_BLOCK_SIZE + bitmap_bits(blocks).div_ceil(8) > len {
+            blocks -= 1;
+        }
+        let arena_ptr = buf_ptr.add(arena);
+        state.blocks = blocks;
+        state.levels = levels(blocks);
+        state.max_align = 1 << (arena_ptr as usize).trailing_zeros();
+        let mut bits = 0;
+        for order in 0 .. state.levels {
+            state.level_bits[order] = bits;
+            bits += blocks.div_ceil(1 << order);
+        }
+        state.bitmap(arena_ptr).write_bytes(0, bits.div_ceil(8));
+        let arena_len = blocks * MIN_BLOCK_SIZE;
+        let mut offset = 0;
+        while offset < arena_len {
+            let mut order = min(offset.trailing_zeros() as usize, ORDERS - 1).saturating_sub(MIN_BLOCK_SIZE.trailing_zeros() as usize);
+            while offset + block_size(order) > arena_len {
+                order -= 1;
+            }
+            state.push(arena_ptr, offset, order);
+            offset += block_size(order);
+        }
+        (arena, state)
+    }
+}
+
+/// A buddy allocator over a buffer.
+///
+/// Free blocks are split in halves on allocating, and merged with their free buddies on deallocating.
+/// A part of the buffer is used for the bitmap of free blocks.
+///
+/// The maximal supported alignment is the arena start address alignment,
+/// the arena starts at the first `MIN_BLOCK_SIZE`-aligned address in the buffer.
+pub struct Buddy {
+    arena: Arena<State>,
+}
+
+unsafe impl NonUnwinding for Buddy { }
+
+impl Buddy {
+    const fn new(buf: Buf) -> Self {
+        Buddy { arena: Arena::new(buf) }
+    }
+
+    pub const fn from_static_slice(
+        buf: &'static mut [MaybeUninit<u8>],
+    ) -> Self {
+        Self::new(Buf::from_static_slice(buf))
+    }
+
+    pub const fn from_static_array<const BUF_LEN: usize>(
+        buf: &'static mut [MaybeUninit<u8>; BUF_LEN],
+    ) -> Self {
+        Self::new(Buf::from_static_array(buf))
+    }
+
+    /// # Safety
+    ///
+    /// Arguments should satisfy [`Stacked::with_buf_raw`](crate::stacked::Stacked::with_buf_raw) requirements.
+    pub unsafe fn with_buf_raw<T>(
+        buf_ptr: NonNull<MaybeUninit<u8>>,
+        buf_len: usize,
+        f: impl for<'a> FnOnce(&'a Buddy) -> T
+    ) -> T {
+        f(&Buddy::new(Buf::new(buf_ptr, buf_len)))
+    }
+
+    /// Returns the block offset from the arena start.
+    unsafe fn offset(arena: *mut u8, ptr: NonNull<u8>) -> usize {
+        ptr.as_ptr().offset_from(arena) as usize
+    }
+
+    unsafe fn block(arena: *mut u8, offset: usize, order: usize) -> NonNull<[u8]> {
+        let ptr = NonNull::new_unchecked(arena.add(offset));
+        NonNull::slice_from_raw_parts(ptr, block_size(order))
+    }
+
+    unsafe fn resize(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout,
+        zeroed: bool,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let in_place = self.arena.with_state(|state, arena| {
+            let old_order = state.order(old_layout).unwrap();
+            let new_order = state.order(new_layout)?;
+            let offset = Self::offset(arena, ptr);
+            if new_order < old_order {
+                state.shrink_in_place(arena, offset, old_order, new_order);
+            } else if new_order > old_order && !state.grow_in_place(arena, offset, old_order, new_order) {
+                return None;
+            }
+            Some(Self::block(arena, offset, new_order))
+        });
+        if let Some(block) = in_place {
+            if zeroed {
+                ptr.add(old_layout.size()).write_bytes(0, block.len() - old_layout.size());
+            }
+            return Ok(block);
+        }
+        move_block(self, ptr, old_layout, new_layout, zeroed)
+    }
+}
+
+pub fn with_size<const BUF_LEN: usize, T>(
+    f: impl for<'a> FnOnce(&'a Buddy) -> T
+) -> T {
+    buffer::with_size::<BUF_LEN, _>(|buf| f(&Buddy::new(buf)))
+}
+
+pub fn with_buf<T>(
+    buf: &mut [MaybeUninit<u8>],
+    f: impl for<'a> FnOnce(&'a Buddy) -> T
+) -> T {
+    buffer::with_buf(buf, |buf| f(&Buddy::new(buf)))
+}
+
+unsafe impl Fallbackable for Buddy {
+    unsafe fn has_allocated(&self, ptr: NonNull<u8>, _layout: alloc::Layout) -> bool {
+        self.arena.buf().contains(ptr)
+    }
+
+    fn allows_fallback(&self, _layout: alloc::Layout) -> bool {
+        true
+    }
+}
+
+unsafe impl Allocator for Buddy {
+    fn allocate(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.arena.with_state(|state, arena| {
+            let order = state.order(layout)?;
+            let offset = unsafe { state.allocate(arena, order) }?;
+            Some(unsafe { Self::block(arena, offset, order) })
+        }).ok_or(AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: alloc::Layout) {
+        self.arena.with_state(|state, arena| {
+            let order = state.order(layout).unwrap();
+            let offset = Self::offset(arena, ptr);
+            state.deallocate(arena, offset, order);
+        });
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old_layout, new_layout, false)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old_layout, new_layout, true)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old_layout, new_layout, false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use crate::Fallbackable;
+    use crate::buddy::{self, MIN_BLOCK_SIZE};
+    use core::alloc::{self, Allocator};
+    use core::mem::MaybeUninit;
+    use core::ptr::NonNull;
+    use std::vec::Vec;
+
+    const ARENA: usize = 4096;
+
+    /// The bitmap size for `ARENA / MIN_BLOCK_SIZE` blocks.
+    const BITMAP: usize = (2 * ARENA / MIN_BLOCK_SIZE).div_ceil(8);
+
+    /// An arena-aligned buffer with room for exactly the arena and its bitmap.
+    #[repr(align(4096))]
+    struct Buf([MaybeUninit<u8>; ARENA + BITMAP]);
+
+    impl Buf {
+        fn new() -> Self { Buf([MaybeUninit::uninit(); ARENA + BITMAP]) }
+    }
+
+    fn layout(size: usize) -> alloc::Layout {
+        alloc::Layout::from_size_align(size, MIN_BLOCK_SIZE).unwrap()
+    }
+
+    #[test]
+    fn resizes_in_place_when_buddy_is_free() {
+        let mut buf = Buf::new();
+        buddy::with_buf(&mut buf.0, |buddy| {
+            let a = buddy.allocate(layout(16)).unwrap().as_non_null_ptr();
+            let grown = unsafe { buddy.grow(a, layout(16), layout(32)) }.unwrap();
+            assert_eq!(grown.as_non_null_ptr(), a);
+            assert_eq!(grown.len(), 32);
+            let b = buddy.allocate(layout(32)).unwrap().as_non_null_ptr();
+            assert_eq!(b.as_ptr(), a.as_ptr().wrapping_add(32));
+            let moved = unsafe { buddy.grow(a, layout(32), layout(64)) }.unwrap();
+            assert_ne!(moved.as_non_null_ptr(), a);
+            let shrunk = unsafe { buddy.shrink(moved.as_non_null_ptr(), layout(64), layout(16)) }.unwrap();
+            assert_eq!(shrunk.as_non_null_ptr(), moved.as_non_null_ptr());
+            assert_eq!(shrunk.len(), 16);
+            let c = buddy.allocate(layout(16)).unwrap().as_non_null_ptr();
+            assert_eq!(c.as_ptr(), moved.as_mut_ptr().wrapping_add(16));
+            unsafe {
+                buddy.deallocate(c, layout(16));
+                buddy.deallocate(shrunk.as_non_null_ptr(), layout(16));
+                buddy.deallocate(b, layout(32));
+            }
+        });
+    }
+
+    #[test]
+    fn coalesces_into_one_block() {
+        let mut buf = Buf::new();
+        buddy::with_buf(&mut buf.0, |buddy| {
+            let mut blocks = Vec::new();
+            while let Ok(block) = buddy.allocate(layout(MIN_BLOCK_SIZE)) {
+                blocks.push(block.as_non_null_ptr());
+            }
+            assert_eq!(blocks.len(), ARENA / MIN_BLOCK_SIZE);
+            assert!(buddy.allocate(layout(ARENA)).is_err());
+            let (even, odd): (Vec<_>, Vec<_>) = blocks.iter().enumerate().partition(|(i, _)| i % 2 == 0);
+            for (_, &block) in even.into_iter().chain(odd.into_iter().rev()) {
+                unsafe { buddy.deallocate(block, layout(MIN_BLOCK_SIZE)); }
+            }
+            let whole = buddy.allocate(layout(ARENA)).unwrap();
+            assert_eq!(whole.as_mut_ptr(), buf_start(&blocks));
+            assert_eq!(whole.len(), ARENA);
+            unsafe { buddy.deallocate(whole.as_non_null_ptr(), layout(ARENA)); }
+        });
+    }
+
+    fn buf_start(blocks: &[NonNull<u8>]) -> *mut u8 {
+        blocks.iter().map(|x| x.as_ptr()).min().unwrap()
+    }
+
+    #[test]
+    fn has_allocated_by_buffer_range() {
+        let mut buf = Buf::new();
+        let start = buf.0.as_mut_ptr() as *mut u8;
+        buddy::with_buf(&mut buf.0, |buddy| {
+            let block = buddy.allocate(layout(64)).unwrap().as_non_null_ptr();
+            unsafe {
+                assert!(buddy.has_allocated(block, layout(64)));
+                assert!(buddy.has_allocated(NonNull::new_unchecked(start.wrapping_add(ARENA - 1)), layout(16)));
+                assert!(!buddy.has_allocated(NonNull::new_unchecked(start.wrapping_add(ARENA + BITMAP)), layout(16)));
+                assert!(!buddy.has_allocated(NonNull::new_unchecked(start.wrapping_sub(1)), layout(16)));
+                buddy.deallocate(block, layout(64));
+            }
+        });
+    }
+
+    #[test]
+    fn unaligned_buffer_start() {
+        let mut buf = Buf::new();
+        let start = buf.0.as_mut_ptr() as *mut u8;
+        buddy::with_buf(&mut buf.0[1 ..], |buddy| {
+            let block = buddy.allocate(layout(16)).unwrap().as_non_null_ptr();
+            assert!(block.as_ptr() > start);
+            assert_eq!(block.as_ptr() as usize % MIN_BLOCK_SIZE, 0);
+            let over_aligned = alloc::Layout::from_size_align(16, 2 * MIN_BLOCK_SIZE).unwrap();
+            assert!(buddy.allocate(over_aligned).is_err());
+            unsafe { buddy.deallocate(block, layout(16)); }
+        });
+    }
+}
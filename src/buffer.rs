@@ -0,0 +1,91 @@
+use crate::spin_lock::SpinLock;
+use core::mem::MaybeUninit;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+/// A buffer, which an allocator hands out memory from.
+pub(crate) struct Buf {
+    ptr: AtomicPtr<u8>,
+    len: usize,
+}
+
+impl Buf {
+    pub(crate) const fn from_static_slice(buf: &'static mut [MaybeUninit<u8>]) -> Self {
+        Buf { ptr: AtomicPtr::new(buf.as_mut_ptr() as *mut u8), len: buf.len() }
+    }
+
+    pub(crate) const fn from_static_array<const BUF_LEN: usize>(buf: &'static mut [MaybeUninit<u8>; BUF_LEN]) -> Self {
+        Buf { ptr: AtomicPtr::new(buf.as_mut_ptr() as *mut u8), len: BUF_LEN }
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` should be a valid unique pointer to a slice with `len` bytes length.
+    ///
+    /// Arguments should satisfy
+    /// `len <= isize::MAX as usize`,
+    /// and
+    /// `(isize::MAX as usize) - len >= ptr as usize`
+    pub(crate) const unsafe fn new(ptr: NonNull<MaybeUninit<u8>>, len: usize) -> Self {
+        Buf { ptr: AtomicPtr::new(ptr.as_ptr() as *mut u8), len }
+    }
+
+    pub(crate) fn ptr(&self) -> *mut u8 { self.ptr.load(Ordering::Relaxed) }
+
+    pub(crate) fn len(&self) -> usize { self.len }
+
+    pub(crate) fn contains(&self, ptr: NonNull<u8>) -> bool {
+        (ptr.as_ptr() as usize).checked_sub(self.ptr() as usize).is_some_and(|offset| offset < self.len)
+    }
+}
+
+/// Calls `f` with a `BUF_LEN` bytes buffer placed on the stack.
+pub(crate) fn with_size<const BUF_LEN: usize, T>(f: impl FnOnce(Buf) -> T) -> T {
+    let mut buf: [MaybeUninit<u8>; BUF_LEN] = [MaybeUninit::uninit(); BUF_LEN];
+    let buf_ptr = unsafe { NonNull::new_unchecked(buf.as_mut_ptr()) };
+    assert!((isize::MAX as usize) - BUF_LEN >= buf_ptr.as_ptr() as usize);
+    f(unsafe { Buf::new(buf_ptr, BUF_LEN) })
+}
+
+/// Calls `f` with the `buf` buffer.
+pub(crate) fn with_buf<T>(buf: &mut [MaybeUninit<u8>], f: impl FnOnce(Buf) -> T) -> T {
+    let buf_len = buf.len();
+    assert!(buf_len <= isize::MAX as usize && (isize::MAX as usize) - buf_len >= buf.as_ptr() as usize);
+    let buf_ptr = unsafe { NonNull::new_unchecked(buf.as_mut_ptr()) };
+    f(unsafe { Buf::new(buf_ptr, buf_len) })
+}
+
+/// An allocator state, kept in [`Arena`].
+pub(crate) trait ArenaState: Sized {
+    /// Places the arena into the `buf_len` bytes buffer at `buf_ptr`,
+    /// and returns the arena offset from the buffer start, and the state.
+    ///
+    /// # Safety
+    ///
+    /// The buffer should be valid and not used by anything else.
+    unsafe fn init(buf_ptr: *mut u8, buf_len: usize) -> (usize, Self);
+}
+
+/// A buffer with an allocator state, which is initialized on first use,
+/// so the allocator can be created in a `const` context.
+pub(crate) struct Arena<S> {
+    buf: Buf,
+    state: SpinLock<Option<(usize, S)>>,
+}
+
+impl<S: ArenaState> Arena<S> {
+    pub(crate) const fn new(buf: Buf) -> Self {
+        Arena { buf, state: SpinLock::new(None) }
+    }
+
+    pub(crate) fn buf(&self) -> &Buf { &self.buf }
+
+    /// Calls `f` with the locked state and the arena start.
+    pub(crate) fn with_state<T>(&self, f: impl FnOnce(&mut S, *mut u8) -> T) -> T {
+        let buf_ptr = self.buf.ptr();
+        self.state.with(|state| {
+            let (arena, state) = state.get_or_insert_with(|| unsafe { S::init(buf_ptr, self.buf.len) });
+            f(state, unsafe { buf_ptr.add(*arena) })
+        })
+    }
+}
@@ -0,0 +1,256 @@
+use crate::base::*;
+use crate::corruption::*;
+use core::alloc::{self, AllocError, Allocator};
+use core::cmp::{max, min};
+use core::ptr::{self, NonNull};
+
+/// Guard bytes count after a block.
+/// Before a block, there are `max(CANARY_SIZE, align)` guard bytes.
+pub const CANARY_SIZE: usize = 16;
+
+pub const CANARY_BYTE: u8 = 0xCA;
+
+fn prefix(layout: alloc::Layout) -> usize {
+    max(CANARY_SIZE, layout.align())
+}
+
+fn inner(layout: alloc::Layout) -> Option<alloc::Layout> {
+    let size = prefix(layout).checked_add(layout.size())?.checked_add(CANARY_SIZE)?;
+    alloc::Layout::from_size_align(size, layout.align()).ok()
+}
+
+/// Surrounds every block with guard bytes,
+/// and checks them on deallocating, growing, and shrinking.
+///
+/// The base allocator gets adjusted layouts, with the same alignment.
+pub struct Canary<A: Allocator, H: CorruptionHandler = PanicOnCorruption>(pub A, pub H);
+
+unsafe impl<A: NonUnwinding> NonUnwinding for Canary<A, AbortOnCorruption> { }
+
+impl<A: Allocator, H: CorruptionHandler> Canary<A, H> {
+    unsafe fn arm(&self, block: NonNull<[u8]>, layout: alloc::Layout) -> NonNull<[u8]> {
+        let prefix = prefix(layout);
+        let ptr = block.as_non_null_ptr();
+        ptr.write_bytes(CANARY_BYTE, prefix);
+        ptr.add(prefix + layout.size()).write_bytes(CANARY_BYTE, CANARY_SIZE);
+        NonNull::slice_from_raw_parts(ptr.add(prefix), layout.size())
+    }
+
+    unsafe fn check(&self, ptr: NonNull<u8>, layout: alloc::Layout) {
+        let prefix = prefix(layout);
+        if let Some(i) = (1 ..= prefix).find(|&i| *ptr.as_ptr().sub(i) != CANARY_BYTE) {
+            self.1.corrupted(&Corruption { kind: CorruptionKind::Underflow, ptr, layout, offset: -(i as isize) });
+        }
+        let end = ptr.add(layout.size());
+        if let Some(i) = (0 .. CANARY_SIZE).find(|&i| *end.as_ptr().add(i) != CANARY_BYTE) {
+            let offset = (layout.size() + i) as isize;
+            self.1.corrupted(&Corruption { kind: CorruptionKind::Overflow, ptr, layout, offset });
+        }
+    }
+
+    unsafe fn move_block(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout,
+        zeroed: bool,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let block = if zeroed { self.allocate_zeroed(new_layout)? } else { self.allocate(new_layout)? };
+        ptr::copy_nonoverlapping(ptr.as_ptr(), block.as_mut_ptr(), min(old_layout.size(), new_layout.size()));
+        self.0.deallocate(ptr.sub(prefix(old_layout)), inner(old_layout).unwrap());
+        Ok(block)
+    }
+
+    unsafe fn grow_with(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout,
+        zeroed: bool,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.check(ptr, old_layout);
+        let prefix = prefix(old_layout);
+        if prefix != self::prefix(new_layout) {
+            return self.move_block(ptr, old_layout, new_layout, zeroed);
+        }
+        let inner_old_layout = inner(old_layout).unwrap();
+        let inner_new_layout = inner(new_layout).ok_or(AllocError)?;
+        let block = if zeroed {
+            self.0.grow_zeroed(ptr.sub(prefix), inner_old_layout, inner_new_layout)?
+        } else {
+            self.0.grow(ptr.sub(prefix), inner_old_layout, inner_new_layout)?
+        };
+        let ptr = block.as_non_null_ptr().add(prefix);
+        if zeroed {
+            ptr.add(old_layout.size()).write_bytes(0, min(CANARY_SIZE, new_layout.size() - old_layout.size()));
+        }
+        ptr.add(new_layout.size()).write_bytes(CANARY_BYTE, CANARY_SIZE);
+        Ok(NonNull::slice_from_raw_parts(ptr, new_layout.size()))
+    }
+}
+
+unsafe impl<A: Fallbackable, H: CorruptionHandler> Fallbackable for Canary<A, H> {
+    unsafe fn has_allocated(&self, ptr: NonNull<u8>, layout: alloc::Layout) -> bool {
+        let Some(inner_layout) = inner(layout) else { return false; };
+        // The pointer may be not allocated by this allocator, so the prefix is subtracted without `sub`.
+        let Some(inner_ptr) = NonNull::new(ptr.as_ptr().wrapping_sub(prefix(layout))) else { return false; };
+        self.0.has_allocated(inner_ptr, inner_layout)
+    }
+
+    fn allows_fallback(&self, layout: alloc::Layout) -> bool {
+        inner(layout).is_none_or(|x| self.0.allows_fallback(x))
+    }
+}
+
+unsafe impl<A: Allocator, H: CorruptionHandler> Allocator for Canary<A, H> {
+    fn allocate(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let block = self.0.allocate(inner(layout).ok_or(AllocError)?)?;
+        Ok(unsafe { self.arm(block, layout) })
+    }
+
+    fn allocate_zeroed(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let block = self.0.allocate_zeroed(inner(layout).ok_or(AllocError)?)?;
+        Ok(unsafe { self.arm(block, layout) })
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: alloc::Layout) {
+        self.check(ptr, layout);
+        self.0.deallocate(ptr.sub(prefix(layout)), inner(layout).unwrap());
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.grow_with(ptr, old_layout, new_layout, false)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.grow_with(ptr, old_layout, new_layout, true)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.check(ptr, old_layout);
+        let prefix = prefix(old_layout);
+        if prefix != self::prefix(new_layout) {
+            return self.move_block(ptr, old_layout, new_layout, false);
+        }
+        let block = self.0.shrink(ptr.sub(prefix), inner(old_layout).unwrap(), inner(new_layout).unwrap())?;
+        let ptr = block.as_non_null_ptr().add(prefix);
+        ptr.add(new_layout.size()).write_bytes(CANARY_BYTE, CANARY_SIZE);
+        Ok(NonNull::slice_from_raw_parts(ptr, new_layout.size()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::System;
+    use crate::canary::*;
+    use crate::corruption::test::{recorded, recording};
+    use core::slice;
+
+    fn canary() -> Canary<System, CallbackOnCorruption> {
+        Canary(System, recording())
+    }
+
+    fn layout(size: usize, align: usize) -> alloc::Layout {
+        alloc::Layout::from_size_align(size, align).unwrap()
+    }
+
+    unsafe fn assert_armed(ptr: NonNull<u8>, layout: alloc::Layout) {
+        let before = slice::from_raw_parts(ptr.as_ptr().sub(prefix(layout)), prefix(layout));
+        let after = slice::from_raw_parts(ptr.as_ptr().add(layout.size()), CANARY_SIZE);
+        assert!(before.iter().chain(after).all(|&x| x == CANARY_BYTE));
+    }
+
+    #[test]
+    fn reports_one_byte_overflow_and_underflow() {
+        let canary = canary();
+        let layout = layout(24, 8);
+        let ptr = canary.allocate(layout).unwrap().as_non_null_ptr();
+        unsafe { ptr.write_bytes(0, layout.size()); }
+        unsafe { canary.deallocate(ptr, layout); }
+        assert_eq!(recorded(), []);
+        let ptr = canary.allocate(layout).unwrap().as_non_null_ptr();
+        unsafe { ptr.add(layout.size()).write(0); }
+        unsafe { canary.deallocate(ptr, layout); }
+        assert_eq!(recorded(), [Corruption { kind: CorruptionKind::Overflow, ptr, layout, offset: 24 }]);
+        let ptr = canary.allocate(layout).unwrap().as_non_null_ptr();
+        unsafe { ptr.sub(1).write(0); }
+        unsafe { canary.deallocate(ptr, layout); }
+        assert_eq!(recorded(), [Corruption { kind: CorruptionKind::Underflow, ptr, layout, offset: -1 }]);
+    }
+
+    #[test]
+    fn canaries_survive_grow_and_shrink() {
+        let canary = canary();
+        let small = layout(16, 8);
+        let large = layout(64, 8);
+        let ptr = canary.allocate(small).unwrap().as_non_null_ptr();
+        unsafe { ptr.write_bytes(1, small.size()); }
+        let ptr = unsafe { canary.grow(ptr, small, large) }.unwrap().as_non_null_ptr();
+        unsafe { assert_armed(ptr, large); }
+        assert!(unsafe { slice::from_raw_parts(ptr.as_ptr(), small.size()) }.iter().all(|&x| x == 1));
+        unsafe { ptr.write_bytes(2, large.size()); }
+        let ptr = unsafe { canary.shrink(ptr, large, small) }.unwrap().as_non_null_ptr();
+        unsafe { assert_armed(ptr, small); }
+        assert!(unsafe { slice::from_raw_parts(ptr.as_ptr(), small.size()) }.iter().all(|&x| x == 2));
+        let ptr = unsafe { canary.grow_zeroed(ptr, small, large) }.unwrap().as_non_null_ptr();
+        unsafe { assert_armed(ptr, large); }
+        let bytes = unsafe { slice::from_raw_parts(ptr.as_ptr(), large.size()) };
+        assert!(bytes[.. small.size()].iter().all(|&x| x == 2));
+        assert!(bytes[small.size() ..].iter().all(|&x| x == 0));
+        unsafe { canary.deallocate(ptr, large); }
+        assert_eq!(recorded(), []);
+    }
+
+    #[test]
+    fn overflow_past_moved_trailing_canary_is_reported() {
+        let canary = canary();
+        let small = layout(16, 8);
+        let large = layout(64, 8);
+        let ptr = canary.allocate(large).unwrap().as_non_null_ptr();
+        let ptr = unsafe { canary.shrink(ptr, large, small) }.unwrap().as_non_null_ptr();
+        unsafe { ptr.add(small.size()).write(0); }
+        unsafe { canary.deallocate(ptr, small); }
+        assert_eq!(recorded(), [Corruption { kind: CorruptionKind::Overflow, ptr, layout: small, offset: 16 }]);
+    }
+
+    #[test]
+    fn honours_large_alignments() {
+        let canary = canary();
+        for align in [32, 64, 4096] {
+            let layout = layout(40, align);
+            let ptr = canary.allocate(layout).unwrap().as_non_null_ptr();
+            assert_eq!(ptr.as_ptr() as usize % align, 0);
+            unsafe { assert_armed(ptr, layout); }
+            let grown = alloc::Layout::from_size_align(layout.size() * 4, align).unwrap();
+            let ptr = unsafe { canary.grow(ptr, layout, grown) }.unwrap().as_non_null_ptr();
+            assert_eq!(ptr.as_ptr() as usize % align, 0);
+            unsafe { assert_armed(ptr, grown); }
+            unsafe { canary.deallocate(ptr, grown); }
+        }
+        let small = layout(40, 8);
+        let ptr = canary.allocate(small).unwrap().as_non_null_ptr();
+        unsafe { ptr.write_bytes(1, small.size()); }
+        let aligned = layout(160, 64);
+        let ptr = unsafe { canary.grow(ptr, small, aligned) }.unwrap().as_non_null_ptr();
+        assert_eq!(ptr.as_ptr() as usize % 64, 0);
+        unsafe { assert_armed(ptr, aligned); }
+        assert!(unsafe { slice::from_raw_parts(ptr.as_ptr(), small.size()) }.iter().all(|&x| x == 1));
+        unsafe { canary.deallocate(ptr, aligned); }
+        assert_eq!(recorded(), []);
+    }
+}
@@ -0,0 +1,297 @@
+use crate::base::*;
+use core::alloc::{self, AllocError, Allocator};
+use core::cmp::max;
+use core::mem::{align_of, size_of};
+use core::ptr::{self, NonNull, null_mut};
+use core::sync::atomic::AtomicPtr;
+use sync_no_std::mutex::Mutex;
+
+struct Chunk {
+    prev: *mut Chunk,
+    layout: alloc::Layout,
+    allocated: usize,
+    allocations_count: usize,
+}
+
+struct Chunks {
+    top: AtomicPtr<Chunk>,
+    /// An emptied chunk kept to avoid requesting a new one from the base allocator
+    /// when allocations repeatedly cross a chunk boundary.
+    spare: AtomicPtr<Chunk>,
+}
+
+pub struct ChainedStacked<A: Allocator + Clone> {
+    chunks: Mutex<Chunks, A>,
+    chunk_size: usize,
+}
+
+impl<A: Allocator + Clone> Drop for ChainedStacked<A> {
+    fn drop(&mut self) {
+        self.release_spare();
+        let chunks = self.chunks.get_mut().unwrap();
+        assert!(chunks.top.get_mut().is_null(), "memory leaks in ChainedStacked allocator");
+    }
+}
+
+unsafe impl<A: NonUnwinding + Clone> NonUnwinding for ChainedStacked<A> { }
+
+impl<A: Allocator + Clone> ChainedStacked<A> {
+    pub const fn new(chunk_size: usize, base: A) -> Self {
+        ChainedStacked {
+            chunks: Mutex::new_in(Chunks { top: AtomicPtr::new(null_mut()), spare: AtomicPtr::new(null_mut()) }, base),
+            chunk_size,
+        }
+    }
+
+    fn base(&self) -> &A { self.chunks.allocator() }
+
+    /// Returns the kept empty chunk, if any, to the base allocator.
+    pub fn release_spare(&self) {
+        let mut chunks = self.chunks.lock().unwrap();
+        let spare = *chunks.spare.get_mut();
+        if spare.is_null() { return; }
+        *chunks.spare.get_mut() = null_mut();
+        unsafe { self.base().deallocate(NonNull::new_unchecked(spare as *mut u8), (*spare).layout); }
+    }
+
+    unsafe fn find(chunks: &mut Chunks, ptr: NonNull<u8>) -> Option<(*mut *mut Chunk, *mut Chunk)> {
+        let mut link: *mut *mut Chunk = chunks.top.get_mut();
+        while !(*link).is_null() {
+            let chunk = *link;
+            if let Some(offset) = (ptr.as_ptr() as usize).checked_sub(chunk as usize) {
+                // A block never starts at the chunk start, because the chunk header is placed there,
+                // and never starts at the chunk end, see `bump`.
+                if offset != 0 && offset < (*chunk).layout.size() {
+                    return Some((link, chunk));
+                }
+            }
+            link = &raw mut (*chunk).prev;
+        }
+        None
+    }
+
+    unsafe fn bump(chunk: *mut Chunk, layout: alloc::Layout) -> Option<NonNull<[u8]>> {
+        let allocated = (*chunk).allocated;
+        let ptr = (chunk as *mut u8).add(allocated);
+        let padding = (layout.align() - (ptr as usize) % layout.align()) % layout.align();
+        let size = padding.checked_add(layout.size())?;
+        // A zero-sized block at the chunk end would be indistinguishable from a block after the chunk.
+        if padding >= (*chunk).layout.size() - allocated || size > (*chunk).layout.size() - allocated {
+            return None;
+        }
+        (*chunk).allocated = allocated + size;
+        (*chunk).allocations_count += 1;
+        Some(NonNull::slice_from_raw_parts(NonNull::new_unchecked(ptr.add(padding)), layout.size()))
+    }
+
+    fn allocate_in(&self, chunks: &mut Chunks, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let top = *chunks.top.get_mut();
+        if !top.is_null() {
+            if let Some(block) = unsafe { Self::bump(top, layout) } {
+                return Ok(block);
+            }
+        }
+        let size = size_of::<Chunk>()
+            .checked_add(layout.align() - 1)
+            .and_then(|x| x.checked_add(max(layout.size(), 1)))
+            .ok_or(AllocError)?;
+        let spare = *chunks.spare.get_mut();
+        let (chunk, chunk_layout) = if !spare.is_null() && unsafe { (*spare).layout }.size() >= size {
+            *chunks.spare.get_mut() = null_mut();
+            (spare, unsafe { (*spare).layout })
+        } else {
+            let chunk_layout = alloc::Layout::from_size_align(max(size, self.chunk_size), align_of::<Chunk>())
+                .map_err(|_| AllocError)?;
+            let chunk_block = self.base().allocate(chunk_layout)?;
+            let chunk_layout = unsafe { alloc::Layout::from_size_align_unchecked(chunk_block.len(), chunk_layout.align()) };
+            (chunk_block.as_mut_ptr() as *mut Chunk, chunk_layout)
+        };
+        unsafe {
+            ptr::write(chunk, Chunk {
+                prev: top,
+                layout: chunk_layout,
+                allocated: size_of::<Chunk>(),
+                allocations_count: 0,
+            });
+        }
+        *chunks.top.get_mut() = chunk;
+        unsafe { Self::bump(chunk, layout) }.ok_or(AllocError)
+    }
+
+    unsafe fn deallocate_in(&self, chunks: &mut Chunks, ptr: NonNull<u8>, layout: alloc::Layout) {
+        let Some((link, chunk)) = Self::find(chunks, ptr) else { return; };
+        let start_offset = ptr.as_ptr() as usize - chunk as usize;
+        if start_offset + layout.size() == (*chunk).allocated {
+            (*chunk).allocated = start_offset;
+        }
+        (*chunk).allocations_count -= 1;
+        if (*chunk).allocations_count == 0 {
+            *link = (*chunk).prev;
+            let spare = *chunks.spare.get_mut();
+            if spare.is_null() {
+                *chunks.spare.get_mut() = chunk;
+            } else {
+                let chunk_layout = (*chunk).layout;
+                self.base().deallocate(NonNull::new_unchecked(chunk as *mut u8), chunk_layout);
+            }
+        }
+    }
+
+    unsafe fn grow_raw(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout,
+        zeroed: bool,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let mut chunks = self.chunks.lock().unwrap();
+        let (_, chunk) = Self::find(&mut chunks, ptr).ok_or(AllocError)?;
+        let start_offset = ptr.as_ptr() as usize - chunk as usize;
+        let block = if
+            new_layout.align() <= old_layout.align() &&
+            start_offset + old_layout.size() == (*chunk).allocated &&
+            new_layout.size() <= (*chunk).layout.size() - start_offset
+        {
+            (*chunk).allocated = start_offset + new_layout.size();
+            NonNull::slice_from_raw_parts(ptr, new_layout.size())
+        } else {
+            let block = self.allocate_in(&mut chunks, new_layout)?;
+            ptr::copy_nonoverlapping(ptr.as_ptr(), block.as_mut_ptr(), old_layout.size());
+            self.deallocate_in(&mut chunks, ptr, old_layout);
+            block
+        };
+        if zeroed {
+            block.as_mut_ptr().add(old_layout.size()).write_bytes(0, new_layout.size() - old_layout.size());
+        }
+        Ok(block)
+    }
+}
+
+unsafe impl<A: Allocator + Clone> Fallbackable for ChainedStacked<A> {
+    unsafe fn has_allocated(&self, ptr: NonNull<u8>, _layout: alloc::Layout) -> bool {
+        let mut chunks = self.chunks.lock().unwrap();
+        Self::find(&mut chunks, ptr).is_some()
+    }
+
+    fn allows_fallback(&self, _layout: alloc::Layout) -> bool {
+        true
+    }
+}
+
+unsafe impl<A: Allocator + Clone> Allocator for ChainedStacked<A> {
+    fn allocate(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let mut chunks = self.chunks.lock().unwrap();
+        self.allocate_in(&mut chunks, layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: alloc::Layout) {
+        let mut chunks = self.chunks.lock().unwrap();
+        self.deallocate_in(&mut chunks, ptr, layout);
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.grow_raw(ptr, old_layout, new_layout, false)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.grow_raw(ptr, old_layout, new_layout, true)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let mut chunks = self.chunks.lock().unwrap();
+        let (_, chunk) = Self::find(&mut chunks, ptr).ok_or(AllocError)?;
+        if new_layout.align() > old_layout.align() {
+            let block = self.allocate_in(&mut chunks, new_layout)?;
+            ptr::copy_nonoverlapping(ptr.as_ptr(), block.as_mut_ptr(), new_layout.size());
+            self.deallocate_in(&mut chunks, ptr, old_layout);
+            return Ok(block);
+        }
+        let start_offset = ptr.as_ptr() as usize - chunk as usize;
+        let size = if start_offset + old_layout.size() == (*chunk).allocated {
+            (*chunk).allocated = start_offset + new_layout.size();
+            new_layout.size()
+        } else {
+            old_layout.size()
+        };
+        Ok(NonNull::slice_from_raw_parts(ptr, size))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Fallbackable, Stats, System};
+    use crate::chained_stacked::{ChainedStacked, Chunk};
+    use core::alloc::{self, Allocator};
+    use core::mem::size_of;
+    use core::ptr::NonNull;
+
+    const CHUNK_SIZE: usize = 256;
+
+    /// Returns the number of live chunks, not counting the mutex allocated from the base.
+    fn chunks(base: &Stats<System>) -> usize {
+        base.live_blocks() - 1
+    }
+
+    #[test]
+    fn chains_and_releases_chunks() {
+        let base = Stats::new(System);
+        let stacked = ChainedStacked::new(CHUNK_SIZE, &base);
+        let layout = alloc::Layout::from_size_align(100, 1).unwrap();
+        let blocks = [(); 4].map(|_| stacked.allocate(layout).unwrap().as_non_null_ptr());
+        assert_eq!(chunks(&base), 2);
+        for &block in blocks.iter().rev() {
+            unsafe { stacked.deallocate(block, layout); }
+        }
+        assert_eq!(chunks(&base), 1);
+        stacked.release_spare();
+        assert_eq!(chunks(&base), 0);
+    }
+
+    #[test]
+    fn keeps_spare_chunk() {
+        let base = Stats::new(System);
+        let stacked = ChainedStacked::new(CHUNK_SIZE, &base);
+        let layout = alloc::Layout::from_size_align(CHUNK_SIZE - size_of::<Chunk>(), 1).unwrap();
+        let first = stacked.allocate(layout).unwrap().as_non_null_ptr();
+        let allocations = base.snapshot().allocations;
+        for _ in 0 .. 10 {
+            let block = stacked.allocate(layout).unwrap().as_non_null_ptr();
+            unsafe { stacked.deallocate(block, layout); }
+        }
+        assert_eq!(base.snapshot().allocations, allocations + 1);
+        unsafe { stacked.deallocate(first, layout); }
+    }
+
+    #[test]
+    fn owns_only_chunk_blocks() {
+        let stacked = ChainedStacked::new(CHUNK_SIZE, System);
+        let layout = alloc::Layout::from_size_align(CHUNK_SIZE - size_of::<Chunk>(), 1).unwrap();
+        let block = stacked.allocate(layout).unwrap().as_non_null_ptr();
+        unsafe {
+            assert!(stacked.has_allocated(block, layout));
+            let chunk_end = NonNull::new_unchecked(block.as_ptr().add(layout.size()));
+            assert!(!stacked.has_allocated(chunk_end, layout));
+            let empty = alloc::Layout::from_size_align(0, 1).unwrap();
+            let zero_sized = stacked.allocate(empty).unwrap().as_non_null_ptr();
+            assert_ne!(zero_sized, chunk_end);
+            assert!(stacked.has_allocated(zero_sized, empty));
+            stacked.deallocate(zero_sized, empty);
+            stacked.deallocate(block, layout);
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use crate::abort::abort_with;
+use core::alloc;
+use core::fmt::{self, Display, Formatter};
+use core::ptr::NonNull;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CorruptionKind {
+    /// Guard bytes before a block are overwritten.
+    Underflow,
+    /// Guard bytes after a block are overwritten.
+    Overflow,
+    /// A freed block is written.
+    WriteAfterFree,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Corruption {
+    pub kind: CorruptionKind,
+    pub ptr: NonNull<u8>,
+    pub layout: alloc::Layout,
+    /// The first corrupted byte offset from `ptr`.
+    pub offset: isize,
+}
+
+impl Display for Corruption {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            CorruptionKind::Underflow => "buffer underflow",
+            CorruptionKind::Overflow => "buffer overflow",
+            CorruptionKind::WriteAfterFree => "write after free",
+        };
+        write!(f, "{kind} detected: block {:p} {:?}, offset {}", self.ptr, self.layout, self.offset)
+    }
+}
+
+pub trait CorruptionHandler {
+    fn corrupted(&self, corruption: &Corruption);
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct PanicOnCorruption;
+
+impl CorruptionHandler for PanicOnCorruption {
+    fn corrupted(&self, corruption: &Corruption) {
+        panic!("{corruption}");
+    }
+}
+
+/// Aborts the process, regardless of the panic strategy.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct AbortOnCorruption;
+
+impl CorruptionHandler for AbortOnCorruption {
+    fn corrupted(&self, corruption: &Corruption) {
+        abort_with(corruption)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct CallbackOnCorruption(pub fn(&Corruption));
+
+impl CorruptionHandler for CallbackOnCorruption {
+    fn corrupted(&self, corruption: &Corruption) {
+        (self.0)(corruption)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    extern crate std;
+
+    use crate::corruption::*;
+    use core::cell::RefCell;
+    use std::thread_local;
+    use std::vec::Vec;
+
+    thread_local! {
+        static CORRUPTIONS: RefCell<Vec<Corruption>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// A handler recording corruptions, reported in the current thread.
+    pub(crate) fn recording() -> CallbackOnCorruption {
+        CallbackOnCorruption(|corruption| CORRUPTIONS.with_borrow_mut(|x| x.push(*corruption)))
+    }
+
+    /// Returns and forgets corruptions, recorded in the current thread.
+    pub(crate) fn recorded() -> Vec<Corruption> {
+        CORRUPTIONS.take()
+    }
+}
@@ -0,0 +1,245 @@
+use crate::base::*;
+use core::alloc::{self, AllocError, Allocator};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Defines which calls [`FailureInjection`] fails.
+///
+/// Calls are `allocate`, `allocate_zeroed`, `grow`, `grow_zeroed`, and `shrink`,
+/// numbered from `1`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FailurePolicy {
+    /// Fail the `n`th call only.
+    Nth(usize),
+    /// Fail the `n`th, `2n`th, `3n`th, and so on, calls.
+    EveryNth(usize),
+    /// Fail a call with the `percent` probability.
+    /// The result depends only on the `seed` and the call number.
+    Random { seed: u64, percent: u8 },
+    /// Fail all calls requesting more than `size` bytes.
+    AboveSize(usize),
+    /// Fail all `grow` and `grow_zeroed` calls.
+    Grow,
+}
+
+fn splitmix64(x: u64) -> u64 {
+    let x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    let x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+/// Fails some calls, according to the [`FailurePolicy`], and forwards others to the base allocator.
+pub struct FailureInjection<A: Allocator> {
+    policy: FailurePolicy,
+    calls: AtomicUsize,
+    failures: AtomicUsize,
+    base: A,
+}
+
+unsafe impl<A: NonUnwinding> NonUnwinding for FailureInjection<A> { }
+
+impl<A: Allocator> FailureInjection<A> {
+    pub const fn new(policy: FailurePolicy, base: A) -> Self {
+        FailureInjection { policy, calls: AtomicUsize::new(0), failures: AtomicUsize::new(0), base }
+    }
+
+    pub fn policy(&self) -> FailurePolicy { self.policy }
+
+    pub fn base(&self) -> &A { &self.base }
+
+    pub fn calls(&self) -> usize { self.calls.load(Ordering::Relaxed) }
+
+    pub fn failures(&self) -> usize { self.failures.load(Ordering::Relaxed) }
+
+    /// Resets calls and failures counters, so the policy starts over.
+    pub fn reset(&self) {
+        self.calls.store(0, Ordering::Relaxed);
+        self.failures.store(0, Ordering::Relaxed);
+    }
+
+    fn fails(&self, layout: alloc::Layout, grow: bool) -> bool {
+        let call = self.calls.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+        let fails = match self.policy {
+            FailurePolicy::Nth(n) => call == n,
+            FailurePolicy::EveryNth(n) => n != 0 && call % n == 0,
+            FailurePolicy::Random { seed, percent } =>
+                splitmix64(seed ^ splitmix64(call as u64)) % 100 < percent as u64,
+            FailurePolicy::AboveSize(size) => layout.size() > size,
+            FailurePolicy::Grow => grow,
+        };
+        if fails {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        fails
+    }
+}
+
+unsafe impl<A: Fallbackable> Fallbackable for FailureInjection<A> {
+    unsafe fn has_allocated(&self, ptr: NonNull<u8>, layout: alloc::Layout) -> bool {
+        self.base.has_allocated(ptr, layout)
+    }
+
+    fn allows_fallback(&self, layout: alloc::Layout) -> bool {
+        self.base.allows_fallback(layout)
+    }
+}
+
+unsafe impl<A: Allocator> Allocator for FailureInjection<A> {
+    fn allocate(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if self.fails(layout, false) { return Err(AllocError); }
+        self.base.allocate(layout)
+    }
+
+    fn allocate_zeroed(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if self.fails(layout, false) { return Err(AllocError); }
+        self.base.allocate_zeroed(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: alloc::Layout) {
+        self.base.deallocate(ptr, layout);
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if self.fails(new_layout, true) { return Err(AllocError); }
+        self.base.grow(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if self.fails(new_layout, true) { return Err(AllocError); }
+        self.base.grow_zeroed(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if self.fails(new_layout, false) { return Err(AllocError); }
+        self.base.shrink(ptr, old_layout, new_layout)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use crate::{Stats, System};
+    use crate::failure_injection::*;
+    use core::slice;
+    use std::vec::Vec;
+
+    fn layout(size: usize) -> alloc::Layout {
+        alloc::Layout::from_size_align(size, 8).unwrap()
+    }
+
+    /// Makes `sizes.len()` allocations and returns which ones failed.
+    fn failed(allocator: &FailureInjection<Stats<System>>, sizes: &[usize]) -> Vec<bool> {
+        let failed = sizes.iter().map(|&size| match allocator.allocate(layout(size)) {
+            Ok(block) => {
+                unsafe { allocator.deallocate(block.as_non_null_ptr(), layout(size)); }
+                false
+            },
+            Err(AllocError) => true,
+        }).collect();
+        assert_eq!(allocator.base().live_blocks(), 0);
+        failed
+    }
+
+    fn injection(policy: FailurePolicy) -> FailureInjection<Stats<System>> {
+        FailureInjection::new(policy, Stats::new(System))
+    }
+
+    #[test]
+    fn nth_fails_exactly_nth_call() {
+        let allocator = injection(FailurePolicy::Nth(3));
+        let outcomes = failed(&allocator, &[16; 8]);
+        assert_eq!(outcomes, [false, false, true, false, false, false, false, false]);
+        assert_eq!(allocator.calls(), 8);
+        assert_eq!(allocator.failures(), 1);
+        allocator.reset();
+        assert_eq!(allocator.calls(), 0);
+        assert_eq!(allocator.failures(), 0);
+        assert_eq!(failed(&allocator, &[16; 4]), [false, false, true, false]);
+    }
+
+    #[test]
+    fn every_nth_fails_periodically() {
+        let allocator = injection(FailurePolicy::EveryNth(3));
+        let outcomes = failed(&allocator, &[16; 9]);
+        assert_eq!(outcomes, [false, false, true, false, false, true, false, false, true]);
+        assert_eq!(allocator.failures(), 3);
+        let allocator = injection(FailurePolicy::EveryNth(0));
+        assert!(!failed(&allocator, &[16; 4]).contains(&true));
+    }
+
+    #[test]
+    fn random_depends_only_on_seed() {
+        let policy = FailurePolicy::Random { seed: 42, percent: 50 };
+        let first = failed(&injection(policy), &[16; 64]);
+        let second = failed(&injection(policy), &[16; 64]);
+        assert_eq!(first, second);
+        assert!(first.contains(&true) && first.contains(&false));
+        let other = failed(&injection(FailurePolicy::Random { seed: 43, percent: 50 }), &[16; 64]);
+        assert_ne!(first, other);
+        let never = failed(&injection(FailurePolicy::Random { seed: 42, percent: 0 }), &[16; 64]);
+        assert!(!never.contains(&true));
+        let always = failed(&injection(FailurePolicy::Random { seed: 42, percent: 100 }), &[16; 64]);
+        assert!(!always.contains(&false));
+    }
+
+    #[test]
+    fn above_size_fails_only_above_threshold() {
+        let allocator = injection(FailurePolicy::AboveSize(32));
+        let outcomes = failed(&allocator, &[0, 16, 32, 33, 64, 31]);
+        assert_eq!(outcomes, [false, false, false, true, true, false]);
+        assert_eq!(allocator.failures(), 2);
+    }
+
+    #[test]
+    fn failed_resize_keeps_base_consistent() {
+        let allocator = injection(FailurePolicy::EveryNth(2));
+        let small = layout(16);
+        let large = layout(64);
+        let ptr = allocator.allocate(small).unwrap().as_non_null_ptr();
+        unsafe { ptr.write_bytes(1, small.size()); }
+        assert!(unsafe { allocator.grow(ptr, small, large) }.is_err());
+        let ptr = unsafe { allocator.grow(ptr, small, large) }.unwrap().as_non_null_ptr();
+        assert!(unsafe { allocator.grow_zeroed(ptr, large, layout(128)) }.is_err());
+        assert!(unsafe { slice::from_raw_parts(ptr.as_ptr(), small.size()) }.iter().all(|&x| x == 1));
+        let counters = allocator.base().snapshot();
+        assert_eq!((counters.grows, counters.grow_failures, counters.live_bytes), (1, 0, 64));
+        let ptr = unsafe { allocator.shrink(ptr, large, small) }.unwrap().as_non_null_ptr();
+        assert!(unsafe { allocator.shrink(ptr, small, layout(8)) }.is_err());
+        assert!(unsafe { slice::from_raw_parts(ptr.as_ptr(), small.size()) }.iter().all(|&x| x == 1));
+        let counters = allocator.base().snapshot();
+        assert_eq!((counters.shrinks, counters.shrink_failures, counters.live_bytes), (1, 0, 16));
+        unsafe { allocator.deallocate(ptr, small); }
+        assert_eq!(allocator.base().live_blocks(), 0);
+        assert_eq!(allocator.base().snapshot().live_bytes, 0);
+        assert_eq!(allocator.failures(), 3);
+    }
+
+    #[test]
+    fn grow_policy_fails_only_grows() {
+        let allocator = injection(FailurePolicy::Grow);
+        let ptr = allocator.allocate(layout(16)).unwrap().as_non_null_ptr();
+        assert!(unsafe { allocator.grow(ptr, layout(16), layout(32)) }.is_err());
+        assert!(unsafe { allocator.grow_zeroed(ptr, layout(16), layout(32)) }.is_err());
+        let ptr = unsafe { allocator.shrink(ptr, layout(16), layout(8)) }.unwrap().as_non_null_ptr();
+        unsafe { allocator.deallocate(ptr, layout(8)); }
+        assert_eq!(allocator.base().live_blocks(), 0);
+        assert_eq!(allocator.failures(), 2);
+    }
+}
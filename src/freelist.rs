@@ -1,10 +1,12 @@
 use crate::base::*;
-use const_default::ConstDefault;
 use const_default_derive::ConstDefault;
 use core::alloc::{self, AllocError, Allocator};
+use core::cell::RefCell;
+use core::cmp::{max, min};
 use core::mem::{align_of, size_of};
+use core::ops::DerefMut;
 use core::ptr::{self, NonNull, null_mut};
-use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 use sync_no_std::mutex::Mutex;
 
 pub const MIN_LAYOUT_SIZE: usize = size_of::<Node>();
@@ -15,56 +17,55 @@ pub const MIN_LAYOUT_ALIGN: usize = align_of::<Node>();
 ///
 /// This trait cannot be implemented outside of this module.
 pub unsafe trait LimitParam {
-    #[doc(hidden)]
-    type ListLen: ConstDefault + Copy;
-
-    #[doc(hidden)]
-    #[allow(clippy::missing_safety_doc)]
-    unsafe fn limit_reached(&self, list_len: Self::ListLen) -> bool;
-
-    #[doc(hidden)]
-    #[allow(clippy::missing_safety_doc)]
-    unsafe fn dec_list_len(&self, list_len: Self::ListLen) -> Self::ListLen;
-
     #[doc(hidden)]
     #[allow(clippy::missing_safety_doc)]
-    unsafe fn inc_list_len(&self, list_len: Self::ListLen) -> Self::ListLen;
+    unsafe fn limit_reached(&self, list_len: usize) -> bool;
 }
 
-#[derive(ConstDefault)]
+#[derive(ConstDefault, Debug, Copy, Clone)]
 pub struct NoLimit;
 
 unsafe impl LimitParam for NoLimit {
-    type ListLen = ();
-
-    unsafe fn limit_reached(&self, (): Self::ListLen) -> bool { false }
-
-    unsafe fn dec_list_len(&self, (): Self::ListLen) -> Self::ListLen { }
-
-    unsafe fn inc_list_len(&self, (): Self::ListLen) -> Self::ListLen { }
+    unsafe fn limit_reached(&self, _list_len: usize) -> bool { false }
 }
 
-#[doc(hidden)]
-#[derive(ConstDefault, Clone, Copy)]
-pub struct FixedLimitListLen(usize);
-
+#[derive(Debug, Copy, Clone)]
 pub struct FixedLimit {
     limit: usize,
 }
 
-unsafe impl LimitParam for FixedLimit {
-    type ListLen = FixedLimitListLen;
+impl FixedLimit {
+    pub const fn new(limit: usize) -> Self {
+        FixedLimit { limit }
+    }
 
-    unsafe fn limit_reached(&self, list_len: Self::ListLen) -> bool {
-        list_len.0 == self.limit
+    pub fn get(&self) -> usize { self.limit }
+}
+
+unsafe impl LimitParam for FixedLimit {
+    unsafe fn limit_reached(&self, list_len: usize) -> bool {
+        list_len >= self.limit
     }
+}
 
-    unsafe fn dec_list_len(&self, list_len: Self::ListLen) -> Self::ListLen {
-        FixedLimitListLen(list_len.0 - 1)
+/// A limit, which can be changed at runtime with [`Freelist::set_limit`].
+pub struct DynamicLimit {
+    limit: AtomicUsize,
+}
+
+impl DynamicLimit {
+    pub const fn new(limit: usize) -> Self {
+        DynamicLimit { limit: AtomicUsize::new(limit) }
     }
 
-    unsafe fn inc_list_len(&self, list_len: Self::ListLen) -> Self::ListLen {
-        FixedLimitListLen(list_len.0 + 1)
+    pub fn get(&self) -> usize { self.limit.load(Ordering::Relaxed) }
+
+    pub(crate) fn set(&self, limit: usize) { self.limit.store(limit, Ordering::Relaxed) }
+}
+
+unsafe impl LimitParam for DynamicLimit {
+    unsafe fn limit_reached(&self, list_len: usize) -> bool {
+        list_len >= self.limit.load(Ordering::Relaxed)
     }
 }
 
@@ -72,16 +73,331 @@ struct Node {
     next: AtomicPtr<u8>,
 }
 
-struct List<Limit: LimitParam> {
+/// Batch header, placed at a batch start.
+///
+/// Batches are aligned to their power of two size, so a block batch is found by masking the block address.
+struct Batch {
+    next: *mut Batch,
+    cached: usize,
+}
+
+pub(crate) struct List {
     head: Node,
-    len: <Limit as LimitParam>::ListLen,
+    len: usize,
+    batches: AtomicPtr<Batch>,
 }
 
-pub struct Freelist<Limit: LimitParam, A: Allocator + Clone> {
-    list: Mutex<List<Limit>, A>,
+impl List {
+    pub(crate) const fn new() -> Self {
+        List {
+            head: Node { next: AtomicPtr::new(null_mut()) },
+            len: 0,
+            batches: AtomicPtr::new(null_mut()),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize { self.len }
+}
+
+/// Provides exclusive access to a free blocks list.
+pub(crate) trait ListLock {
+    fn lock_list(&self) -> impl DerefMut<Target=List>;
+}
+
+impl<A: Allocator + Clone> ListLock for Mutex<List, A> {
+    fn lock_list(&self) -> impl DerefMut<Target=List> { self.lock().unwrap() }
+}
+
+impl ListLock for RefCell<List> {
+    fn lock_list(&self) -> impl DerefMut<Target=List> { self.borrow_mut() }
+}
+
+/// Freelist logic shared by [`Freelist`] and [`LocalFreelist`](crate::local_freelist::LocalFreelist),
+/// which differ only in the way the list is locked.
+pub(crate) struct Core<Limit: LimitParam> {
     layout: alloc::Layout,
     tolerance: alloc::Layout,
     limit: Limit,
+    batch: usize,
+}
+
+impl<Limit: LimitParam> Core<Limit> {
+    pub(crate) const fn new(layout: alloc::Layout, tolerance: alloc::Layout, limit: Limit, batch: usize) -> Self {
+        Core { layout, tolerance, limit, batch }
+    }
+
+    pub(crate) fn layout(&self) -> alloc::Layout { self.layout }
+
+    pub(crate) fn limit(&self) -> &Limit { &self.limit }
+
+    pub(crate) fn tolerance(&self) -> alloc::Layout { self.tolerance }
+
+    fn manages(&self, layout: alloc::Layout) -> bool {
+        (self.tolerance.size() ..= self.layout.size()).contains(&layout.size()) &&
+        (self.tolerance.align() ..= self.layout.size()).contains(&layout.align())
+    }
+
+    fn is_batched(&self) -> bool { self.batch > 1 }
+
+    /// Returns the batch of a block, the block should be allocated by a batched freelist.
+    fn batch_of(&self, ptr: NonNull<u8>) -> *mut Batch {
+        let (layout, _, _, _) = self.batch_layout().unwrap();
+        ptr.as_ptr().map_addr(|x| x & !(layout.size() - 1)) as *mut Batch
+    }
+
+    unsafe fn pop(&self, list: &mut List) -> Option<NonNull<u8>> {
+        let next_ptr = NonNull::new(*list.head.next.get_mut())?;
+        let next = ptr::read(next_ptr.as_ptr() as *const Node).next;
+        list.head = Node { next };
+        list.len -= 1;
+        if self.is_batched() {
+            (*self.batch_of(next_ptr)).cached -= 1;
+        }
+        Some(next_ptr)
+    }
+
+    unsafe fn push(&self, list: &mut List, ptr: NonNull<u8>) {
+        ptr::write(ptr.as_ptr() as *mut Node, Node { next: AtomicPtr::new(*list.head.next.get_mut()) });
+        *list.head.next.get_mut() = ptr.as_ptr();
+        list.len += 1;
+        if self.is_batched() {
+            (*self.batch_of(ptr)).cached += 1;
+        }
+    }
+
+    unsafe fn retain(list: &mut List, mut f: impl FnMut(NonNull<u8>) -> bool) {
+        let mut link: *mut *mut u8 = list.head.next.get_mut();
+        while let Some(node) = NonNull::new(*link) {
+            let node = node.as_ptr() as *mut Node;
+            let next = *(*node).next.get_mut();
+            if f(NonNull::new_unchecked(node as *mut u8)) {
+                link = (*node).next.get_mut();
+            } else {
+                *link = next;
+                list.len -= 1;
+            }
+        }
+    }
+
+    /// Releases cached free blocks to the base allocator, keeping at most `keep` of them.
+    pub(crate) unsafe fn release(&self, list: &mut List, keep: usize, base: &impl Allocator) {
+        if !self.is_batched() {
+            let mut excess = list.len.saturating_sub(keep);
+            Self::retain(list, |ptr| {
+                if excess == 0 { return true; }
+                base.deallocate(ptr, self.layout);
+                excess -= 1;
+                false
+            });
+            return;
+        }
+        let (layout, _, _, blocks) = self.batch_layout().unwrap();
+        let mut link: *mut *mut Batch = list.batches.get_mut();
+        while list.len > keep && !(*link).is_null() {
+            let batch = *link;
+            if (*batch).cached == blocks {
+                *link = (*batch).next;
+                Self::retain(list, |ptr| self.batch_of(ptr) != batch);
+                base.deallocate(NonNull::new_unchecked(batch as *mut u8), layout);
+            } else {
+                link = &raw mut (*batch).next;
+            }
+        }
+    }
+
+    /// Returns the batch layout, blocks offset and stride, and the number of blocks in a batch.
+    fn batch_layout(&self) -> Option<(alloc::Layout, usize, usize, usize)> {
+        let align = max(self.layout.align(), align_of::<Batch>());
+        let offset = size_of::<Batch>().checked_next_multiple_of(align)?;
+        let stride = self.layout.pad_to_align().size();
+        let size = stride.checked_mul(self.batch)?.checked_add(offset)?.checked_next_power_of_two()?;
+        Some((alloc::Layout::from_size_align(size, size).ok()?, offset, stride, (size - offset) / stride))
+    }
+
+    fn allocate_batch(&self, list: &mut List, base: &impl Allocator) -> Result<NonNull<u8>, AllocError> {
+        if !self.is_batched() {
+            return base.allocate(self.layout).map(|x| x.as_non_null_ptr());
+        }
+        let (layout, offset, stride, blocks) = self.batch_layout().ok_or(AllocError)?;
+        let batch = base.allocate(layout)?.as_mut_ptr();
+        unsafe {
+            ptr::write(batch as *mut Batch, Batch {
+                next: *list.batches.get_mut(),
+                cached: 0,
+            });
+            *list.batches.get_mut() = batch as *mut Batch;
+            for i in 1 .. blocks {
+                self.push(list, NonNull::new_unchecked(batch.add(offset + i * stride)));
+            }
+            Ok(NonNull::new_unchecked(batch.add(offset)))
+        }
+    }
+
+    /// Resizes a block of a batched freelist, when the old or the new layout is managed.
+    ///
+    /// A batched block can only be resized in place within the managed layouts range,
+    /// otherwise it is moved.
+    unsafe fn resize_batched(
+        &self,
+        list: &impl ListLock,
+        base: &impl Allocator,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout,
+        zeroed: bool
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if self.manages(old_layout) && self.manages(new_layout) {
+            if zeroed && new_layout.size() > old_layout.size() {
+                ptr.add(old_layout.size()).write_bytes(0, self.layout.size() - old_layout.size());
+            }
+            return Ok(NonNull::slice_from_raw_parts(ptr, self.layout.size()));
+        }
+        let block = if zeroed {
+            self.allocate_zeroed(list, base, new_layout)?
+        } else {
+            self.allocate(list, base, new_layout)?
+        };
+        ptr::copy_nonoverlapping(ptr.as_ptr(), block.as_mut_ptr(), min(old_layout.size(), new_layout.size()));
+        self.deallocate(list, base, ptr, old_layout);
+        Ok(block)
+    }
+
+    pub(crate) unsafe fn has_allocated(&self, base: &impl Fallbackable, ptr: NonNull<u8>, layout: alloc::Layout) -> bool {
+        if !self.manages(layout) { return base.has_allocated(ptr, layout); }
+        if self.is_batched() {
+            let Some((batch_layout, _, _, _)) = self.batch_layout() else { return false; };
+            return base.has_allocated(NonNull::new_unchecked(self.batch_of(ptr) as *mut u8), batch_layout);
+        }
+        base.has_allocated(ptr, self.layout)
+    }
+
+    pub(crate) fn allows_fallback(&self, base: &impl Fallbackable, layout: alloc::Layout) -> bool {
+        let layout = if self.manages(layout) { self.layout } else { layout };
+        base.allows_fallback(layout)
+    }
+
+    pub(crate) fn allocate(
+        &self,
+        list: &impl ListLock,
+        base: &impl Allocator,
+        layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if !self.manages(layout) {
+            return base.allocate(layout);
+        }
+        let mut list = list.lock_list();
+        let ptr = if let Some(next_ptr) = unsafe { self.pop(&mut list) } {
+            next_ptr
+        } else {
+            self.allocate_batch(&mut list, base)?
+        };
+        Ok(NonNull::slice_from_raw_parts(ptr, self.layout.size()))
+    }
+
+    pub(crate) fn allocate_zeroed(
+        &self,
+        list: &impl ListLock,
+        base: &impl Allocator,
+        layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if !self.manages(layout) {
+            return base.allocate_zeroed(layout);
+        }
+        let mut list = list.lock_list();
+        let ptr = if let Some(next_ptr) = unsafe { self.pop(&mut list) } {
+            next_ptr
+        } else if !self.is_batched() {
+            return base.allocate_zeroed(self.layout);
+        } else {
+            self.allocate_batch(&mut list, base)?
+        };
+        let ptr = NonNull::slice_from_raw_parts(ptr, self.layout.size());
+        unsafe { ptr.as_mut_ptr().write_bytes(0, ptr.len()); }
+        Ok(ptr)
+    }
+
+    pub(crate) unsafe fn deallocate(
+        &self,
+        list: &impl ListLock,
+        base: &impl Allocator,
+        ptr: NonNull<u8>,
+        layout: alloc::Layout
+    ) {
+        if !self.manages(layout) {
+            return base.deallocate(ptr, layout);
+        }
+        let mut list = list.lock_list();
+        if !self.is_batched() && self.limit.limit_reached(list.len) {
+            return base.deallocate(ptr, self.layout);
+        }
+        self.push(&mut list, ptr);
+    }
+
+    pub(crate) unsafe fn grow(
+        &self,
+        list: &impl ListLock,
+        base: &impl Allocator,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if self.is_batched() && (self.manages(old_layout) || self.manages(new_layout)) {
+            return self.resize_batched(list, base, ptr, old_layout, new_layout, false);
+        }
+        let old_layout = if self.manages(old_layout) { self.layout } else { old_layout };
+        base.grow(ptr, old_layout, new_layout)
+    }
+
+    pub(crate) unsafe fn grow_zeroed(
+        &self,
+        list: &impl ListLock,
+        base: &impl Allocator,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if self.is_batched() && (self.manages(old_layout) || self.manages(new_layout)) {
+            return self.resize_batched(list, base, ptr, old_layout, new_layout, true);
+        }
+        let old_layout = if self.manages(old_layout) { self.layout } else { old_layout };
+        base.grow_zeroed(ptr, old_layout, new_layout)
+    }
+
+    pub(crate) unsafe fn shrink(
+        &self,
+        list: &impl ListLock,
+        base: &impl Allocator,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if self.is_batched() && self.manages(old_layout) != self.manages(new_layout) {
+            return self.resize_batched(list, base, ptr, old_layout, new_layout, false);
+        }
+        let old_layout = if self.manages(old_layout) {
+            if self.manages(new_layout) {
+                return Ok(NonNull::slice_from_raw_parts(ptr, self.layout.size()));
+            }
+            self.layout
+        } else {
+            old_layout
+        };
+        base.shrink(ptr, old_layout, new_layout)
+    }
+}
+
+impl Core<DynamicLimit> {
+    /// Changes the limit, releasing cached blocks exceeding the new limit to the base allocator.
+    pub(crate) fn set_limit(&self, list: &impl ListLock, base: &impl Allocator, limit: usize) {
+        let mut list = list.lock_list();
+        self.limit.set(limit);
+        unsafe { self.release(&mut list, limit, base); }
+    }
+}
+
+pub struct Freelist<Limit: LimitParam, A: Allocator + Clone> {
+    list: Mutex<List, A>,
+    core: Core<Limit>,
 }
 
 unsafe impl<Limit: LimitParam, A: NonUnwinding + Clone> NonUnwinding for Freelist<Limit, A> { }
@@ -93,6 +409,27 @@ impl<Limit: LimitParam, A: Allocator + Clone> Freelist<Limit, A> {
         unsafe { Self::new_unchecked(layout, tolerance, limit, base) }
     }
 
+    /// Creates a freelist, which, when there are no cached blocks,
+    /// requests memory for at least `batch` blocks at once from the base allocator.
+    ///
+    /// A batch size is rounded up to a power of two, and the batch is aligned to its size,
+    /// so the base allocator should support such alignment.
+    ///
+    /// Blocks carved from a batch cannot be released to the base allocator one by one,
+    /// so they are cached regardless of the limit,
+    /// and are released by whole batches, when all blocks of a batch are cached.
+    pub const fn new_batched(
+        layout: alloc::Layout,
+        tolerance: alloc::Layout,
+        limit: Limit,
+        batch: usize,
+        base: A
+    ) -> Self {
+        assert!(tolerance.size() <= layout.size() && tolerance.align() <= layout.align());
+        assert!(layout.size() >= MIN_LAYOUT_SIZE && layout.align() >= MIN_LAYOUT_ALIGN);
+        unsafe { Self::new_batched_unchecked(layout, tolerance, limit, batch, base) }
+    }
+
     /// # Safety
     ///
     /// Arguments should satisfy
@@ -100,78 +437,84 @@ impl<Limit: LimitParam, A: Allocator + Clone> Freelist<Limit, A> {
     /// and
     /// `layout.size() >= MIN_LAYOUT_SIZE && layout.align() >= MIN_LAYOUT_ALIGN`.
     pub const unsafe fn new_unchecked(layout: alloc::Layout, tolerance: alloc::Layout, limit: Limit, base: A) -> Self {
+        Self::new_batched_unchecked(layout, tolerance, limit, 1, base)
+    }
+
+    /// # Safety
+    ///
+    /// Arguments should satisfy
+    /// `tolerance.size() <= layout.size() && tolerance.align() <= layout.align()`,
+    /// and
+    /// `layout.size() >= MIN_LAYOUT_SIZE && layout.align() >= MIN_LAYOUT_ALIGN`.
+    pub const unsafe fn new_batched_unchecked(
+        layout: alloc::Layout,
+        tolerance: alloc::Layout,
+        limit: Limit,
+        batch: usize,
+        base: A
+    ) -> Self {
         Freelist {
-            list: Mutex::new_in(List {
-                head: Node { next: AtomicPtr::new(null_mut()) },
-                len: ConstDefault::DEFAULT,
-            }, base),
-            layout,
-            tolerance,
-            limit,
+            list: Mutex::new_in(List::new(), base),
+            core: Core::new(layout, tolerance, limit, batch),
         }
     }
 
-    fn manages(&self, layout: alloc::Layout) -> bool {
-        (self.tolerance.size() ..= self.layout.size()).contains(&layout.size()) &&
-        (self.tolerance.align() ..= self.layout.size()).contains(&layout.align())
-    }
+    pub fn layout(&self) -> alloc::Layout { self.core.layout() }
+
+    pub fn limit(&self) -> &Limit { self.core.limit() }
+
+    pub fn tolerance(&self) -> alloc::Layout { self.core.tolerance() }
 
     fn base(&self) -> &A { self.list.allocator() }
+
+    /// Returns the number of cached free blocks.
+    pub fn cached_len(&self) -> usize {
+        self.list.lock().unwrap().len
+    }
+
+    /// Releases cached free blocks to the base allocator, keeping at most `keep` of them.
+    pub fn trim(&self, keep: usize) {
+        let mut list = self.list.lock().unwrap();
+        unsafe { self.core.release(&mut list, keep, self.base()); }
+    }
+
+    /// Releases all cached free blocks to the base allocator.
+    ///
+    /// Cached blocks are not released on drop, so this should be called explicitly,
+    /// if the base allocator outlives the freelist.
+    pub fn release_all(&self) {
+        self.trim(0);
+    }
+}
+
+impl<A: Allocator + Clone> Freelist<DynamicLimit, A> {
+    /// Changes the limit, releasing cached blocks exceeding the new limit to the base allocator.
+    pub fn set_limit(&self, limit: usize) {
+        self.core.set_limit(&self.list, self.base(), limit);
+    }
 }
 
 unsafe impl<Limit: LimitParam, A: Fallbackable + Clone> Fallbackable for Freelist<Limit, A> {
     unsafe fn has_allocated(&self, ptr: NonNull<u8>, layout: alloc::Layout) -> bool {
-        let layout = if self.manages(layout) { self.layout } else { layout };
-        self.base().has_allocated(ptr, layout)
+        self.core.has_allocated(self.base(), ptr, layout)
     }
 
     fn allows_fallback(&self, layout: alloc::Layout) -> bool {
-        let layout = if self.manages(layout) { self.layout } else { layout };
-        self.base().allows_fallback(layout)
+        self.core.allows_fallback(self.base(), layout)
     }
 }
 
 unsafe impl<Limit: LimitParam, A: Allocator + Clone> Allocator for Freelist<Limit, A> {
     fn allocate(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
-        if !self.manages(layout) {
-            return self.base().allocate(layout);
-        }
-        let mut list = self.list.lock().unwrap();
-        if let Some(next_ptr) = NonNull::new(*list.head.next.get_mut()) {
-            let next = unsafe { ptr::read(next_ptr.as_ptr() as *const Node) }.next;
-            list.head = Node { next };
-            list.len = unsafe { self.limit.dec_list_len(list.len) };
-            Ok(NonNull::slice_from_raw_parts(next_ptr, self.layout.size()))
-        } else {
-            self.base().allocate(self.layout)
-        }
+        self.core.allocate(&self.list, self.base(), layout)
     }
 
     fn allocate_zeroed(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
-        if !self.manages(layout) {
-            return self.base().allocate_zeroed(layout);
-        }
-        let mut list = self.list.lock().unwrap();
-        if let Some(next_ptr) = NonNull::new(*list.head.next.get_mut()) {
-            let next = unsafe { ptr::read(next_ptr.as_ptr() as *const Node) }.next;
-            list.head = Node { next };
-            list.len = unsafe { self.limit.dec_list_len(list.len) };
-            let ptr = NonNull::slice_from_raw_parts(next_ptr, self.layout.size());
-            unsafe { ptr.as_mut_ptr().write_bytes(0, ptr.len()); }
-            Ok(ptr)
-        } else {
-            self.base().allocate_zeroed(self.layout)
-        }
+        self.core.allocate_zeroed(&self.list, self.base(), layout)
     }
 
     unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: alloc::Layout) {
-        let mut list = self.list.lock().unwrap();
-        if self.limit.limit_reached(list.len) || !self.manages(layout) {
-            return self.base().deallocate(ptr, layout);
-        }
-        ptr::write(ptr.as_ptr() as *mut Node, Node { next: AtomicPtr::new(*list.head.next.get_mut()) });
-        *list.head.next.get_mut() = ptr.as_ptr();
-        list.len = unsafe { self.limit.inc_list_len(list.len) };
+        self.core.deallocate(&self.list, self.base(), ptr, layout)
     }
 
     unsafe fn grow(
@@ -180,8 +523,7 @@ unsafe impl<Limit: LimitParam, A: Allocator + Clone> Allocator for Freelist<Limi
         old_layout: alloc::Layout, 
         new_layout: alloc::Layout
     ) -> Result<NonNull<[u8]>, AllocError> {
-        let old_layout = if self.manages(old_layout) { self.layout } else { old_layout };
-        self.base().grow(ptr, old_layout, new_layout)
+        self.core.grow(&self.list, self.base(), ptr, old_layout, new_layout)
     }
 
     unsafe fn grow_zeroed(
@@ -190,8 +532,7 @@ unsafe impl<Limit: LimitParam, A: Allocator + Clone> Allocator for Freelist<Limi
         old_layout: alloc::Layout, 
         new_layout: alloc::Layout
     ) -> Result<NonNull<[u8]>, AllocError> {
-        let old_layout = if self.manages(old_layout) { self.layout } else { old_layout };
-        self.base().grow_zeroed(ptr, old_layout, new_layout)
+        self.core.grow_zeroed(&self.list, self.base(), ptr, old_layout, new_layout)
     }
 
     unsafe fn shrink(
@@ -200,14 +541,91 @@ unsafe impl<Limit: LimitParam, A: Allocator + Clone> Allocator for Freelist<Limi
         old_layout: alloc::Layout, 
         new_layout: alloc::Layout
     ) -> Result<NonNull<[u8]>, AllocError> {
-        let old_layout = if self.manages(old_layout) {
-            if self.manages(new_layout) {
-                return Ok(NonNull::slice_from_raw_parts(ptr, self.layout.size()));
+        self.core.shrink(&self.list, self.base(), ptr, old_layout, new_layout)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Fallbackable, Stats, System};
+    use crate::freelist::{FixedLimit, Freelist, NoLimit};
+    use crate::stacked;
+    use core::alloc::{self, Allocator};
+    use core::ptr::NonNull;
+
+    #[test]
+    fn trim_and_release_all() {
+        let base = Stats::new(System);
+        let layout = alloc::Layout::from_size_align(32, 8).unwrap();
+        let freelist = Freelist::new(layout, layout, NoLimit, &base);
+        let blocks = [(); 8].map(|_| freelist.allocate(layout).unwrap().as_non_null_ptr());
+        let live_blocks = base.live_blocks();
+        for block in blocks {
+            unsafe { freelist.deallocate(block, layout); }
+        }
+        assert_eq!(freelist.cached_len(), 8);
+        assert_eq!(base.live_blocks(), live_blocks);
+        freelist.trim(3);
+        assert_eq!(freelist.cached_len(), 3);
+        assert_eq!(base.live_blocks(), live_blocks - 5);
+        freelist.release_all();
+        assert_eq!(freelist.cached_len(), 0);
+        assert_eq!(base.live_blocks(), live_blocks - 8);
+    }
+
+    #[test]
+    fn limit_bounds_cache() {
+        let base = Stats::new(System);
+        let layout = alloc::Layout::from_size_align(16, 8).unwrap();
+        let freelist = Freelist::new(layout, layout, FixedLimit::new(2), &base);
+        let blocks = [(); 5].map(|_| freelist.allocate(layout).unwrap().as_non_null_ptr());
+        for block in blocks {
+            unsafe { freelist.deallocate(block, layout); }
+        }
+        assert_eq!(freelist.cached_len(), 2);
+        freelist.release_all();
+    }
+
+    #[test]
+    fn batched_blocks_are_cached_and_released() {
+        let base = Stats::new(System);
+        let layout = alloc::Layout::from_size_align(32, 8).unwrap();
+        let freelist = Freelist::new_batched(layout, layout, FixedLimit::new(1), 4, &base);
+        let blocks = [(); 8].map(|_| freelist.allocate(layout).unwrap().as_non_null_ptr());
+        let live_batches = base.live_blocks();
+        for block in blocks {
+            unsafe { freelist.deallocate(block, layout); }
+        }
+        assert!(freelist.cached_len() >= 8);
+        assert_eq!(base.live_blocks(), live_batches);
+        freelist.release_all();
+        assert_eq!(freelist.cached_len(), 0);
+        assert!(base.live_blocks() < live_batches);
+        let block = freelist.allocate(layout).unwrap().as_non_null_ptr();
+        unsafe { freelist.deallocate(block, layout); }
+        freelist.release_all();
+    }
+
+    #[test]
+    fn batched_blocks_are_allocated_by_freelist() {
+        stacked::with_size::<4096, _>(|base| {
+            let layout = alloc::Layout::from_size_align(32, 8).unwrap();
+            let tolerance = alloc::Layout::from_size_align(16, 8).unwrap();
+            let freelist = Freelist::new_batched(layout, tolerance, NoLimit, 4, base);
+            let blocks = [(); 6].map(|_| freelist.allocate(layout).unwrap().as_non_null_ptr());
+            for block in blocks {
+                assert!(unsafe { freelist.has_allocated(block, layout) });
             }
-            self.layout
-        } else {
-            old_layout
-        };
-        self.base().shrink(ptr, old_layout, new_layout)
+            let grown = unsafe {
+                freelist.grow(blocks[0], tolerance, layout)
+            }.unwrap();
+            assert_eq!(grown.as_non_null_ptr(), blocks[0]);
+            let outside = 0u64;
+            assert!(!unsafe { freelist.has_allocated(NonNull::from(&outside).cast(), layout) });
+            for block in blocks {
+                unsafe { freelist.deallocate(block, layout); }
+            }
+            freelist.release_all();
+        });
     }
 }
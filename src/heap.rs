@@ -0,0 +1,462 @@
+use crate::base::*;
+use crate::buffer::{self, Arena, ArenaState, Buf};
+use core::alloc::{self, AllocError, Allocator};
+use core::cmp::max;
+use core::mem::{MaybeUninit, size_of};
+use core::ptr::NonNull;
+
+const WORD: usize = size_of::<usize>();
+
+/// Blocks sizes and payloads alignment.
+const ALIGN: usize = 2 * WORD;
+
+/// Header and footer size.
+const TAGS: usize = 2 * WORD;
+
+/// A free block should fit tags and free list links.
+const MIN_BLOCK: usize = TAGS + size_of::<Links>();
+
+const USED: usize = 1;
+
+const NIL: usize = usize::MAX;
+
+/// Free list links, placed right after a free block header.
+struct Links {
+    next: usize,
+    prev: usize,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Fit {
+    /// Takes the first free block, which fits.
+    First,
+    /// Takes the smallest free block, which fits.
+    Best,
+}
+
+fn round_up(size: usize, align: usize) -> Option<usize> {
+    Some(size.checked_add(align - 1)? & !(align - 1))
+}
+
+/// Returns the block size for a requested size, including tags.
+fn block_size(size: usize) -> Option<usize> {
+    Some(max(round_up(size, ALIGN)?.checked_add(TAGS)?, MIN_BLOCK))
+}
+
+struct State {
+    /// The free list head, as an offset from the arena start.
+    head: usize,
+}
+
+impl State {
+    const fn new() -> Self {
+        State { head: NIL }
+    }
+
+    unsafe fn tag(&self, arena: *mut u8, offset: usize) -> *mut usize {
+        arena.add(offset) as *mut usize
+    }
+
+    unsafe fn links(&self, arena: *mut u8, offset: usize) -> *mut Links {
+        arena.add(offset + WORD) as *mut Links
+    }
+
+    unsafe fn size(&self, arena: *mut u8, offset: usize) -> usize {
+        *self.tag(arena, offset) & !USED
+    }
+
+    unsafe fn is_used(&self, arena: *mut u8, offset: usize) -> bool {
+        *self.tag(arena, offset) & USED != 0
+    }
+
+    /// Writes a block header and footer.
+    unsafe fn set(&self, arena: *mut u8, offset: usize, size: usize, used: bool) {
+        let tag = if used { size | USED } else { size };
+        self.tag(arena, offset).write(tag);
+        self.tag(arena, offset + size - WORD).write(tag);
+    }
+
+    unsafe fn insert(&mut self, arena: *mut u8, offset: usize) {
+        self.links(arena, offset).write(Links { next: self.head, prev: NIL });
+        if self.head != NIL {
+            (*self.links(arena, self.head)).prev = offset;
+        }
+        self.head = offset;
+    }
+
+    unsafe fn remove(&mut self, arena: *mut u8, offset: usize) {
+        let Links { next, prev } = self.links(arena, offset).read();
+        if prev == NIL {
+            self.head = next;
+        } else {
+            (*self.links(arena, prev)).next = next;
+        }
+        if next != NIL {
+            (*self.links(arena, next)).prev = prev;
+        }
+    }
+
+    /// Returns the gap before the `align`-aligned payload in a block,
+    /// the gap is either zero, or big enough to be a free block.
+    fn gap(arena: *mut u8, offset: usize, align: usize) -> Option<usize> {
+        if align <= ALIGN { return Some(0); }
+        let payload = arena as usize + offset + WORD;
+        let gap = round_up(payload, align)? - payload;
+        if gap == 0 || gap >= MIN_BLOCK {
+            Some(gap)
+        } else {
+            Some(round_up(payload + MIN_BLOCK, align)? - payload)
+        }
+    }
+
+    /// Returns a free block offset and the gap before the aligned payload in it.
+    unsafe fn find(&self, arena: *mut u8, fit: Fit, size: usize, align: usize) -> Option<(usize, usize)> {
+        let mut found: Option<(usize, usize, usize)> = None;
+        let mut offset = self.head;
+        while offset != NIL {
+            let block_size = self.size(arena, offset);
+            if let Some(gap) = Self::gap(arena, offset, align) {
+                if gap.checked_add(size).is_some_and(|x| x <= block_size) {
+                    if fit == Fit::First || gap + size == block_size { return Some((offset, gap)); }
+                    if found.is_none_or(|(_, _, x)| block_size < x) {
+                        found = Some((offset, gap, block_size));
+                    }
+                }
+            }
+            offset = (*self.links(arena, offset)).next;
+        }
+        found.map(|(offset, gap, _)| (offset, gap))
+    }
+
+    /// Marks a not listed block used with `size` bytes, releasing the rest of it.
+    unsafe fn split(&mut self, arena: *mut u8, offset: usize, size: usize) {
+        let block_size = self.size(arena, offset);
+        if block_size - size < MIN_BLOCK {
+            self.set(arena, offset, block_size, true);
+        } else {
+            self.set(arena, offset, size, true);
+            self.set(arena, offset + size, block_size - size, true);
+            self.release(arena, offset + size);
+        }
+    }
+
+    /// Merges a block with its free neighbours, and inserts it into the free list.
+    unsafe fn release(&mut self, arena: *mut u8, mut offset: usize) {
+        let mut size = self.size(arena, offset);
+        if !self.is_used(arena, offset - WORD) {
+            let prev_size = self.size(arena, offset - WORD);
+            offset -= prev_size;
+            size += prev_size;
+            self.remove(arena, offset);
+        }
+        let next = offset + size;
+        if !self.is_used(arena, next) {
+            size += self.size(arena, next);
+            self.remove(arena, next);
+        }
+        self.set(arena, offset, size, false);
+        self.insert(arena, offset);
+    }
+
+    unsafe fn allocate(&mut self, arena: *mut u8, fit: Fit, layout: alloc::Layout) -> Option<usize> {
+        let size = block_size(layout.size())?;
+        let (mut offset, gap) = self.find(arena, fit, size, layout.align())?;
+        self.remove(arena, offset);
+        if gap != 0 {
+            let block_size = self.size(arena, offset);
+            self.set(arena, offset, gap, false);
+            self.insert(arena, offset);
+            offset += gap;
+            self.set(arena, offset, block_size - gap, false);
+        }
+        self.split(arena, offset, size);
+        Some(offset)
+    }
+
+    /// Resizes a block in place, returns `false`, if it is impossible.
+    unsafe fn resize(&mut self, arena: *mut u8, offset: usize, size: usize) -> bool {
+        let block_size = self.size(arena, offset);
+        if size > block_size {
+            let next = offset + block_size;
+            if self.is_used(arena, next) || block_size + self.size(arena, next) < size {
+                return false;
+            }
+            let next_size = self.size(arena, next);
+            self.remove(arena, next);
+            self.set(arena, offset, block_size + next_size, true);
+        }
+        self.split(arena, offset, size);
+        true
+    }
+}
+
+impl ArenaState for State {
+    unsafe fn init(buf_ptr: *mut u8, buf_len: usize) -> (usize, Self) {
+        let mut state = State::new();
+        let arena = buf_ptr.align_offset(ALIGN);
+        if arena >= buf_len || buf_len - arena < MIN_BLOCK + TAGS { return (0, state); }
+        let size = (buf_len - arena - TAGS) & !(ALIGN - 1);
+        let arena_ptr = buf_ptr.add(arena);
+        // The arena starts with a used prologue footer, and ends with a used epilogue header,
+        // so merging stops at the arena bounds.
+        state.tag(arena_ptr, 0).write(USED);
+        state.tag(arena_ptr, WORD + size).write(USED);
+        state.set(arena_ptr, WORD, size, false);
+        state.insert(arena_ptr, WORD);
+        (arena, state)
+    }
+}
+
+/// A free list heap over a buffer.
+///
+/// Every block has a one word header and a one word footer,
+/// so freed blocks are merged with their free neighbours,
+/// and can be reused for any layouts.
+/// Free blocks are searched linearly, taking either the first or the best fitting one.
+pub struct Heap {
+    arena: Arena<State>,
+    fit: Fit,
+}
+
+unsafe impl NonUnwinding for Heap { }
+
+impl Heap {
+    const fn new(buf: Buf, fit: Fit) -> Self {
+        Heap { arena: Arena::new(buf), fit }
+    }
+
+    pub const fn from_static_slice(
+        buf: &'static mut [MaybeUninit<u8>],
+        fit: Fit,
+    ) -> Self {
+        Self::new(Buf::from_static_slice(buf), fit)
+    }
+
+    pub const fn from_static_array<const BUF_LEN: usize>(
+        buf: &'static mut [MaybeUninit<u8>; BUF_LEN],
+        fit: Fit,
+    ) -> Self {
+        Self::new(Buf::from_static_array(buf), fit)
+    }
+
+    /// # Safety
+    ///
+    /// Arguments should satisfy [`Stacked::with_buf_raw`](crate::stacked::Stacked::with_buf_raw) requirements.
+    pub unsafe fn with_buf_raw<T>(
+        buf_ptr: NonNull<MaybeUninit<u8>>,
+        buf_len: usize,
+        fit: Fit,
+        f: impl for<'a> FnOnce(&'a Heap) -> T
+    ) -> T {
+        f(&Heap::new(Buf::new(buf_ptr, buf_len), fit))
+    }
+
+    pub fn fit(&self) -> Fit { self.fit }
+
+    unsafe fn offset(arena: *mut u8, ptr: NonNull<u8>) -> usize {
+        ptr.as_ptr().offset_from(arena) as usize - WORD
+    }
+
+    unsafe fn block(state: &State, arena: *mut u8, offset: usize) -> NonNull<[u8]> {
+        let ptr = NonNull::new_unchecked(arena.add(offset + WORD));
+        NonNull::slice_from_raw_parts(ptr, state.size(arena, offset) - TAGS)
+    }
+
+    unsafe fn resize(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout,
+        zeroed: bool,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let in_place = if (ptr.as_ptr() as usize) % new_layout.align() != 0 {
+            None
+        } else {
+            let size = block_size(new_layout.size()).ok_or(AllocError)?;
+            self.arena.with_state(|state, arena| {
+                let offset = Self::offset(arena, ptr);
+                if state.resize(arena, offset, size) { Some(Self::block(state, arena, offset)) } else { None }
+            })
+        };
+        if let Some(block) = in_place {
+            if zeroed {
+                ptr.add(old_layout.size()).write_bytes(0, block.len() - old_layout.size());
+            }
+            return Ok(block);
+        }
+        move_block(self, ptr, old_layout, new_layout, zeroed)
+    }
+}
+
+pub fn with_size<const BUF_LEN: usize, T>(
+    fit: Fit,
+    f: impl for<'a> FnOnce(&'a Heap) -> T
+) -> T {
+    buffer::with_size::<BUF_LEN, _>(|buf| f(&Heap::new(buf, fit)))
+}
+
+pub fn with_buf<T>(
+    buf: &mut [MaybeUninit<u8>],
+    fit: Fit,
+    f: impl for<'a> FnOnce(&'a Heap) -> T
+) -> T {
+    buffer::with_buf(buf, |buf| f(&Heap::new(buf, fit)))
+}
+
+unsafe impl Fallbackable for Heap {
+    unsafe fn has_allocated(&self, ptr: NonNull<u8>, _layout: alloc::Layout) -> bool {
+        self.arena.buf().contains(ptr)
+    }
+
+    fn allows_fallback(&self, _layout: alloc::Layout) -> bool {
+        true
+    }
+}
+
+unsafe impl Allocator for Heap {
+    fn allocate(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.arena.with_state(|state, arena| unsafe {
+            let offset = state.allocate(arena, self.fit, layout)?;
+            Some(Self::block(state, arena, offset))
+        }).ok_or(AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, _layout: alloc::Layout) {
+        self.arena.with_state(|state, arena| state.release(arena, Self::offset(arena, ptr)));
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old_layout, new_layout, false)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old_layout, new_layout, true)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old_layout, new_layout, false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::heap::{self, Fit, Heap};
+    use core::alloc::{self, Allocator};
+    use core::mem::MaybeUninit;
+    use core::ptr::NonNull;
+
+    #[repr(align(256))]
+    struct Buf([MaybeUninit<u8>; 1024]);
+
+    impl Buf {
+        fn new() -> Self { Buf([MaybeUninit::uninit(); 1024]) }
+    }
+
+    fn layout(size: usize) -> alloc::Layout {
+        alloc::Layout::from_size_align(size, 8).unwrap()
+    }
+
+    fn allocate(heap: &Heap, size: usize) -> NonNull<u8> {
+        heap.allocate(layout(size)).unwrap().as_non_null_ptr()
+    }
+
+    /// Frees a large block and then an exactly fitting one, and allocates the exactly fitting size.
+    ///
+    /// Returns the allocated block, and the large and the exactly fitting blocks.
+    fn fit_scenario(fit: Fit) -> [usize; 3] {
+        heap::with_size::<1024, _>(fit, |heap| {
+            let large = allocate(heap, 96);
+            let _guard = allocate(heap, 16);
+            let exact = allocate(heap, 32);
+            let _guard = allocate(heap, 16);
+            unsafe {
+                heap.deallocate(exact, layout(32));
+                heap.deallocate(large, layout(96));
+            }
+            let block = allocate(heap, 32);
+            [block, large, exact].map(|x| x.as_ptr() as usize)
+        })
+    }
+
+    #[test]
+    fn first_and_best_fit() {
+        let [block, large, _] = fit_scenario(Fit::First);
+        assert_eq!(block, large);
+        let [block, _, exact] = fit_scenario(Fit::Best);
+        assert_eq!(block, exact);
+    }
+
+    #[test]
+    fn coalesces_with_both_neighbours() {
+        heap::with_size::<1024, _>(Fit::First, |heap| {
+            let blocks = [(); 3].map(|_| allocate(heap, 32));
+            let guard = allocate(heap, 16);
+            unsafe {
+                heap.deallocate(blocks[0], layout(32));
+                heap.deallocate(blocks[2], layout(32));
+                heap.deallocate(blocks[1], layout(32));
+            }
+            // Three blocks with their tags make one exactly fitting block.
+            let merged = heap.allocate(layout(3 * 48 - 16)).unwrap();
+            assert_eq!(merged.as_non_null_ptr(), blocks[0]);
+            assert_eq!(merged.len(), 3 * 48 - 16);
+            unsafe {
+                heap.deallocate(merged.as_non_null_ptr(), layout(3 * 48 - 16));
+                heap.deallocate(guard, layout(16));
+            }
+        });
+    }
+
+    #[test]
+    fn splits_aligned_gap_into_free_block() {
+        let mut buf = Buf::new();
+        let start = buf.0.as_mut_ptr() as usize;
+        heap::with_buf(&mut buf.0, Fit::Best, |heap| {
+            let aligned = alloc::Layout::from_size_align(64, 256).unwrap();
+            let block = heap.allocate(aligned).unwrap().as_non_null_ptr();
+            assert_eq!(block.as_ptr() as usize, start + 256);
+            let small = allocate(heap, 16);
+            assert!((small.as_ptr() as usize) < start + 256);
+            unsafe {
+                heap.deallocate(small, layout(16));
+                heap.deallocate(block, aligned);
+            }
+        });
+    }
+
+    #[test]
+    fn resizes_in_place() {
+        heap::with_size::<1024, _>(Fit::First, |heap| {
+            let a = allocate(heap, 32);
+            let grown = unsafe { heap.grow(a, layout(32), layout(64)) }.unwrap();
+            assert_eq!(grown.as_non_null_ptr(), a);
+            assert_eq!(grown.len(), 64);
+            let shrunk = unsafe { heap.shrink(a, layout(64), layout(16)) }.unwrap();
+            assert_eq!(shrunk.as_non_null_ptr(), a);
+            assert_eq!(shrunk.len(), 16);
+            let b = allocate(heap, 16);
+            assert_eq!(b.as_ptr(), a.as_ptr().wrapping_add(32));
+            let moved = unsafe { heap.grow(a, layout(16), layout(32)) }.unwrap();
+            assert_ne!(moved.as_non_null_ptr(), a);
+            unsafe {
+                heap.deallocate(moved.as_non_null_ptr(), layout(32));
+                heap.deallocate(b, layout(16));
+            }
+        });
+    }
+}
@@ -0,0 +1,328 @@
+use crate::base::*;
+use crate::bucketizer::SizeClasses;
+use crate::freelist::{MIN_LAYOUT_ALIGN, MIN_LAYOUT_SIZE};
+use crate::spin_lock::SpinLock;
+use core::alloc::{self, AllocError, Allocator};
+use core::cmp::{max, min};
+use core::ptr::NonNull;
+
+pub const SIZE_BINS: usize = usize::BITS as usize + 1;
+
+pub const ALIGN_BINS: usize = usize::BITS as usize;
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct SizeBin {
+    pub count: usize,
+    /// Saturating sum of requested sizes.
+    pub total_size: usize,
+    pub min_size: usize,
+    pub max_size: usize,
+    pub max_align: usize,
+}
+
+impl SizeBin {
+    const EMPTY: SizeBin = SizeBin { count: 0, total_size: 0, min_size: 0, max_size: 0, max_align: 0 };
+
+    /// Estimates requests, which sizes lie in `lo ..= hi`, and their total size,
+    /// assuming sizes are distributed uniformly in `min_size ..= max_size`.
+    fn part(&self, lo: usize, hi: usize) -> (usize, usize) {
+        if self.count == 0 { return (0, 0); }
+        let lo = max(lo, self.min_size);
+        let hi = min(hi, self.max_size);
+        if lo > hi { return (0, 0); }
+        if lo == self.min_size && hi == self.max_size { return (self.count, self.total_size); }
+        let span = (self.max_size - self.min_size) as u128 + 1;
+        let count = (self.count as u128 * (hi - lo + 1) as u128 / span) as usize;
+        let total_size = (count as u128 * (lo as u128 + hi as u128) / 2) as usize;
+        (count, total_size)
+    }
+}
+
+/// Estimated internal fragmentation of a configuration.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct Fragmentation {
+    /// Requests served by the configuration.
+    pub requests: usize,
+    /// Bytes allocated, but not requested, summed over all served requests.
+    pub wasted_bytes: usize,
+}
+
+impl Fragmentation {
+    fn add(&mut self, other: Fragmentation) {
+        self.requests = self.requests.saturating_add(other.requests);
+        self.wasted_bytes = self.wasted_bytes.saturating_add(other.wasted_bytes);
+    }
+}
+
+/// A suggested [`Freelist`](crate::freelist::Freelist) configuration.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct FreelistSuggestion {
+    pub layout: alloc::Layout,
+    pub tolerance: alloc::Layout,
+    pub fragmentation: Fragmentation,
+}
+
+/// Requested sizes and alignments distribution.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Distribution {
+    /// `sizes[k]` describes requests with sizes in `2^(k-1) + 1 ..= 2^k`,
+    /// `sizes[0]` describes requests with sizes `0` and `1`.
+    pub sizes: [SizeBin; SIZE_BINS],
+    /// `aligns[k]` counts requests with the `2^k` alignment.
+    pub aligns: [usize; ALIGN_BINS],
+}
+
+impl Default for Distribution {
+    fn default() -> Self { Self::new() }
+}
+
+impl Distribution {
+    pub const fn new() -> Self {
+        Distribution { sizes: [SizeBin::EMPTY; SIZE_BINS], aligns: [0; ALIGN_BINS] }
+    }
+
+    fn record(&mut self, layout: alloc::Layout) {
+        let bin = &mut self.sizes[layout.size().checked_next_power_of_two().map_or(SIZE_BINS - 1, |x| x.trailing_zeros() as usize)];
+        if bin.count == 0 {
+            bin.min_size = layout.size();
+            bin.max_size = layout.size();
+        } else {
+            bin.min_size = min(bin.min_size, layout.size());
+            bin.max_size = max(bin.max_size, layout.size());
+        }
+        bin.count = bin.count.saturating_add(1);
+        bin.total_size = bin.total_size.saturating_add(layout.size());
+        bin.max_align = max(bin.max_align, layout.align());
+        let align = &mut self.aligns[layout.align().trailing_zeros() as usize];
+        *align = align.saturating_add(1);
+    }
+
+    pub fn requests(&self) -> usize {
+        self.sizes.iter().fold(0, |sum, x| sum.saturating_add(x.count))
+    }
+
+    /// Estimates internal fragmentation of a freelist with given layouts,
+    /// i.e. of serving all recorded requests with sizes in `tolerance.size() ..= layout.size()`
+    /// by `layout.size()`-bytes blocks.
+    ///
+    /// Size bins with `max_align` greater than `layout.align()` are considered as not served.
+    pub fn freelist_fragmentation(&self, layout: alloc::Layout, tolerance: alloc::Layout) -> Fragmentation {
+        let mut res = Fragmentation::default();
+        for bin in self.sizes.iter().filter(|x| x.max_align <= layout.align()) {
+            let (count, total_size) = bin.part(tolerance.size(), layout.size());
+            res.add(Fragmentation {
+                requests: count,
+                wasted_bytes: count.saturating_mul(layout.size()).saturating_sub(total_size),
+            });
+        }
+        res
+    }
+
+    /// Estimates internal fragmentation of a [`Bucketizer`](crate::bucketizer::Bucketizer)
+    /// with `buckets` size classes, not counting requests served by the fallback.
+    ///
+    /// Classes, which cannot form a layout with `align`, are skipped along with all following ones.
+    pub fn bucketizer_fragmentation(&self, classes: SizeClasses, buckets: usize, align: usize) -> Fragmentation {
+        let mut res = Fragmentation::default();
+        let mut tolerance = 0;
+        let mut size = classes.first();
+        for i in 0 .. buckets {
+            let Ok(layout) = alloc::Layout::from_size_align(size, align) else { break; };
+            let tolerance_layout = alloc::Layout::from_size_align(tolerance, 1).unwrap();
+            res.add(self.freelist_fragmentation(layout, tolerance_layout));
+            if i + 1 < buckets {
+                tolerance = size + 1;
+                size = classes.next(size);
+            }
+        }
+        res
+    }
+
+    /// Suggests a freelist for every non-empty size bin.
+    /// Bins below [`MIN_LAYOUT_SIZE`] are merged with the next ones.
+    /// Bins, which cannot form a layout, are skipped.
+    ///
+    /// Suggested layouts are exactly fitting recorded requests,
+    /// so the estimated fragmentation is exact.
+    pub fn freelists(&self) -> impl Iterator<Item=FreelistSuggestion> + '_ {
+        let mut bins = self.sizes.iter().filter(|x| x.count != 0).peekable();
+        core::iter::from_fn(move || loop {
+            let mut bin = *bins.next()?;
+            while let Some(next) = bins.next_if(|x| x.min_size <= max(bin.max_size, MIN_LAYOUT_SIZE)) {
+                bin.count = bin.count.saturating_add(next.count);
+                bin.total_size = bin.total_size.saturating_add(next.total_size);
+                bin.max_size = next.max_size;
+                bin.max_align = max(bin.max_align, next.max_align);
+            }
+            let align = max(bin.max_align, MIN_LAYOUT_ALIGN);
+            let Ok(layout) = alloc::Layout::from_size_align(max(bin.max_size, MIN_LAYOUT_SIZE), align) else { continue; };
+            let Ok(tolerance) = alloc::Layout::from_size_align(bin.min_size, 1) else { continue; };
+            let wasted_bytes = bin.count.saturating_mul(layout.size()).saturating_sub(bin.total_size);
+            return Some(FreelistSuggestion {
+                layout,
+                tolerance,
+                fragmentation: Fragmentation { requests: bin.count, wasted_bytes },
+            });
+        })
+    }
+
+    /// Suggests the smallest [`LimitedUpTo`](crate::limited_up_to::LimitedUpTo) layout
+    /// admitting at least `percent` percents of recorded requests.
+    ///
+    /// Returns `None` if there are no recorded requests.
+    pub fn limited_up_to_threshold(&self, percent: u8) -> Option<alloc::Layout> {
+        let requests = self.requests();
+        if requests == 0 { return None; }
+        let required = (requests as u128 * min(percent, 100) as u128).div_ceil(100);
+        let mut admitted = 0u128;
+        let mut align = 1;
+        for bin in self.sizes.iter().filter(|x| x.count != 0) {
+            admitted += bin.count as u128;
+            align = max(align, bin.max_align);
+            if admitted >= required {
+                return alloc::Layout::from_size_align(bin.max_size, align).ok();
+            }
+        }
+        None
+    }
+}
+
+/// Records requested sizes and alignments distribution.
+pub struct Histogram<A: Allocator> {
+    distribution: SpinLock<Distribution>,
+    base: A,
+}
+
+unsafe impl<A: NonUnwinding> NonUnwinding for Histogram<A> { }
+
+impl<A: Allocator> Histogram<A> {
+    pub const fn new(base: A) -> Self {
+        Histogram { distribution: SpinLock::new(Distribution::new()), base }
+    }
+
+    pub fn base(&self) -> &A { &self.base }
+
+    pub fn snapshot(&self) -> Distribution {
+        self.distribution.with(|x| *x)
+    }
+
+    pub fn reset(&self) {
+        self.distribution.with(|x| *x = Distribution::new());
+    }
+
+    fn record(&self, layout: alloc::Layout) {
+        self.distribution.with(|x| x.record(layout));
+    }
+}
+
+unsafe impl<A: Fallbackable> Fallbackable for Histogram<A> {
+    unsafe fn has_allocated(&self, ptr: NonNull<u8>, layout: alloc::Layout) -> bool {
+        self.base.has_allocated(ptr, layout)
+    }
+
+    fn allows_fallback(&self, layout: alloc::Layout) -> bool {
+        self.base.allows_fallback(layout)
+    }
+}
+
+unsafe impl<A: Allocator> Allocator for Histogram<A> {
+    fn allocate(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.record(layout);
+        self.base.allocate(layout)
+    }
+
+    fn allocate_zeroed(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.record(layout);
+        self.base.allocate_zeroed(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: alloc::Layout) {
+        self.base.deallocate(ptr, layout);
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.record(new_layout);
+        self.base.grow(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.record(new_layout);
+        self.base.grow_zeroed(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.record(new_layout);
+        self.base.shrink(ptr, old_layout, new_layout)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::System;
+    use crate::bucketizer::SizeClasses;
+    use crate::histogram::{Distribution, Fragmentation, Histogram, SizeBin};
+    use core::alloc::{self, Allocator};
+
+    fn record(histogram: &Histogram<System>, size: usize, align: usize) {
+        let layout = alloc::Layout::from_size_align(size, align).unwrap();
+        let block = histogram.allocate(layout).unwrap();
+        unsafe { histogram.deallocate(block.as_non_null_ptr(), layout); }
+    }
+
+    #[test]
+    fn suggests_freelists_from_recorded_sizes() {
+        let histogram = Histogram::new(System);
+        for _ in 0 .. 3 { record(&histogram, 24, 8); }
+        record(&histogram, 20, 4);
+        record(&histogram, 100, 16);
+        let distribution = histogram.snapshot();
+        assert_eq!(distribution.requests(), 5);
+        let mut freelists = distribution.freelists();
+        let small = freelists.next().unwrap();
+        assert_eq!(small.layout, alloc::Layout::from_size_align(24, 8).unwrap());
+        assert_eq!(small.tolerance.size(), 20);
+        assert_eq!(small.fragmentation, Fragmentation { requests: 4, wasted_bytes: 4 });
+        let large = freelists.next().unwrap();
+        assert_eq!(large.layout, alloc::Layout::from_size_align(100, 16).unwrap());
+        assert_eq!(large.fragmentation, Fragmentation { requests: 1, wasted_bytes: 0 });
+        assert!(freelists.next().is_none());
+        let fragmentation = distribution.bucketizer_fragmentation(SizeClasses::PowerOfTwo { min: 32 }, 3, 16);
+        assert_eq!(fragmentation, Fragmentation { requests: 5, wasted_bytes: 4 * 32 - 92 + 128 - 100 });
+        assert_eq!(distribution.limited_up_to_threshold(80), Some(alloc::Layout::from_size_align(24, 8).unwrap()));
+    }
+
+    #[test]
+    fn skips_classes_without_layout() {
+        let mut distribution = Distribution::new();
+        distribution.sizes[3] = SizeBin { count: 2, total_size: 16, min_size: 8, max_size: 8, max_align: 8 };
+        distribution.sizes[usize::BITS as usize] = SizeBin {
+            count: 1,
+            total_size: usize::MAX,
+            min_size: usize::MAX,
+            max_size: usize::MAX,
+            max_align: 1,
+        };
+        let mut freelists = distribution.freelists();
+        assert_eq!(freelists.next().unwrap().layout.size(), 8);
+        assert!(freelists.next().is_none());
+        let fragmentation = distribution.bucketizer_fragmentation(SizeClasses::PowerOfTwo { min: 8 }, usize::BITS as usize, 8);
+        assert_eq!(fragmentation, Fragmentation { requests: 2, wasted_bytes: 0 });
+        let fragmentation = distribution.bucketizer_fragmentation(SizeClasses::Linear { min: 8, step: 8 }, 4, 3);
+        assert_eq!(fragmentation, Fragmentation::default());
+    }
+}
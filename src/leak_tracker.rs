@@ -0,0 +1,275 @@
+use crate::base::*;
+use crate::spin_lock::SpinLock;
+use core::alloc::{self, AllocError, Allocator};
+use core::fmt::{self, Display, Formatter};
+use core::ptr::NonNull;
+use print_no_std::Stderr;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct LiveBlock {
+    pub ptr: NonNull<u8>,
+    pub layout: alloc::Layout,
+    /// The allocation sequence number, starting from `0`.
+    pub seq: u64,
+    /// The tag [set](LeakTracker::set_tag) when the block was allocated.
+    pub tag: Option<&'static str>,
+}
+
+impl Display for LiveBlock {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "#{} {:p} {:?}", self.seq, self.ptr, self.layout)?;
+        if let Some(tag) = self.tag {
+            write!(f, " [{tag}]")?;
+        }
+        Ok(())
+    }
+}
+
+struct Table<const N: usize> {
+    blocks: [Option<LiveBlock>; N],
+    len: usize,
+    untracked: usize,
+    next_seq: u64,
+    tag: Option<&'static str>,
+}
+
+unsafe impl<const N: usize> Send for Table<N> { }
+
+impl<const N: usize> Table<N> {
+    fn insert(&mut self, ptr: NonNull<u8>, layout: alloc::Layout) {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        if let Some(slot) = self.blocks.iter_mut().find(|x| x.is_none()) {
+            *slot = Some(LiveBlock { ptr, layout, seq, tag: self.tag });
+            self.len += 1;
+        } else {
+            self.untracked += 1;
+        }
+    }
+
+    fn find(&mut self, ptr: NonNull<u8>) -> Option<&mut Option<LiveBlock>> {
+        self.blocks.iter_mut().find(|x| x.is_some_and(|x| x.ptr == ptr))
+    }
+
+    fn remove(&mut self, ptr: NonNull<u8>) {
+        if let Some(slot) = self.find(ptr) {
+            *slot = None;
+            self.len -= 1;
+        } else {
+            self.untracked = self.untracked.saturating_sub(1);
+        }
+    }
+
+    fn update(&mut self, old_ptr: NonNull<u8>, ptr: NonNull<u8>, layout: alloc::Layout) {
+        if let Some(Some(block)) = self.find(old_ptr) {
+            block.ptr = ptr;
+            block.layout = layout;
+        }
+    }
+}
+
+/// Keeps a table of up to `N` live blocks, and dumps it to stderr on drop if it is not empty.
+///
+/// Blocks allocated while the table is full are only counted.
+pub struct LeakTracker<A: Allocator, const N: usize> {
+    table: SpinLock<Table<N>>,
+    base: A,
+}
+
+unsafe impl<A: NonUnwinding, const N: usize> NonUnwinding for LeakTracker<A, N> { }
+
+impl<A: Allocator, const N: usize> Drop for LeakTracker<A, N> {
+    fn drop(&mut self) {
+        if self.live_len() != 0 || self.untracked_len() != 0 {
+            self.dump();
+        }
+    }
+}
+
+impl<A: Allocator, const N: usize> LeakTracker<A, N> {
+    pub const fn new(base: A) -> Self {
+        LeakTracker {
+            table: SpinLock::new(Table { blocks: [None; N], len: 0, untracked: 0, next_seq: 0, tag: None }),
+            base,
+        }
+    }
+
+    pub fn base(&self) -> &A { &self.base }
+
+    /// Sets the tag for blocks allocated afterwards.
+    pub fn set_tag(&self, tag: Option<&'static str>) {
+        self.table.with(|table| table.tag = tag);
+    }
+
+    /// Returns the number of live blocks in the table.
+    pub fn live_len(&self) -> usize {
+        self.table.with(|table| table.len)
+    }
+
+    /// Returns the number of live blocks, which did not fit into the table.
+    pub fn untracked_len(&self) -> usize {
+        self.table.with(|table| table.untracked)
+    }
+
+    /// Calls `f` for every live block in the table, in no particular order.
+    ///
+    /// The table is locked while `f` runs, so `f` should not allocate with this allocator.
+    pub fn for_each_live(&self, mut f: impl FnMut(&LiveBlock)) {
+        self.table.with(|table| table.blocks.iter().flatten().for_each(&mut f));
+    }
+
+    /// Writes all live blocks to stderr.
+    pub fn dump(&self) {
+        let mut stderr = Stderr { panic: false };
+        self.table.with(|table| {
+            let _ = writeln!(stderr, "{} live blocks, {} untracked", table.len, table.untracked);
+            for block in table.blocks.iter().flatten() {
+                let _ = writeln!(stderr, "    {block}");
+            }
+        });
+    }
+}
+
+unsafe impl<A: Fallbackable, const N: usize> Fallbackable for LeakTracker<A, N> {
+    unsafe fn has_allocated(&self, ptr: NonNull<u8>, layout: alloc::Layout) -> bool {
+        self.base.has_allocated(ptr, layout)
+    }
+
+    fn allows_fallback(&self, layout: alloc::Layout) -> bool {
+        self.base.allows_fallback(layout)
+    }
+}
+
+unsafe impl<A: Allocator, const N: usize> Allocator for LeakTracker<A, N> {
+    fn allocate(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let block = self.base.allocate(layout)?;
+        self.table.with(|table| table.insert(block.as_non_null_ptr(), layout));
+        Ok(block)
+    }
+
+    fn allocate_zeroed(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let block = self.base.allocate_zeroed(layout)?;
+        self.table.with(|table| table.insert(block.as_non_null_ptr(), layout));
+        Ok(block)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: alloc::Layout) {
+        self.table.with(|table| table.remove(ptr));
+        self.base.deallocate(ptr, layout);
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let block = self.base.grow(ptr, old_layout, new_layout)?;
+        self.table.with(|table| table.update(ptr, block.as_non_null_ptr(), new_layout));
+        Ok(block)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let block = self.base.grow_zeroed(ptr, old_layout, new_layout)?;
+        self.table.with(|table| table.update(ptr, block.as_non_null_ptr(), new_layout));
+        Ok(block)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let block = self.base.shrink(ptr, old_layout, new_layout)?;
+        self.table.with(|table| table.update(ptr, block.as_non_null_ptr(), new_layout));
+        Ok(block)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use crate::{Stats, System};
+    use crate::fallbacked::Fallbacked;
+    use crate::leak_tracker::*;
+    use crate::stacked;
+    use std::vec::Vec;
+
+    fn layout(size: usize) -> alloc::Layout {
+        alloc::Layout::from_size_align(size, 8).unwrap()
+    }
+
+    fn live<A: Allocator, const N: usize>(tracker: &LeakTracker<A, N>) -> Vec<LiveBlock> {
+        let mut blocks = Vec::new();
+        tracker.for_each_live(|block| blocks.push(*block));
+        blocks.sort_by_key(|block| block.seq);
+        blocks
+    }
+
+    #[test]
+    fn records_seq_and_tag() {
+        let tracker = LeakTracker::<_, 4>::new(System);
+        let a = tracker.allocate(layout(16)).unwrap().as_non_null_ptr();
+        tracker.set_tag(Some("parser"));
+        let b = tracker.allocate_zeroed(layout(32)).unwrap().as_non_null_ptr();
+        tracker.set_tag(None);
+        let c = tracker.allocate(layout(8)).unwrap().as_non_null_ptr();
+        assert_eq!(live(&tracker), [
+            LiveBlock { ptr: a, layout: layout(16), seq: 0, tag: None },
+            LiveBlock { ptr: b, layout: layout(32), seq: 1, tag: Some("parser") },
+            LiveBlock { ptr: c, layout: layout(8), seq: 2, tag: None },
+        ]);
+        unsafe { tracker.deallocate(b, layout(32)); }
+        let d = tracker.allocate(layout(8)).unwrap().as_non_null_ptr();
+        assert_eq!(live(&tracker)[2], LiveBlock { ptr: d, layout: layout(8), seq: 3, tag: None });
+        for (ptr, size) in [(a, 16), (c, 8), (d, 8)] {
+            unsafe { tracker.deallocate(ptr, layout(size)); }
+        }
+        assert_eq!(tracker.live_len(), 0);
+    }
+
+    #[test]
+    fn moving_grow_updates_entry() {
+        stacked::with_size::<256, _>(|stacked| {
+            let tracker = LeakTracker::<_, 4>::new(Fallbacked(stacked, System));
+            tracker.set_tag(Some("grown"));
+            let a = tracker.allocate(layout(16)).unwrap().as_non_null_ptr();
+            let b = tracker.allocate(layout(16)).unwrap().as_non_null_ptr();
+            let c = unsafe { tracker.grow(a, layout(16), layout(64)) }.unwrap().as_non_null_ptr();
+            assert_ne!(a, c);
+            assert_eq!(live(&tracker), [
+                LiveBlock { ptr: c, layout: layout(64), seq: 0, tag: Some("grown") },
+                LiveBlock { ptr: b, layout: layout(16), seq: 1, tag: Some("grown") },
+            ]);
+            let c = unsafe { tracker.shrink(c, layout(64), layout(8)) }.unwrap().as_non_null_ptr();
+            assert_eq!(live(&tracker)[0], LiveBlock { ptr: c, layout: layout(8), seq: 0, tag: Some("grown") });
+            unsafe { tracker.deallocate(c, layout(8)); }
+            unsafe { tracker.deallocate(b, layout(16)); }
+            assert_eq!(tracker.live_len(), 0);
+        });
+    }
+
+    #[test]
+    fn counts_blocks_past_capacity_as_untracked() {
+        let stats = Stats::new(System);
+        let tracker = LeakTracker::<_, 2>::new(&stats);
+        let blocks = (0 .. 5).map(|_| tracker.allocate(layout(16)).unwrap().as_non_null_ptr()).collect::<Vec<_>>();
+        assert_eq!(tracker.live_len(), 2);
+        assert_eq!(tracker.untracked_len(), 3);
+        assert_eq!(live(&tracker).iter().map(|x| x.ptr).collect::<Vec<_>>(), blocks[.. 2]);
+        for &ptr in blocks.iter().rev() {
+            unsafe { tracker.deallocate(ptr, layout(16)); }
+        }
+        assert_eq!(tracker.live_len(), 0);
+        assert_eq!(tracker.untracked_len(), 0);
+        assert!(live(&tracker).is_empty());
+        assert_eq!(stats.live_blocks(), 0);
+    }
+}
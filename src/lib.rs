@@ -17,19 +17,56 @@ extern crate alloc;
 mod base;
 pub use base::*;
 
+mod spin_lock;
+
+mod buffer;
+
+mod abort;
+
 pub mod fallbacked;
 
 pub mod limited_up_to;
 
+pub mod segregator;
+
 mod global;
 pub use global::*;
 
 mod as_global;
 pub use as_global::*;
 
+#[cfg(feature="logging")]
 mod logging;
+#[cfg(feature="logging")]
 pub use logging::*;
 
+mod stats;
+pub use stats::*;
+
+mod tracing;
+pub use tracing::*;
+
+mod histogram;
+pub use histogram::*;
+
+mod quota;
+pub use quota::*;
+
+mod corruption;
+pub use corruption::*;
+
+mod canary;
+pub use canary::*;
+
+mod quarantine;
+pub use quarantine::*;
+
+mod zeroize_on_free;
+pub use zeroize_on_free::*;
+
+mod leak_tracker;
+pub use leak_tracker::*;
+
 #[cfg(all(not(target_os="dos"), windows))]
 mod winapi;
 
@@ -48,263 +85,116 @@ pub use impossible::*;
 mod non_working;
 pub use non_working::*;
 
-pub mod stacked;
-
-pub mod freelist;
+mod failure_injection;
+pub use failure_injection::*;
 
-#[doc(hidden)]
-pub use core::alloc::Layout as std_alloc_Layout;
-#[doc(hidden)]
-pub use core::mem::MaybeUninit as std_mem_MaybeUninit;
-#[doc(hidden)]
-pub use core::ptr::addr_of_mut as std_ptr_addr_of_mut;
+pub mod stacked;
 
-#[macro_export]
-macro_rules! freelist_allocator_128_KiB_align_8 {
-    ($name:ident : $ty:ident) => {
-        const MEM_SIZE: usize = 131072;
-
-        static mut MEM: [$crate::std_mem_MaybeUninit<u8>; MEM_SIZE] =
-            [$crate::std_mem_MaybeUninit::uninit(); MEM_SIZE]
-        ;
-
-        static STACKED: $crate::stacked::Stacked =
-            $crate::stacked::Stacked::from_static_array(unsafe { &mut *$crate::std_ptr_addr_of_mut!(MEM) })
-        ;
-
-        type $ty = $crate::fallbacked::Fallbacked<$crate::limited_up_to::LimitedUpTo<
-            $crate::freelist::Freelist<$crate::freelist::NoLimit, &'static $crate::stacked::Stacked>
-        >, &'static Freelist16B>;
-
-        static $name: $ty = $crate::fallbacked::Fallbacked(
-            $crate::limited_up_to::LimitedUpTo::new(
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(8, 8) },
-                $crate::freelist::Freelist::new(
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(8, 8) },
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(1, 1) },
-                    $crate::freelist::NoLimit,
-                    &STACKED
-                )
-            ),
-            &FREELIST_16_B
-        );
+pub mod chained_stacked;
 
-        type Freelist16B = $crate::fallbacked::Fallbacked<$crate::limited_up_to::LimitedUpTo<
-            $crate::freelist::Freelist<$crate::freelist::NoLimit, &'static $crate::stacked::Stacked>
-        >, &'static Freelist32B>;
-
-        static FREELIST_16_B: Freelist16B = $crate::fallbacked::Fallbacked(
-            $crate::limited_up_to::LimitedUpTo::new(
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(16, 8) },
-                $crate::freelist::Freelist::new(
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(16, 8) },
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(9, 1) },
-                    $crate::freelist::NoLimit,
-                    &STACKED
-                )
-            ),
-            &FREELIST_32_B
-        );
+pub mod local_stacked;
 
-        type Freelist32B = $crate::fallbacked::Fallbacked<$crate::limited_up_to::LimitedUpTo<
-            $crate::freelist::Freelist<$crate::freelist::NoLimit, &'static $crate::stacked::Stacked>
-        >, &'static Freelist64B>;
-
-        static FREELIST_32_B: Freelist32B = $crate::fallbacked::Fallbacked(
-            $crate::limited_up_to::LimitedUpTo::new(
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(32, 8) },
-                $crate::freelist::Freelist::new(
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(32, 8) },
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(17, 1) },
-                    $crate::freelist::NoLimit,
-                    &STACKED
-                )
-            ),
-            &FREELIST_64_B
-        );
+pub mod buddy;
 
-        type Freelist64B = $crate::fallbacked::Fallbacked<$crate::limited_up_to::LimitedUpTo<
-            $crate::freelist::Freelist<$crate::freelist::NoLimit, &'static $crate::stacked::Stacked>
-        >, &'static Freelist128B>;
-
-        static FREELIST_64_B: Freelist64B = $crate::fallbacked::Fallbacked(
-            $crate::limited_up_to::LimitedUpTo::new(
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(64, 8) },
-                $crate::freelist::Freelist::new(
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(64, 8) },
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(33, 1) },
-                    $crate::freelist::NoLimit,
-                    &STACKED
-                )
-            ),
-            &FREELIST_128_B
-        );
+pub mod tlsf;
 
-        type Freelist128B = $crate::fallbacked::Fallbacked<$crate::limited_up_to::LimitedUpTo<
-            $crate::freelist::Freelist<$crate::freelist::NoLimit, &'static $crate::stacked::Stacked>
-        >, &'static Freelist256B>;
-
-        static FREELIST_128_B: Freelist128B = $crate::fallbacked::Fallbacked(
-            $crate::limited_up_to::LimitedUpTo::new(
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(128, 8) },
-                $crate::freelist::Freelist::new(
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(128, 8) },
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(65, 1) },
-                    $crate::freelist::NoLimit,
-                    &STACKED
-                )
-            ),
-            &FREELIST_256_B
-        );
+pub mod heap;
 
-        type Freelist256B = $crate::fallbacked::Fallbacked<$crate::limited_up_to::LimitedUpTo<
-            $crate::freelist::Freelist<$crate::freelist::NoLimit, &'static $crate::stacked::Stacked>
-        >, &'static Freelist512B>;
-
-        static FREELIST_256_B: Freelist256B = $crate::fallbacked::Fallbacked(
-            $crate::limited_up_to::LimitedUpTo::new(
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(256, 8) },
-                $crate::freelist::Freelist::new(
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(256, 8) },
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(129, 1) },
-                    $crate::freelist::NoLimit,
-                    &STACKED
-                )
-            ),
-            &FREELIST_512_B
-        );
+pub mod bitmapped_block;
 
-        type Freelist512B = $crate::fallbacked::Fallbacked<$crate::limited_up_to::LimitedUpTo<
-            $crate::freelist::Freelist<$crate::freelist::NoLimit, &'static $crate::stacked::Stacked>
-        >, &'static Freelist1KiB>;
-
-        static FREELIST_512_B: Freelist512B = $crate::fallbacked::Fallbacked(
-            $crate::limited_up_to::LimitedUpTo::new(
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(512, 8) },
-                $crate::freelist::Freelist::new(
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(512, 8) },
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(257, 1) },
-                    $crate::freelist::NoLimit,
-                    &STACKED
-                )
-            ),
-            &FREELIST_1_KIB
-        );
+pub mod freelist;
 
-        type Freelist1KiB = $crate::fallbacked::Fallbacked<$crate::limited_up_to::LimitedUpTo<
-            $crate::freelist::Freelist<$crate::freelist::NoLimit, &'static $crate::stacked::Stacked>
-        >, &'static Freelist2KiB>;
-
-        static FREELIST_1_KIB: Freelist1KiB = $crate::fallbacked::Fallbacked(
-            $crate::limited_up_to::LimitedUpTo::new(
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(1024, 8) },
-                $crate::freelist::Freelist::new(
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(1024, 8) },
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(513, 1) },
-                    $crate::freelist::NoLimit,
-                    &STACKED
-                )
-            ),
-            &FREELIST_2_KIB
-        );
+#[cfg(target_has_atomic="64")]
+pub mod lock_free_freelist;
 
-        type Freelist2KiB = $crate::fallbacked::Fallbacked<$crate::limited_up_to::LimitedUpTo<
-            $crate::freelist::Freelist<$crate::freelist::NoLimit, &'static $crate::stacked::Stacked>
-        >, &'static Freelist4KiB>;
-
-        static FREELIST_2_KIB: Freelist2KiB = $crate::fallbacked::Fallbacked(
-            $crate::limited_up_to::LimitedUpTo::new(
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(2048, 8) },
-                $crate::freelist::Freelist::new(
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(2048, 8) },
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(1025, 1) },
-                    $crate::freelist::NoLimit,
-                    &STACKED
-                )
-            ),
-            &FREELIST_4_KIB
-        );
+pub mod local_freelist;
 
-        type Freelist4KiB = $crate::fallbacked::Fallbacked<$crate::limited_up_to::LimitedUpTo<
-            $crate::freelist::Freelist<$crate::freelist::NoLimit, &'static $crate::stacked::Stacked>
-        >, &'static Freelist8KiB>;
-
-        static FREELIST_4_KIB: Freelist4KiB = $crate::fallbacked::Fallbacked(
-            $crate::limited_up_to::LimitedUpTo::new(
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(4096, 8) },
-                $crate::freelist::Freelist::new(
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(4096, 8) },
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(2049, 1) },
-                    $crate::freelist::NoLimit,
-                    &STACKED
-                )
-            ),
-            &FREELIST_8_KIB
-        );
+pub mod slab;
 
-        type Freelist8KiB = $crate::fallbacked::Fallbacked<$crate::limited_up_to::LimitedUpTo<
-            $crate::freelist::Freelist<$crate::freelist::NoLimit, &'static $crate::stacked::Stacked>
-        >, &'static Freelist16KiB>;
-
-        static FREELIST_8_KIB: Freelist8KiB = $crate::fallbacked::Fallbacked(
-            $crate::limited_up_to::LimitedUpTo::new(
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(8192, 8) },
-                $crate::freelist::Freelist::new(
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(8192, 8) },
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(4097, 1) },
-                    $crate::freelist::NoLimit,
-                    &STACKED
-                )
-            ),
-            &FREELIST_16_KIB
-        );
+pub mod bucketizer;
 
-        type Freelist16KiB = $crate::fallbacked::Fallbacked<$crate::limited_up_to::LimitedUpTo<
-            $crate::freelist::Freelist<$crate::freelist::NoLimit, &'static $crate::stacked::Stacked>
-        >, &'static Freelist32KiB>;
-
-        static FREELIST_16_KIB: Freelist16KiB = $crate::fallbacked::Fallbacked(
-            $crate::limited_up_to::LimitedUpTo::new(
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(16384, 8) },
-                $crate::freelist::Freelist::new(
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(16384, 8) },
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(8193, 1) },
-                    $crate::freelist::NoLimit,
-                    &STACKED
-                )
-            ),
-            &FREELIST_32_KIB
-        );
-    
-        type Freelist32KiB = $crate::fallbacked::Fallbacked<$crate::limited_up_to::LimitedUpTo<
-            $crate::freelist::Freelist<$crate::freelist::NoLimit, &'static $crate::stacked::Stacked>
-        >, &'static Freelist64KiB>;
-
-        static FREELIST_32_KIB: Freelist32KiB = $crate::fallbacked::Fallbacked(
-            $crate::limited_up_to::LimitedUpTo::new(
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(32768, 8) },
-                $crate::freelist::Freelist::new(
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(32768, 8) },
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(16385, 1) },
-                    $crate::freelist::NoLimit,
-                    &STACKED
-                )
-            ),
-            &FREELIST_64_KIB
-        );
+#[doc(hidden)]
+pub use core::alloc::Layout as std_alloc_Layout;
+#[doc(hidden)]
+pub use core::mem::MaybeUninit as std_mem_MaybeUninit;
+#[doc(hidden)]
+pub use core::ptr::addr_of_mut as std_ptr_addr_of_mut;
 
-        type Freelist64KiB = $crate::limited_up_to::LimitedUpTo<
-            $crate::freelist::Freelist<$crate::freelist::NoLimit, &'static $crate::stacked::Stacked>
-        >;
+/// Defines a `$ty` type alias for the
+/// [`Bucketizer`](bucketizer::Bucketizer) over a static `$mem_size`-bytes buffer,
+/// and a `$name` static of this type.
+///
+/// Only `$name` and `$ty` are defined in the caller scope,
+/// so the macro can be invoked several times in one module.
+///
+/// # Examples
+///
+/// ```
+/// # #![feature(allocator_api)]
+/// use composable_allocators::{System, freelist_allocator};
+/// use composable_allocators::bucketizer::SizeClasses;
+///
+/// freelist_allocator!(
+///     ALLOCATOR: Allocator,
+///     mem_size: 4096,
+///     align: 8,
+///     classes: SizeClasses::Linear { min: 16, step: 16 },
+///     buckets: 8,
+///     fallback: System = System,
+/// );
+///
+/// freelist_allocator!(
+///     OTHER_ALLOCATOR: OtherAllocator,
+///     mem_size: 1024,
+///     align: 8,
+///     classes: SizeClasses::PowerOfTwo { min: 8 },
+///     buckets: 4,
+///     fallback: System = System,
+/// );
+///
+/// let mut v = Vec::new_in(&ALLOCATOR);
+/// v.extend([1u8, 2, 3]);
+/// let mut w = Vec::new_in(&OTHER_ALLOCATOR);
+/// w.extend([1u8, 2, 3]);
+/// assert_ne!(v.as_ptr(), w.as_ptr());
+/// ```
+#[macro_export]
+macro_rules! freelist_allocator {
+    (
+        $name:ident : $ty:ident,
+        mem_size: $mem_size:expr,
+        align: $align:expr,
+        classes: $classes:expr,
+        buckets: $buckets:expr,
+        fallback: $fallback_ty:ty = $fallback:expr $(,)?
+    ) => {
+        type $ty = $crate::bucketizer::Bucketizer<&'static $crate::stacked::Stacked, $fallback_ty, { $buckets }>;
+
+        static $name: $ty = {
+            // Helper statics are scoped to this block.
+            static mut MEM: [$crate::std_mem_MaybeUninit<u8>; $mem_size] =
+                [$crate::std_mem_MaybeUninit::uninit(); $mem_size]
+            ;
+
+            static STACKED: $crate::stacked::Stacked =
+                $crate::stacked::Stacked::from_static_array(unsafe { &mut *$crate::std_ptr_addr_of_mut!(MEM) })
+            ;
+
+            $crate::bucketizer::Bucketizer::new($classes, $align, &STACKED, $fallback)
+        };
+    };
+}
 
-        static FREELIST_64_KIB: Freelist64KiB = $crate::limited_up_to::LimitedUpTo::new(
-            unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(65536, 8) },
-            $crate::freelist::Freelist::new(
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(65536, 8) },
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(32769, 1) },
-                $crate::freelist::NoLimit,
-                &STACKED
-            )
+#[macro_export]
+macro_rules! freelist_allocator_128_KiB_align_8 {
+    ($name:ident : $ty:ident) => {
+        $crate::freelist_allocator!(
+            $name: $ty,
+            mem_size: 131072,
+            align: 8,
+            classes: $crate::bucketizer::SizeClasses::PowerOfTwo { min: 8 },
+            buckets: 14,
+            fallback: $crate::NonWorking = $crate::NonWorking,
         );
     };
 }
@@ -312,250 +202,105 @@ macro_rules! freelist_allocator_128_KiB_align_8 {
 #[macro_export]
 macro_rules! global_freelist_allocator_128_KiB_align_8 {
     () => {
-        const MEM_SIZE: usize = 131072;
-
-        static mut MEM: [$crate::std_mem_MaybeUninit<u8>; MEM_SIZE] =
-            [$crate::std_mem_MaybeUninit::uninit(); MEM_SIZE]
-        ;
-
-        static STACKED: $crate::stacked::Stacked =
-            $crate::stacked::Stacked::from_static_array(unsafe { &mut *$crate::std_ptr_addr_of_mut!(MEM) })
-        ;
-
-        type Freelist8B = $crate::AsGlobal<$crate::fallbacked::Fallbacked<$crate::limited_up_to::LimitedUpTo<
-            $crate::freelist::Freelist<$crate::freelist::NoLimit, &'static $crate::stacked::Stacked>
-        >, &'static Freelist16B>>;
+        $crate::freelist_allocator_128_KiB_align_8!(FREELIST: Freelist);
 
         #[global_allocator]
-        static FREELIST_8_B: Freelist8B = $crate::AsGlobal($crate::fallbacked::Fallbacked(
-            $crate::limited_up_to::LimitedUpTo::new(
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(8, 8) },
-                $crate::freelist::Freelist::new(
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(8, 8) },
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(1, 1) },
-                    $crate::freelist::NoLimit,
-                    &STACKED
-                )
-            ),
-            &FREELIST_16_B
-        ));
-
-        type Freelist16B = $crate::fallbacked::Fallbacked<$crate::limited_up_to::LimitedUpTo<
-            $crate::freelist::Freelist<$crate::freelist::NoLimit, &'static $crate::stacked::Stacked>
-        >, &'static Freelist32B>;
-
-        static FREELIST_16_B: Freelist16B = $crate::fallbacked::Fallbacked(
-            $crate::limited_up_to::LimitedUpTo::new(
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(16, 8) },
-                $crate::freelist::Freelist::new(
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(16, 8) },
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(9, 1) },
-                    $crate::freelist::NoLimit,
-                    &STACKED
-                )
-            ),
-            &FREELIST_32_B
-        );
-
-        type Freelist32B = $crate::fallbacked::Fallbacked<$crate::limited_up_to::LimitedUpTo<
-            $crate::freelist::Freelist<$crate::freelist::NoLimit, &'static $crate::stacked::Stacked>
-        >, &'static Freelist64B>;
-
-        static FREELIST_32_B: Freelist32B = $crate::fallbacked::Fallbacked(
-            $crate::limited_up_to::LimitedUpTo::new(
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(32, 8) },
-                $crate::freelist::Freelist::new(
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(32, 8) },
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(17, 1) },
-                    $crate::freelist::NoLimit,
-                    &STACKED
-                )
-            ),
-            &FREELIST_64_B
-        );
-
-        type Freelist64B = $crate::fallbacked::Fallbacked<$crate::limited_up_to::LimitedUpTo<
-            $crate::freelist::Freelist<$crate::freelist::NoLimit, &'static $crate::stacked::Stacked>
-        >, &'static Freelist128B>;
-
-        static FREELIST_64_B: Freelist64B = $crate::fallbacked::Fallbacked(
-            $crate::limited_up_to::LimitedUpTo::new(
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(64, 8) },
-                $crate::freelist::Freelist::new(
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(64, 8) },
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(33, 1) },
-                    $crate::freelist::NoLimit,
-                    &STACKED
-                )
-            ),
-            &FREELIST_128_B
-        );
-
-        type Freelist128B = $crate::fallbacked::Fallbacked<$crate::limited_up_to::LimitedUpTo<
-            $crate::freelist::Freelist<$crate::freelist::NoLimit, &'static $crate::stacked::Stacked>
-        >, &'static Freelist256B>;
-
-        static FREELIST_128_B: Freelist128B = $crate::fallbacked::Fallbacked(
-            $crate::limited_up_to::LimitedUpTo::new(
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(128, 8) },
-                $crate::freelist::Freelist::new(
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(128, 8) },
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(65, 1) },
-                    $crate::freelist::NoLimit,
-                    &STACKED
-                )
-            ),
-            &FREELIST_256_B
-        );
-
-        type Freelist256B = $crate::fallbacked::Fallbacked<$crate::limited_up_to::LimitedUpTo<
-            $crate::freelist::Freelist<$crate::freelist::NoLimit, &'static $crate::stacked::Stacked>
-        >, &'static Freelist512B>;
-
-        static FREELIST_256_B: Freelist256B = $crate::fallbacked::Fallbacked(
-            $crate::limited_up_to::LimitedUpTo::new(
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(256, 8) },
-                $crate::freelist::Freelist::new(
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(256, 8) },
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(129, 1) },
-                    $crate::freelist::NoLimit,
-                    &STACKED
-                )
-            ),
-            &FREELIST_512_B
-        );
-
-        type Freelist512B = $crate::fallbacked::Fallbacked<$crate::limited_up_to::LimitedUpTo<
-            $crate::freelist::Freelist<$crate::freelist::NoLimit, &'static $crate::stacked::Stacked>
-        >, &'static Freelist1KiB>;
-
-        static FREELIST_512_B: Freelist512B = $crate::fallbacked::Fallbacked(
-            $crate::limited_up_to::LimitedUpTo::new(
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(512, 8) },
-                $crate::freelist::Freelist::new(
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(512, 8) },
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(257, 1) },
-                    $crate::freelist::NoLimit,
-                    &STACKED
-                )
-            ),
-            &FREELIST_1_KIB
-        );
-
-        type Freelist1KiB = $crate::fallbacked::Fallbacked<$crate::limited_up_to::LimitedUpTo<
-            $crate::freelist::Freelist<$crate::freelist::NoLimit, &'static $crate::stacked::Stacked>
-        >, &'static Freelist2KiB>;
-
-        static FREELIST_1_KIB: Freelist1KiB = $crate::fallbacked::Fallbacked(
-            $crate::limited_up_to::LimitedUpTo::new(
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(1024, 8) },
-                $crate::freelist::Freelist::new(
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(1024, 8) },
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(513, 1) },
-                    $crate::freelist::NoLimit,
-                    &STACKED
-                )
-            ),
-            &FREELIST_2_KIB
-        );
-
-        type Freelist2KiB = $crate::fallbacked::Fallbacked<$crate::limited_up_to::LimitedUpTo<
-            $crate::freelist::Freelist<$crate::freelist::NoLimit, &'static $crate::stacked::Stacked>
-        >, &'static Freelist4KiB>;
-
-        static FREELIST_2_KIB: Freelist2KiB = $crate::fallbacked::Fallbacked(
-            $crate::limited_up_to::LimitedUpTo::new(
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(2048, 8) },
-                $crate::freelist::Freelist::new(
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(2048, 8) },
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(1025, 1) },
-                    $crate::freelist::NoLimit,
-                    &STACKED
-                )
-            ),
-            &FREELIST_4_KIB
-        );
+        static GLOBAL_FREELIST: $crate::AsGlobal<&'static Freelist> = $crate::AsGlobal(&FREELIST);
+    };
+}
 
-        type Freelist4KiB = $crate::fallbacked::Fallbacked<$crate::limited_up_to::LimitedUpTo<
-            $crate::freelist::Freelist<$crate::freelist::NoLimit, &'static $crate::stacked::Stacked>
-        >, &'static Freelist8KiB>;
-
-        static FREELIST_4_KIB: Freelist4KiB = $crate::fallbacked::Fallbacked(
-            $crate::limited_up_to::LimitedUpTo::new(
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(4096, 8) },
-                $crate::freelist::Freelist::new(
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(4096, 8) },
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(2049, 1) },
-                    $crate::freelist::NoLimit,
-                    &STACKED
-                )
-            ),
-            &FREELIST_8_KIB
-        );
+/// Defines a `$ty` type alias for the
+/// [`Bucketizer`](bucketizer::Bucketizer) over a [`Heap`](heap::Heap) in a static `$mem_size`-bytes buffer,
+/// and a `$name` static of this type.
+///
+/// Unlike [`freelist_allocator`], every size class caches at most `bucket_limit` free blocks.
+/// Blocks exceeding the limit, including blocks moved out on growing,
+/// and blocks released with [`Freelist::trim`](freelist::Freelist::trim),
+/// are merged in the heap, and can be reused by other size classes.
+///
+/// # Examples
+///
+/// ```
+/// # #![feature(allocator_api)]
+/// use composable_allocators::{System, heap_freelist_allocator};
+/// use composable_allocators::bucketizer::SizeClasses;
+/// use composable_allocators::heap::Fit;
+///
+/// heap_freelist_allocator!(
+///     ALLOCATOR: Allocator,
+///     mem_size: 4096,
+///     fit: Fit::Best,
+///     align: 8,
+///     classes: SizeClasses::Linear { min: 16, step: 16 },
+///     buckets: 8,
+///     bucket_limit: 4,
+///     fallback: System = System,
+/// );
+///
+/// let mut v = Vec::new_in(&ALLOCATOR);
+/// v.extend([1u8, 2, 3]);
+/// ```
+#[macro_export]
+macro_rules! heap_freelist_allocator {
+    (
+        $name:ident : $ty:ident,
+        mem_size: $mem_size:expr,
+        fit: $fit:expr,
+        align: $align:expr,
+        classes: $classes:expr,
+        buckets: $buckets:expr,
+        bucket_limit: $bucket_limit:expr,
+        fallback: $fallback_ty:ty = $fallback:expr $(,)?
+    ) => {
+        type $ty = $crate::bucketizer::Bucketizer<
+            &'static $crate::heap::Heap,
+            $fallback_ty,
+            { $buckets },
+            $crate::freelist::FixedLimit
+        >;
 
-        type Freelist8KiB = $crate::fallbacked::Fallbacked<$crate::limited_up_to::LimitedUpTo<
-            $crate::freelist::Freelist<$crate::freelist::NoLimit, &'static $crate::stacked::Stacked>
-        >, &'static Freelist16KiB>;
-
-        static FREELIST_8_KIB: Freelist8KiB = $crate::fallbacked::Fallbacked(
-            $crate::limited_up_to::LimitedUpTo::new(
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(8192, 8) },
-                $crate::freelist::Freelist::new(
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(8192, 8) },
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(4097, 1) },
-                    $crate::freelist::NoLimit,
-                    &STACKED
-                )
-            ),
-            &FREELIST_16_KIB
-        );
+        static $name: $ty = {
+            static mut MEM: [$crate::std_mem_MaybeUninit<u8>; $mem_size] =
+                [$crate::std_mem_MaybeUninit::uninit(); $mem_size]
+            ;
+
+            static HEAP: $crate::heap::Heap =
+                $crate::heap::Heap::from_static_array(unsafe { &mut *$crate::std_ptr_addr_of_mut!(MEM) }, $fit)
+            ;
+
+            $crate::bucketizer::Bucketizer::with_limit(
+                $classes,
+                $align,
+                $crate::freelist::FixedLimit::new($bucket_limit),
+                &HEAP,
+                $fallback
+            )
+        };
+    };
+}
 
-        type Freelist16KiB = $crate::fallbacked::Fallbacked<$crate::limited_up_to::LimitedUpTo<
-            $crate::freelist::Freelist<$crate::freelist::NoLimit, &'static $crate::stacked::Stacked>
-        >, &'static Freelist32KiB>;
-
-        static FREELIST_16_KIB: Freelist16KiB = $crate::fallbacked::Fallbacked(
-            $crate::limited_up_to::LimitedUpTo::new(
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(16384, 8) },
-                $crate::freelist::Freelist::new(
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(16384, 8) },
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(8193, 1) },
-                    $crate::freelist::NoLimit,
-                    &STACKED
-                )
-            ),
-            &FREELIST_32_KIB
-        );
-    
-        type Freelist32KiB = $crate::fallbacked::Fallbacked<$crate::limited_up_to::LimitedUpTo<
-            $crate::freelist::Freelist<$crate::freelist::NoLimit, &'static $crate::stacked::Stacked>
-        >, &'static Freelist64KiB>;
-
-        static FREELIST_32_KIB: Freelist32KiB = $crate::fallbacked::Fallbacked(
-            $crate::limited_up_to::LimitedUpTo::new(
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(32768, 8) },
-                $crate::freelist::Freelist::new(
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(32768, 8) },
-                    unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(16385, 1) },
-                    $crate::freelist::NoLimit,
-                    &STACKED
-                )
-            ),
-            &FREELIST_64_KIB
+#[macro_export]
+macro_rules! heap_freelist_allocator_128_KiB_align_8 {
+    ($name:ident : $ty:ident) => {
+        $crate::heap_freelist_allocator!(
+            $name: $ty,
+            mem_size: 131072,
+            fit: $crate::heap::Fit::Best,
+            align: 8,
+            classes: $crate::bucketizer::SizeClasses::PowerOfTwo { min: 8 },
+            buckets: 14,
+            bucket_limit: 16,
+            fallback: $crate::NonWorking = $crate::NonWorking,
         );
+    };
+}
 
-        type Freelist64KiB = $crate::limited_up_to::LimitedUpTo<
-            $crate::freelist::Freelist<$crate::freelist::NoLimit, &'static $crate::stacked::Stacked>
-        >;
+#[macro_export]
+macro_rules! global_heap_freelist_allocator_128_KiB_align_8 {
+    () => {
+        $crate::heap_freelist_allocator_128_KiB_align_8!(FREELIST: Freelist);
 
-        static FREELIST_64_KIB: Freelist64KiB = $crate::limited_up_to::LimitedUpTo::new(
-            unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(65536, 8) },
-            $crate::freelist::Freelist::new(
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(65536, 8) },
-                unsafe { $crate::std_alloc_Layout::from_size_align_unchecked(32769, 1) },
-                $crate::freelist::NoLimit,
-                &STACKED
-            )
-        );
+        #[global_allocator]
+        static GLOBAL_FREELIST: $crate::AsGlobal<&'static Freelist> = $crate::AsGlobal(&FREELIST);
     };
 }
@@ -0,0 +1,193 @@
+use crate::base::*;
+use crate::freelist::{Core, DynamicLimit, LimitParam, List, MIN_LAYOUT_ALIGN, MIN_LAYOUT_SIZE};
+use core::alloc::{self, AllocError, Allocator};
+use core::cell::RefCell;
+use core::ptr::NonNull;
+
+/// A single-threaded [`Freelist`](crate::freelist::Freelist) counterpart without locking.
+///
+/// It shares the freelist logic, including batches, trimming and limits.
+pub struct LocalFreelist<Limit: LimitParam, A: Allocator> {
+    list: RefCell<List>,
+    core: Core<Limit>,
+    base: A,
+}
+
+unsafe impl<Limit: LimitParam, A: NonUnwinding> NonUnwinding for LocalFreelist<Limit, A> { }
+
+impl<Limit: LimitParam, A: Allocator> LocalFreelist<Limit, A> {
+    pub const fn new(layout: alloc::Layout, tolerance: alloc::Layout, limit: Limit, base: A) -> Self {
+        assert!(tolerance.size() <= layout.size() && tolerance.align() <= layout.align());
+        assert!(layout.size() >= MIN_LAYOUT_SIZE && layout.align() >= MIN_LAYOUT_ALIGN);
+        unsafe { Self::new_unchecked(layout, tolerance, limit, base) }
+    }
+
+    /// Creates a freelist, which requests memory for at least `batch` blocks at once from the base allocator,
+    /// see [`Freelist::new_batched`](crate::freelist::Freelist::new_batched).
+    pub const fn new_batched(
+        layout: alloc::Layout,
+        tolerance: alloc::Layout,
+        limit: Limit,
+        batch: usize,
+        base: A
+    ) -> Self {
+        assert!(tolerance.size() <= layout.size() && tolerance.align() <= layout.align());
+        assert!(layout.size() >= MIN_LAYOUT_SIZE && layout.align() >= MIN_LAYOUT_ALIGN);
+        unsafe { Self::new_batched_unchecked(layout, tolerance, limit, batch, base) }
+    }
+
+    /// # Safety
+    ///
+    /// Arguments should satisfy
+    /// `tolerance.size() <= layout.size() && tolerance.align() <= layout.align()`,
+    /// and
+    /// `layout.size() >= MIN_LAYOUT_SIZE && layout.align() >= MIN_LAYOUT_ALIGN`.
+    pub const unsafe fn new_unchecked(layout: alloc::Layout, tolerance: alloc::Layout, limit: Limit, base: A) -> Self {
+        Self::new_batched_unchecked(layout, tolerance, limit, 1, base)
+    }
+
+    /// # Safety
+    ///
+    /// Arguments should satisfy
+    /// `tolerance.size() <= layout.size() && tolerance.align() <= layout.align()`,
+    /// and
+    /// `layout.size() >= MIN_LAYOUT_SIZE && layout.align() >= MIN_LAYOUT_ALIGN`.
+    pub const unsafe fn new_batched_unchecked(
+        layout: alloc::Layout,
+        tolerance: alloc::Layout,
+        limit: Limit,
+        batch: usize,
+        base: A
+    ) -> Self {
+        LocalFreelist {
+            list: RefCell::new(List::new()),
+            core: Core::new(layout, tolerance, limit, batch),
+            base,
+        }
+    }
+
+    pub fn layout(&self) -> alloc::Layout { self.core.layout() }
+
+    pub fn limit(&self) -> &Limit { self.core.limit() }
+
+    pub fn tolerance(&self) -> alloc::Layout { self.core.tolerance() }
+
+    /// Returns the number of cached free blocks.
+    pub fn cached_len(&self) -> usize {
+        self.list.borrow().len()
+    }
+
+    /// Releases cached free blocks to the base allocator, keeping at most `keep` of them.
+    pub fn trim(&self, keep: usize) {
+        let mut list = self.list.borrow_mut();
+        unsafe { self.core.release(&mut list, keep, &self.base); }
+    }
+
+    /// Releases all cached free blocks to the base allocator.
+    ///
+    /// Cached blocks are not released on drop, so this should be called explicitly,
+    /// if the base allocator outlives the freelist.
+    pub fn release_all(&self) {
+        self.trim(0);
+    }
+}
+
+impl<A: Allocator> LocalFreelist<DynamicLimit, A> {
+    /// Changes the limit, releasing cached blocks exceeding the new limit to the base allocator.
+    pub fn set_limit(&self, limit: usize) {
+        self.core.set_limit(&self.list, &self.base, limit);
+    }
+}
+
+unsafe impl<Limit: LimitParam, A: Fallbackable> Fallbackable for LocalFreelist<Limit, A> {
+    unsafe fn has_allocated(&self, ptr: NonNull<u8>, layout: alloc::Layout) -> bool {
+        self.core.has_allocated(&self.base, ptr, layout)
+    }
+
+    fn allows_fallback(&self, layout: alloc::Layout) -> bool {
+        self.core.allows_fallback(&self.base, layout)
+    }
+}
+
+unsafe impl<Limit: LimitParam, A: Allocator> Allocator for LocalFreelist<Limit, A> {
+    fn allocate(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.core.allocate(&self.list, &self.base, layout)
+    }
+
+    fn allocate_zeroed(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.core.allocate_zeroed(&self.list, &self.base, layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: alloc::Layout) {
+        self.core.deallocate(&self.list, &self.base, ptr, layout)
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.core.grow(&self.list, &self.base, ptr, old_layout, new_layout)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.core.grow_zeroed(&self.list, &self.base, ptr, old_layout, new_layout)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.core.shrink(&self.list, &self.base, ptr, old_layout, new_layout)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Stats, System};
+    use crate::freelist::FixedLimit;
+    use crate::local_freelist::LocalFreelist;
+    use core::alloc::{self, Allocator};
+
+    #[test]
+    fn limit_and_trim() {
+        let base = Stats::new(System);
+        let layout = alloc::Layout::from_size_align(32, 8).unwrap();
+        let freelist = LocalFreelist::new(layout, layout, FixedLimit::new(4), &base);
+        let blocks = [(); 6].map(|_| freelist.allocate(layout).unwrap().as_non_null_ptr());
+        for block in blocks {
+            unsafe { freelist.deallocate(block, layout); }
+        }
+        assert_eq!(freelist.cached_len(), 4);
+        assert_eq!(base.live_blocks(), 4);
+        freelist.trim(1);
+        assert_eq!(base.live_blocks(), 1);
+        freelist.release_all();
+        assert_eq!(base.live_blocks(), 0);
+    }
+
+    #[test]
+    fn batched_blocks_are_released_by_batches() {
+        let base = Stats::new(System);
+        let layout = alloc::Layout::from_size_align(32, 8).unwrap();
+        let freelist = LocalFreelist::new_batched(layout, layout, FixedLimit::new(0), 4, &base);
+        // A batch is rounded up to 256 bytes, which holds seven blocks after the header.
+        let blocks = [(); 8].map(|_| freelist.allocate(layout).unwrap().as_non_null_ptr());
+        assert_eq!(base.live_blocks(), 2);
+        for block in blocks {
+            unsafe { freelist.deallocate(block, layout); }
+        }
+        assert_eq!(base.live_blocks(), 2);
+        freelist.release_all();
+        assert_eq!(freelist.cached_len(), 0);
+        assert_eq!(base.live_blocks(), 0);
+    }
+}
@@ -0,0 +1,245 @@
+use crate::base::*;
+use crate::buffer::{self, Buf};
+use crate::stacked::{self, Checkpoint, Core, LeakPolicy, Leaks};
+use core::alloc::{self, AllocError, Allocator};
+use core::cell::Cell;
+use core::mem::MaybeUninit;
+use core::ptr::NonNull;
+
+/// A single-threaded [`Stacked`](crate::stacked::Stacked) counterpart without atomic operations.
+///
+/// It shares the bump allocator logic, including checkpoints and the leak policy.
+/// It is not `Sync`, so it cannot be placed in a `static`,
+/// and is created over a buffer with [`with_size`] or [`with_buf`] instead.
+pub struct LocalStacked {
+    core: Core<Cell<usize>, Cell<LeakPolicy>>,
+}
+
+impl Drop for LocalStacked {
+    fn drop(&mut self) {
+        self.core.finish("LocalStacked");
+    }
+}
+
+unsafe impl NonUnwinding for LocalStacked { }
+
+pub type Frame<'a> = stacked::Frame<'a, LocalStacked>;
+
+impl LocalStacked {
+    /// # Safety
+    ///
+    /// Arguments should satisfy [`Stacked::with_buf_raw`](crate::stacked::Stacked::with_buf_raw) requirements.
+    pub unsafe fn with_buf_raw<T>(
+        buf_ptr: NonNull<MaybeUninit<u8>>,
+        buf_len: usize,
+        f: impl for<'a> FnOnce(&'a LocalStacked) -> T
+    ) -> T {
+        Self::with(Buf::new(buf_ptr, buf_len), f)
+    }
+
+    fn with<T>(buf: Buf, f: impl for<'a> FnOnce(&'a LocalStacked) -> T) -> T {
+        let stacked = LocalStacked {
+            core: Core::new(buf, Cell::new(0), Cell::new(0), Cell::new(LeakPolicy::Panic)),
+        };
+        stacked.core.run(|| f(&stacked))
+    }
+
+    pub fn leak_policy(&self) -> LeakPolicy {
+        self.core.leak_policy()
+    }
+
+    /// Sets what to do on drop, if there are outstanding allocations.
+    /// The default policy is [`LeakPolicy::Panic`].
+    pub fn set_leak_policy(&self, policy: LeakPolicy) {
+        self.core.set_leak_policy(policy);
+    }
+
+    /// Returns outstanding allocations, if any, instead of applying the leak policy on drop,
+    /// see [`Stacked::try_finish`](crate::stacked::Stacked::try_finish).
+    pub fn try_finish(&self) -> Result<(), Leaks> {
+        self.core.try_finish()
+    }
+
+    pub fn checkpoint(&self) -> Checkpoint {
+        self.core.checkpoint()
+    }
+
+    /// Resets the allocator to the state captured by `checkpoint`,
+    /// see [`Stacked::rollback`](crate::stacked::Stacked::rollback).
+    ///
+    /// # Safety
+    ///
+    /// The `checkpoint` should be taken from this allocator.
+    ///
+    /// Blocks allocated after the `checkpoint` was taken should not be used or deallocated after the call.
+    ///
+    /// Blocks allocated before the `checkpoint` was taken should not be deallocated, grown or shrunk between
+    /// taking the `checkpoint` and the call.
+    pub unsafe fn rollback(&self, checkpoint: Checkpoint) {
+        self.core.rollback(checkpoint);
+    }
+
+    /// Takes a checkpoint and returns a guard, which [rolls back](LocalStacked::rollback) to it on drop.
+    ///
+    /// # Safety
+    ///
+    /// Blocks allocated while the frame is alive should not be used or deallocated after it is dropped.
+    ///
+    /// Blocks allocated before the frame was created should not be deallocated, grown or shrunk while it is alive.
+    pub unsafe fn frame(&self) -> Frame<'_> {
+        Frame::new(self, self.checkpoint(), LocalStacked::rollback)
+    }
+}
+
+pub fn with_size<const BUF_LEN: usize, T>(
+    f: impl for<'a> FnOnce(&'a LocalStacked) -> T
+) -> T {
+    buffer::with_size::<BUF_LEN, _>(|buf| LocalStacked::with(buf, f))
+}
+
+pub fn with_buf<T>(
+    buf: &mut [MaybeUninit<u8>],
+    f: impl for<'a> FnOnce(&'a LocalStacked) -> T
+) -> T {
+    buffer::with_buf(buf, |buf| LocalStacked::with(buf, f))
+}
+
+unsafe impl Fallbackable for LocalStacked {
+    unsafe fn has_allocated(&self, ptr: NonNull<u8>, _layout: alloc::Layout) -> bool {
+        self.core.has_allocated(ptr)
+    }
+
+    fn allows_fallback(&self, _layout: alloc::Layout) -> bool {
+        true
+    }
+}
+
+unsafe impl Allocator for LocalStacked {
+    fn allocate(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.core.allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: alloc::Layout) {
+        self.core.deallocate(ptr, layout);
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.core.grow(ptr, old_layout, new_layout, false)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.core.grow(ptr, old_layout, new_layout, true)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.core.shrink(ptr, old_layout, new_layout)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use crate::local_stacked;
+    use crate::stacked::LeakPolicy;
+    use core::alloc::{self, Allocator};
+
+    fn layout(size: usize) -> alloc::Layout {
+        alloc::Layout::from_size_align(size, 8).unwrap()
+    }
+
+    #[test]
+    fn allocates_and_deallocates_top() {
+        local_stacked::with_size::<64, _>(|stacked| {
+            let a = stacked.allocate(layout(8)).unwrap();
+            let b = stacked.allocate(layout(8)).unwrap();
+            assert_eq!(a.len(), 8);
+            assert_eq!(b.as_mut_ptr() as usize, a.as_mut_ptr() as usize + 8);
+            unsafe { stacked.deallocate(b.as_non_null_ptr(), layout(8)); }
+            let c = stacked.allocate(layout(16)).unwrap();
+            assert_eq!(c.as_non_null_ptr(), b.as_non_null_ptr());
+            assert!(stacked.allocate(layout(64)).is_err());
+            unsafe {
+                stacked.deallocate(a.as_non_null_ptr(), layout(8));
+                stacked.deallocate(c.as_non_null_ptr(), layout(16));
+            }
+            assert!(stacked.try_finish().is_ok());
+        });
+    }
+
+    #[test]
+    fn grows_top_block_in_place() {
+        local_stacked::with_size::<64, _>(|stacked| unsafe {
+            let a = stacked.allocate(layout(8)).unwrap().as_non_null_ptr();
+            let a = stacked.grow_zeroed(a, layout(8), layout(24)).unwrap();
+            assert_eq!(a.len(), 24);
+            assert!(a.as_ref()[8 ..].iter().all(|&x| x == 0));
+            let b = stacked.allocate(layout(8)).unwrap().as_non_null_ptr();
+            assert_eq!(b.as_ptr() as usize, a.as_mut_ptr() as usize + 24);
+            assert!(stacked.grow(a.as_non_null_ptr(), layout(24), layout(32)).is_err());
+            let b = stacked.grow(b, layout(8), layout(16)).unwrap();
+            assert_eq!(b.len(), 16);
+            let b = stacked.shrink(b.as_non_null_ptr(), layout(16), layout(8)).unwrap();
+            assert_eq!(b.len(), 8);
+            stacked.deallocate(b.as_non_null_ptr(), layout(8));
+            stacked.deallocate(a.as_non_null_ptr(), layout(24));
+        });
+    }
+
+    #[test]
+    fn rollback_and_frame() {
+        local_stacked::with_size::<64, _>(|stacked| {
+            let kept = stacked.allocate(layout(8)).unwrap().as_non_null_ptr();
+            let checkpoint = stacked.checkpoint();
+            let reused = {
+                let frame = unsafe { stacked.frame() };
+                let block = frame.allocate(layout(8)).unwrap().as_non_null_ptr();
+                frame.allocate(layout(8)).unwrap();
+                block
+            };
+            assert_eq!(stacked.checkpoint(), checkpoint);
+            for _ in 0 .. 2 {
+                stacked.allocate(layout(8)).unwrap();
+            }
+            unsafe { stacked.rollback(checkpoint); }
+            let block = stacked.allocate(layout(8)).unwrap().as_non_null_ptr();
+            assert_eq!(block, reused);
+            unsafe {
+                stacked.deallocate(block, layout(8));
+                stacked.deallocate(kept, layout(8));
+            }
+            assert!(stacked.try_finish().is_ok());
+        });
+    }
+
+    #[test]
+    fn leak_policy_is_applied_on_drop() {
+        local_stacked::with_size::<64, _>(|stacked| {
+            stacked.allocate(alloc::Layout::new::<u32>()).unwrap();
+            assert_eq!(stacked.try_finish().unwrap_err().allocations, 1);
+            assert!(matches!(stacked.leak_policy(), LeakPolicy::Ignore));
+        });
+        let res = std::panic::catch_unwind(|| {
+            local_stacked::with_size::<64, _>(|stacked| {
+                stacked.allocate(alloc::Layout::new::<u32>()).unwrap();
+                panic!("unwinding");
+            })
+        });
+        assert!(res.is_err());
+    }
+}
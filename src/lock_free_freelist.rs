@@ -0,0 +1,307 @@
+use crate::base::*;
+use crate::freelist::{LimitParam, MIN_LAYOUT_ALIGN, MIN_LAYOUT_SIZE};
+use core::alloc::{self, AllocError, Allocator};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+#[cfg(target_pointer_width="64")]
+const PTR_BITS: u32 = 48;
+
+#[cfg(not(target_pointer_width="64"))]
+const PTR_BITS: u32 = usize::BITS;
+
+const PTR_MASK: u64 = (1 << PTR_BITS) - 1;
+
+fn pack(ptr: *mut u8, tag: u64) -> u64 {
+    (ptr.expose_provenance() as u64) | (tag << PTR_BITS)
+}
+
+fn unpack(head: u64) -> (*mut u8, u64) {
+    (ptr::with_exposed_provenance_mut((head & PTR_MASK) as usize), head >> PTR_BITS)
+}
+
+fn fits(ptr: NonNull<u8>) -> bool {
+    (ptr.as_ptr() as usize as u64) & !PTR_MASK == 0
+}
+
+unsafe fn next<'a>(node: *mut u8) -> &'a AtomicPtr<u8> {
+    AtomicPtr::from_ptr(node as *mut *mut u8)
+}
+
+/// A [`Freelist`](crate::freelist::Freelist) counterpart,
+/// which keeps cached blocks in a lock-free stack instead of a mutex-guarded list.
+///
+/// The stack head is a pointer with a modification tag, so a concurrent pop cannot be fooled
+/// by a block removed and pushed back meanwhile.
+/// A block is released to the base allocator only when there are no concurrent pops,
+/// which can still read it.
+pub struct LockFreeFreelist<Limit: LimitParam, A: Allocator> {
+    head: AtomicU64,
+    len: AtomicUsize,
+    poppers: AtomicUsize,
+    layout: alloc::Layout,
+    tolerance: alloc::Layout,
+    limit: Limit,
+    base: A,
+}
+
+impl<Limit: LimitParam, A: Allocator> Drop for LockFreeFreelist<Limit, A> {
+    fn drop(&mut self) {
+        while let Some(ptr) = self.pop() {
+            unsafe { self.base.deallocate(ptr, self.layout); }
+        }
+    }
+}
+
+unsafe impl<Limit: LimitParam, A: NonUnwinding> NonUnwinding for LockFreeFreelist<Limit, A> { }
+
+impl<Limit: LimitParam, A: Allocator> LockFreeFreelist<Limit, A> {
+    pub const fn new(layout: alloc::Layout, tolerance: alloc::Layout, limit: Limit, base: A) -> Self {
+        assert!(tolerance.size() <= layout.size() && tolerance.align() <= layout.align());
+        assert!(layout.size() >= MIN_LAYOUT_SIZE && layout.align() >= MIN_LAYOUT_ALIGN);
+        unsafe { Self::new_unchecked(layout, tolerance, limit, base) }
+    }
+
+    /// # Safety
+    ///
+    /// Arguments should satisfy
+    /// `tolerance.size() <= layout.size() && tolerance.align() <= layout.align()`,
+    /// and
+    /// `layout.size() >= MIN_LAYOUT_SIZE && layout.align() >= MIN_LAYOUT_ALIGN`.
+    pub const unsafe fn new_unchecked(layout: alloc::Layout, tolerance: alloc::Layout, limit: Limit, base: A) -> Self {
+        LockFreeFreelist {
+            head: AtomicU64::new(0),
+            len: AtomicUsize::new(0),
+            poppers: AtomicUsize::new(0),
+            layout,
+            tolerance,
+            limit,
+            base,
+        }
+    }
+
+    pub fn layout(&self) -> alloc::Layout { self.layout }
+
+    pub fn limit(&self) -> &Limit { &self.limit }
+
+    pub fn tolerance(&self) -> alloc::Layout { self.tolerance }
+
+    fn manages(&self, layout: alloc::Layout) -> bool {
+        (self.tolerance.size() ..= self.layout.size()).contains(&layout.size()) &&
+        (self.tolerance.align() ..= self.layout.align()).contains(&layout.align())
+    }
+
+    fn pop(&self) -> Option<NonNull<u8>> {
+        self.poppers.fetch_add(1, Ordering::SeqCst);
+        let mut head = self.head.load(Ordering::SeqCst);
+        let res = loop {
+            let (ptr, tag) = unpack(head);
+            let Some(node) = NonNull::new(ptr) else { break None; };
+            let next = unsafe { next(ptr) }.load(Ordering::SeqCst);
+            match self.head.compare_exchange_weak(
+                head, pack(next, tag.wrapping_add(1)), Ordering::SeqCst, Ordering::SeqCst
+            ) {
+                Ok(_) => break Some(node),
+                Err(actual) => head = actual,
+            }
+        };
+        self.poppers.fetch_sub(1, Ordering::SeqCst);
+        if res.is_some() {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+        }
+        res
+    }
+
+    unsafe fn push(&self, ptr: NonNull<u8>) {
+        let mut head = self.head.load(Ordering::SeqCst);
+        loop {
+            let (head_ptr, tag) = unpack(head);
+            next(ptr.as_ptr()).store(head_ptr, Ordering::SeqCst);
+            match self.head.compare_exchange_weak(
+                head, pack(ptr.as_ptr(), tag.wrapping_add(1)), Ordering::SeqCst, Ordering::SeqCst
+            ) {
+                Ok(_) => break,
+                Err(actual) => head = actual,
+            }
+        }
+        self.len.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn no_poppers(&self) -> bool {
+        self.poppers.load(Ordering::SeqCst) == 0
+    }
+
+    /// Returns the number of cached free blocks.
+    pub fn cached_len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Releases cached free blocks to the base allocator, keeping at most `keep` of them.
+    ///
+    /// Releasing stops early, if there are concurrent allocations.
+    pub fn trim(&self, keep: usize) {
+        while self.len.load(Ordering::Relaxed) > keep {
+            let Some(ptr) = self.pop() else { break; };
+            if self.no_poppers() {
+                unsafe { self.base.deallocate(ptr, self.layout); }
+            } else {
+                unsafe { self.push(ptr); }
+                break;
+            }
+        }
+    }
+
+    /// Releases all cached free blocks to the base allocator.
+    ///
+    /// Releasing stops early, if there are concurrent allocations.
+    pub fn release_all(&self) {
+        self.trim(0);
+    }
+}
+
+unsafe impl<Limit: LimitParam, A: Fallbackable> Fallbackable for LockFreeFreelist<Limit, A> {
+    unsafe fn has_allocated(&self, ptr: NonNull<u8>, layout: alloc::Layout) -> bool {
+        let layout = if self.manages(layout) { self.layout } else { layout };
+        self.base.has_allocated(ptr, layout)
+    }
+
+    fn allows_fallback(&self, layout: alloc::Layout) -> bool {
+        let layout = if self.manages(layout) { self.layout } else { layout };
+        self.base.allows_fallback(layout)
+    }
+}
+
+unsafe impl<Limit: LimitParam, A: Allocator> Allocator for LockFreeFreelist<Limit, A> {
+    fn allocate(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if !self.manages(layout) {
+            return self.base.allocate(layout);
+        }
+        if let Some(ptr) = self.pop() {
+            Ok(NonNull::slice_from_raw_parts(ptr, self.layout.size()))
+        } else {
+            self.base.allocate(self.layout)
+        }
+    }
+
+    fn allocate_zeroed(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if !self.manages(layout) {
+            return self.base.allocate_zeroed(layout);
+        }
+        if let Some(ptr) = self.pop() {
+            let ptr = NonNull::slice_from_raw_parts(ptr, self.layout.size());
+            unsafe { ptr.as_mut_ptr().write_bytes(0, ptr.len()); }
+            Ok(ptr)
+        } else {
+            self.base.allocate_zeroed(self.layout)
+        }
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: alloc::Layout) {
+        if !self.manages(layout) {
+            return self.base.deallocate(ptr, layout);
+        }
+        // A block, which does not fit into the tagged head, could never be cached,
+        // so it cannot be read by a concurrent pop.
+        if !fits(ptr) || (self.limit.limit_reached(self.len.load(Ordering::Relaxed)) && self.no_poppers()) {
+            return self.base.deallocate(ptr, self.layout);
+        }
+        self.push(ptr);
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let old_layout = if self.manages(old_layout) { self.layout } else { old_layout };
+        self.base.grow(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let old_layout = if self.manages(old_layout) { self.layout } else { old_layout };
+        self.base.grow_zeroed(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let old_layout = if self.manages(old_layout) {
+            if self.manages(new_layout) {
+                return Ok(NonNull::slice_from_raw_parts(ptr, self.layout.size()));
+            }
+            self.layout
+        } else {
+            old_layout
+        };
+        self.base.shrink(ptr, old_layout, new_layout)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use crate::System;
+    use crate::freelist::{FixedLimit, NoLimit};
+    use crate::lock_free_freelist::LockFreeFreelist;
+    use core::alloc::{self, Allocator};
+    use core::ptr::NonNull;
+    use std::thread;
+    use std::vec::Vec;
+
+    const THREADS: usize = 8;
+    const ITERATIONS: usize = 10000;
+
+    fn stress<A: Allocator + Sync>(allocator: &A, layout: alloc::Layout) {
+        thread::scope(|scope| {
+            for id in 0 .. THREADS {
+                scope.spawn(move || {
+                    let mut blocks = Vec::new();
+                    for i in 0 .. ITERATIONS {
+                        let block = allocator.allocate(layout).unwrap();
+                        unsafe { block.as_mut_ptr().write_bytes(id as u8, layout.size()); }
+                        blocks.push(block.as_non_null_ptr());
+                        if i % 3 != 0 {
+                            let ptr: NonNull<u8> = blocks.swap_remove((i * 7) % blocks.len());
+                            for offset in 0 .. layout.size() {
+                                assert_eq!(unsafe { *ptr.as_ptr().add(offset) }, id as u8);
+                            }
+                            unsafe { allocator.deallocate(ptr, layout); }
+                        }
+                    }
+                    for ptr in blocks {
+                        unsafe { allocator.deallocate(ptr, layout); }
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn concurrent_no_limit() {
+        let layout = alloc::Layout::from_size_align(32, 8).unwrap();
+        let freelist = LockFreeFreelist::new(layout, layout, NoLimit, System);
+        stress(&freelist, layout);
+        assert!(freelist.cached_len() > 0);
+        freelist.release_all();
+        assert_eq!(freelist.cached_len(), 0);
+    }
+
+    #[test]
+    fn concurrent_fixed_limit() {
+        let layout = alloc::Layout::from_size_align(16, 8).unwrap();
+        let freelist = LockFreeFreelist::new(layout, layout, FixedLimit::new(16), System);
+        stress(&freelist, layout);
+        freelist.trim(4);
+        assert!(freelist.cached_len() <= 4);
+    }
+}
@@ -1,12 +1,234 @@
 use crate::base::*;
+use crate::spin_lock::SpinLock;
 use core::alloc::{self, AllocError, Allocator};
+use core::fmt::{self, Display, Formatter};
+use core::mem::MaybeUninit;
 use core::ptr::NonNull;
 use print_no_std::Stderr;
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Operation {
+    Allocate,
+    AllocateZeroed,
+    Deallocate,
+    Grow,
+    GrowZeroed,
+    Shrink,
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let name = match self {
+            Operation::Allocate => "allocate",
+            Operation::AllocateZeroed => "allocate_zeroed",
+            Operation::Deallocate => "deallocate",
+            Operation::Grow => "grow",
+            Operation::GrowZeroed => "grow_zeroed",
+            Operation::Shrink => "shrink",
+        };
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Record {
+    pub operation: Operation,
+    /// The pointer passed to the operation, if any.
+    pub ptr: Option<NonNull<u8>>,
+    /// The old layout for `grow`, `grow_zeroed`, and `shrink`.
+    pub old_layout: Option<alloc::Layout>,
+    pub layout: alloc::Layout,
+    /// The operation result, if the operation has any.
+    pub result: Option<Result<NonNull<[u8]>, AllocError>>,
+}
+
+impl Display for Record {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}:", self.operation)?;
+        if let Some(ptr) = self.ptr {
+            write!(f, " {ptr:p}")?;
+        }
+        if let Some(old_layout) = self.old_layout {
+            write!(f, " {old_layout:?} ->")?;
+        }
+        write!(f, " {:?}", self.layout)?;
+        match self.result {
+            None => Ok(()),
+            Some(Ok(block)) => write!(f, " = {:p}, {} bytes", block.as_mut_ptr(), block.len()),
+            Some(Err(AllocError)) => write!(f, " = failed"),
+        }
+    }
+}
+
+pub trait LogSink {
+    fn log(&self, record: &Record);
+}
+
+impl<'a, T: LogSink + ?Sized> LogSink for &'a T {
+    fn log(&self, record: &Record) {
+        (*self).log(record)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct StderrSink;
+
+impl LogSink for StderrSink {
+    fn log(&self, record: &Record) {
+        let _ = writeln!(Stderr { panic: false }, "{record}");
+    }
+}
+
+/// Writes records to a raw file descriptor.
+///
+/// Interrupted and partial writes are retried until the whole record is written.
+#[cfg(all(not(target_os="dos"), not(windows)))]
+#[derive(Debug, Copy, Clone)]
+pub struct FdSink(pub libc::c_int);
+
+#[cfg(any(target_os="linux", target_os="emscripten", target_os="hurd", target_os="redox", target_os="dragonfly"))]
+unsafe fn errno() -> libc::c_int { *libc::__errno_location() }
+
+#[cfg(any(target_os="android", target_os="netbsd", target_os="openbsd"))]
+unsafe fn errno() -> libc::c_int { *libc::__errno() }
+
+#[cfg(any(target_vendor="apple", target_os="freebsd"))]
+unsafe fn errno() -> libc::c_int { *libc::__error() }
+
+#[cfg(all(
+    not(target_os="dos"), not(windows),
+    not(any(target_os="linux", target_os="emscripten", target_os="hurd", target_os="redox", target_os="dragonfly")),
+    not(any(target_os="android", target_os="netbsd", target_os="openbsd")),
+    not(any(target_vendor="apple", target_os="freebsd")),
+))]
+unsafe fn errno() -> libc::c_int { 0 }
+
+#[cfg(all(not(target_os="dos"), not(windows)))]
+impl fmt::Write for FdSink {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut bytes = s.as_bytes();
+        while !bytes.is_empty() {
+            let written = unsafe { libc::write(self.0, bytes.as_ptr() as _, bytes.len()) };
+            if written < 0 && unsafe { errno() } == libc::EINTR { continue; }
+            if written <= 0 { return Err(fmt::Error); }
+            bytes = &bytes[written as usize ..];
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(not(target_os="dos"), not(windows)))]
+impl LogSink for FdSink {
+    fn log(&self, record: &Record) {
+        let _ = fmt::Write::write_fmt(&mut { *self }, format_args!("{record}\n"));
+    }
+}
+
+pub struct CallbackSink<F: Fn(&Record)>(pub F);
+
+impl<F: Fn(&Record)> LogSink for CallbackSink<F> {
+    fn log(&self, record: &Record) {
+        (self.0)(record)
+    }
+}
+
+struct RingBuffer<const N: usize> {
+    records: [MaybeUninit<Record>; N],
+    start: usize,
+    len: usize,
+}
+
+unsafe impl<const N: usize> Send for RingBuffer<N> { }
+
+/// Keeps last `N` records.
+pub struct RingBufferSink<const N: usize> {
+    buf: SpinLock<RingBuffer<N>>,
+}
+
+impl<const N: usize> RingBufferSink<N> {
+    pub const fn new() -> Self {
+        RingBufferSink {
+            buf: SpinLock::new(RingBuffer { records: [MaybeUninit::uninit(); N], start: 0, len: 0 }),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.with(|buf| buf.len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        self.buf.with(|buf| {
+            buf.start = 0;
+            buf.len = 0;
+        });
+    }
+
+    /// Calls `f` for every kept record, from the oldest to the newest.
+    ///
+    /// The sink is locked while `f` runs, so `f` should not allocate
+    /// with an allocator logging to this sink.
+    pub fn for_each(&self, mut f: impl FnMut(&Record)) {
+        self.buf.with(|buf| {
+            for i in 0 .. buf.len {
+                f(unsafe { buf.records[(buf.start + i) % N].assume_init_ref() });
+            }
+        });
+    }
+}
+
+impl<const N: usize> Default for RingBufferSink<N> {
+    fn default() -> Self { Self::new() }
+}
+
+impl<const N: usize> LogSink for RingBufferSink<N> {
+    fn log(&self, record: &Record) {
+        if N == 0 { return; }
+        self.buf.with(|buf| {
+            if buf.len == N {
+                buf.records[buf.start] = MaybeUninit::new(*record);
+                buf.start = (buf.start + 1) % N;
+            } else {
+                buf.records[(buf.start + buf.len) % N] = MaybeUninit::new(*record);
+                buf.len += 1;
+            }
+        });
+    }
+}
+
+/// Logs allocator operations to stderr.
+///
+/// Use [`LoggingTo`] to log to another sink.
 pub struct Logging<A: Allocator>(pub A);
 
+/// Logs allocator operations to the `S` sink.
+pub struct LoggingTo<A: Allocator, S: LogSink>(pub A, pub S);
+
+/// Logging logic shared by [`Logging`] and [`LoggingTo`].
+struct Log<'a, A: Allocator, S: LogSink> {
+    base: &'a A,
+    sink: &'a S,
+}
+
+impl<A: Allocator> Logging<A> {
+    fn log(&self) -> Log<'_, A, StderrSink> {
+        Log { base: &self.0, sink: &StderrSink }
+    }
+}
+
+impl<A: Allocator, S: LogSink> LoggingTo<A, S> {
+    fn log(&self) -> Log<'_, A, S> {
+        Log { base: &self.0, sink: &self.1 }
+    }
+}
+
 unsafe impl<A: NonUnwinding> NonUnwinding for Logging<A> { }
 
+unsafe impl<A: NonUnwinding, S: LogSink> NonUnwinding for LoggingTo<A, S> { }
+
 unsafe impl<A: Fallbackable> Fallbackable for Logging<A> {
     unsafe fn has_allocated(&self, ptr: NonNull<u8>, layout: alloc::Layout) -> bool {
         self.0.has_allocated(ptr, layout)
@@ -17,49 +239,248 @@ unsafe impl<A: Fallbackable> Fallbackable for Logging<A> {
     }
 }
 
+unsafe impl<A: Fallbackable, S: LogSink> Fallbackable for LoggingTo<A, S> {
+    unsafe fn has_allocated(&self, ptr: NonNull<u8>, layout: alloc::Layout) -> bool {
+        self.0.has_allocated(ptr, layout)
+    }
+
+    fn allows_fallback(&self, layout: alloc::Layout) -> bool {
+        self.0.allows_fallback(layout)
+    }
+}
+
+impl<'a, A: Allocator, S: LogSink> Log<'a, A, S> {
+    fn log(
+        &self,
+        operation: Operation,
+        ptr: Option<NonNull<u8>>,
+        old_layout: Option<alloc::Layout>,
+        layout: alloc::Layout,
+        result: Result<NonNull<[u8]>, AllocError>,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.sink.log(&Record { operation, ptr, old_layout, layout, result: Some(result) });
+        result
+    }
+}
+
+unsafe impl<'a, A: Allocator, S: LogSink> Allocator for Log<'a, A, S> {
+    fn allocate(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.log(Operation::Allocate, None, None, layout, self.base.allocate(layout))
+    }
+
+    fn allocate_zeroed(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.log(Operation::AllocateZeroed, None, None, layout, self.base.allocate_zeroed(layout))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: alloc::Layout) {
+        self.base.deallocate(ptr, layout);
+        self.sink.log(&Record { operation: Operation::Deallocate, ptr: Some(ptr), old_layout: None, layout, result: None });
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let result = self.base.grow(ptr, old_layout, new_layout);
+        self.log(Operation::Grow, Some(ptr), Some(old_layout), new_layout, result)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let result = self.base.grow_zeroed(ptr, old_layout, new_layout);
+        self.log(Operation::GrowZeroed, Some(ptr), Some(old_layout), new_layout, result)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let result = self.base.shrink(ptr, old_layout, new_layout);
+        self.log(Operation::Shrink, Some(ptr), Some(old_layout), new_layout, result)
+    }
+}
+
 unsafe impl<A: Allocator> Allocator for Logging<A> {
     fn allocate(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
-        let _ = writeln!(Stderr { panic: false }, "allocate: {layout:?}");
-        self.0.allocate(layout)
+        self.log().allocate(layout)
+    }
+
+    fn allocate_zeroed(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.log().allocate_zeroed(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: alloc::Layout) {
+        self.log().deallocate(ptr, layout)
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.log().grow(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.log().grow_zeroed(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.log().shrink(ptr, old_layout, new_layout)
+    }
+}
+
+unsafe impl<A: Allocator, S: LogSink> Allocator for LoggingTo<A, S> {
+    fn allocate(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.log().allocate(layout)
     }
 
     fn allocate_zeroed(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
-        let _ = writeln!(Stderr { panic: false }, "allocate_zeroed: {layout:?}");
-        self.0.allocate_zeroed(layout)
+        self.log().allocate_zeroed(layout)
     }
 
     unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: alloc::Layout) {
-        let _ = writeln!(Stderr { panic: false }, "deallocate: {layout:?}");
-        self.0.deallocate(ptr, layout)
+        self.log().deallocate(ptr, layout)
     }
 
     unsafe fn grow(
-        &self, 
-        ptr: NonNull<u8>, 
-        old_layout: alloc::Layout, 
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
         new_layout: alloc::Layout
     ) -> Result<NonNull<[u8]>, AllocError> {
-        let _ = writeln!(Stderr { panic: false }, "grow: {old_layout:?} -> {new_layout:?}");
-        self.0.grow(ptr, old_layout, new_layout)
+        self.log().grow(ptr, old_layout, new_layout)
     }
 
     unsafe fn grow_zeroed(
-        &self, 
-        ptr: NonNull<u8>, 
-        old_layout: alloc::Layout, 
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
         new_layout: alloc::Layout
     ) -> Result<NonNull<[u8]>, AllocError> {
-        let _ = writeln!(Stderr { panic: false }, "grow_zeroed: {old_layout:?} -> {new_layout:?}");
-        self.0.grow_zeroed(ptr, old_layout, new_layout)
+        self.log().grow_zeroed(ptr, old_layout, new_layout)
     }
 
     unsafe fn shrink(
-        &self, 
-        ptr: NonNull<u8>, 
-        old_layout: alloc::Layout, 
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
         new_layout: alloc::Layout
     ) -> Result<NonNull<[u8]>, AllocError> {
-        let _ = writeln!(Stderr { panic: false }, "shrink: {old_layout:?} -> {new_layout:?}");
-        self.0.shrink(ptr, old_layout, new_layout)
+        self.log().shrink(ptr, old_layout, new_layout)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use crate::{CallbackSink, Logging, LoggingTo, Operation, Record, RingBufferSink, System};
+    use core::alloc::{self, AllocError, Allocator};
+    use core::ptr::NonNull;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use std::format;
+    use std::vec::Vec;
+
+    fn layout(size: usize) -> alloc::Layout {
+        alloc::Layout::from_size_align(size, 8).unwrap()
+    }
+
+    fn ptr(addr: usize) -> NonNull<u8> {
+        NonNull::new(addr as *mut u8).unwrap()
+    }
+
+    #[test]
+    fn formats_records() {
+        let block = NonNull::slice_from_raw_parts(ptr(0x2000), 32);
+        let allocate = Record { operation: Operation::Allocate, ptr: None, old_layout: None, layout: layout(24), result: Some(Ok(block)) };
+        assert_eq!(format!("{allocate}"), format!("allocate: {:?} = 0x2000, 32 bytes", layout(24)));
+        let failed = Record { result: Some(Err(AllocError)), ..allocate };
+        assert_eq!(format!("{failed}"), format!("allocate: {:?} = failed", layout(24)));
+        let grow = Record {
+            operation: Operation::Grow,
+            ptr: Some(ptr(0x1000)),
+            old_layout: Some(layout(8)),
+            layout: layout(24),
+            result: Some(Ok(block)),
+        };
+        assert_eq!(format!("{grow}"), format!("grow: 0x1000 {:?} -> {:?} = 0x2000, 32 bytes", layout(8), layout(24)));
+        let deallocate = Record { operation: Operation::Deallocate, ptr: Some(ptr(0x2000)), old_layout: None, layout: layout(24), result: None };
+        assert_eq!(format!("{deallocate}"), format!("deallocate: 0x2000 {:?}", layout(24)));
+    }
+
+    #[test]
+    fn ring_buffer_keeps_last_records() {
+        let logging = LoggingTo(System, RingBufferSink::<2>::new());
+        let block = logging.allocate(layout(8)).unwrap();
+        let grown = unsafe { logging.grow(block.as_non_null_ptr(), layout(8), layout(16)) }.unwrap();
+        unsafe { logging.deallocate(grown.as_non_null_ptr(), layout(16)); }
+        assert_eq!(logging.1.len(), 2);
+        let mut records = Vec::new();
+        logging.1.for_each(|record| records.push(*record));
+        assert_eq!(records[0].operation, Operation::Grow);
+        assert_eq!(records[0].ptr, Some(block.as_non_null_ptr()));
+        assert_eq!(records[0].old_layout, Some(layout(8)));
+        assert_eq!(records[0].result.unwrap().unwrap().len(), grown.len());
+        assert_eq!(records[1].operation, Operation::Deallocate);
+        assert!(records[1].result.is_none());
+        logging.1.clear();
+        assert!(logging.1.is_empty());
+    }
+
+    #[test]
+    fn logs_to_callback_and_stderr() {
+        static FAILURES: AtomicUsize = AtomicUsize::new(0);
+        let logging = LoggingTo(System, CallbackSink(|record: &Record| if let Some(Err(_)) = record.result {
+            FAILURES.fetch_add(1, Ordering::Relaxed);
+        }));
+        assert!(logging.allocate(alloc::Layout::from_size_align(isize::MAX as usize - 7, 8).unwrap()).is_err());
+        let block = logging.allocate(layout(8)).unwrap();
+        unsafe { logging.deallocate(block.as_non_null_ptr(), layout(8)); }
+        assert_eq!(FAILURES.load(Ordering::Relaxed), 1);
+        let logging = Logging(System);
+        let block = logging.allocate_zeroed(layout(8)).unwrap();
+        unsafe { logging.deallocate(block.as_non_null_ptr(), layout(8)); }
+    }
+
+    #[cfg(all(not(target_os="dos"), not(windows)))]
+    #[test]
+    fn writes_whole_records_to_fd() {
+        use crate::{FdSink, LogSink};
+
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let record = Record { operation: Operation::Deallocate, ptr: Some(ptr(0x1000)), old_layout: None, layout: layout(8), result: None };
+        FdSink(fds[1]).log(&record);
+        unsafe { libc::close(fds[1]); }
+        let mut text = Vec::new();
+        let mut buf = [0u8; 16];
+        loop {
+            let read = unsafe { libc::read(fds[0], buf.as_mut_ptr() as _, buf.len()) };
+            assert!(read >= 0);
+            if read == 0 { break; }
+            text.extend_from_slice(&buf[.. read as usize]);
+        }
+        unsafe { libc::close(fds[0]); }
+        assert_eq!(text, format!("{record}\n").into_bytes());
     }
 }
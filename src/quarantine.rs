@@ -0,0 +1,260 @@
+use crate::base::*;
+use crate::corruption::*;
+use crate::spin_lock::SpinLock;
+use core::alloc::{self, AllocError, Allocator};
+use core::cmp::min;
+use core::mem::MaybeUninit;
+use core::ptr::{self, NonNull};
+
+pub const POISON_BYTE: u8 = 0xDD;
+
+struct Queue<const N: usize> {
+    blocks: [MaybeUninit<(NonNull<u8>, alloc::Layout)>; N],
+    start: usize,
+    len: usize,
+}
+
+unsafe impl<const N: usize> Send for Queue<N> { }
+
+impl<const N: usize> Queue<N> {
+    fn pop(&mut self) -> Option<(NonNull<u8>, alloc::Layout)> {
+        if self.len == 0 { return None; }
+        let block = unsafe { self.blocks[self.start].assume_init() };
+        self.start = (self.start + 1) % N;
+        self.len -= 1;
+        Some(block)
+    }
+
+    /// Returns the evicted block, if the queue is full.
+    fn push(&mut self, block: (NonNull<u8>, alloc::Layout)) -> Option<(NonNull<u8>, alloc::Layout)> {
+        let evicted = if self.len == N { self.pop() } else { None };
+        self.blocks[(self.start + self.len) % N] = MaybeUninit::new(block);
+        self.len += 1;
+        evicted
+    }
+}
+
+/// Poisons freed blocks and keeps last `N` of them,
+/// really deallocating a block only when it is evicted.
+/// An evicted block poison is checked to detect writes after free.
+///
+/// Growing and shrinking always move a block, so the old one is quarantined too.
+pub struct Quarantine<A: Allocator, const N: usize, H: CorruptionHandler = PanicOnCorruption> {
+    queue: SpinLock<Queue<N>>,
+    base: A,
+    handler: H,
+}
+
+unsafe impl<A: NonUnwinding, const N: usize> NonUnwinding for Quarantine<A, N, AbortOnCorruption> { }
+
+impl<A: Allocator, const N: usize, H: CorruptionHandler> Drop for Quarantine<A, N, H> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+impl<A: Allocator, const N: usize, H: CorruptionHandler> Quarantine<A, N, H> {
+    pub const fn new(base: A, handler: H) -> Self {
+        Quarantine {
+            queue: SpinLock::new(Queue { blocks: [const { MaybeUninit::uninit() }; N], start: 0, len: 0 }),
+            base,
+            handler,
+        }
+    }
+
+    pub fn base(&self) -> &A { &self.base }
+
+    pub fn quarantined_len(&self) -> usize {
+        self.queue.with(|queue| queue.len)
+    }
+
+    /// Checks and deallocates all quarantined blocks.
+    pub fn flush(&self) {
+        while let Some((ptr, layout)) = self.queue.with(|queue| queue.pop()) {
+            unsafe { self.release(ptr, layout); }
+        }
+    }
+
+    unsafe fn release(&self, ptr: NonNull<u8>, layout: alloc::Layout) {
+        if let Some(offset) = (0 .. layout.size()).find(|&i| *ptr.as_ptr().add(i) != POISON_BYTE) {
+            let offset = offset as isize;
+            self.handler.corrupted(&Corruption { kind: CorruptionKind::WriteAfterFree, ptr, layout, offset });
+        }
+        self.base.deallocate(ptr, layout);
+    }
+
+    unsafe fn quarantine(&self, ptr: NonNull<u8>, layout: alloc::Layout) {
+        if N == 0 { return self.base.deallocate(ptr, layout); }
+        ptr.write_bytes(POISON_BYTE, layout.size());
+        if let Some((ptr, layout)) = self.queue.with(|queue| queue.push((ptr, layout))) {
+            self.release(ptr, layout);
+        }
+    }
+
+    unsafe fn move_block(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout,
+        zeroed: bool,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let block = if zeroed { self.base.allocate_zeroed(new_layout)? } else { self.base.allocate(new_layout)? };
+        ptr::copy_nonoverlapping(ptr.as_ptr(), block.as_mut_ptr(), min(old_layout.size(), new_layout.size()));
+        self.quarantine(ptr, old_layout);
+        Ok(block)
+    }
+}
+
+unsafe impl<A: Fallbackable, const N: usize, H: CorruptionHandler> Fallbackable for Quarantine<A, N, H> {
+    unsafe fn has_allocated(&self, ptr: NonNull<u8>, layout: alloc::Layout) -> bool {
+        self.base.has_allocated(ptr, layout)
+    }
+
+    fn allows_fallback(&self, layout: alloc::Layout) -> bool {
+        self.base.allows_fallback(layout)
+    }
+}
+
+unsafe impl<A: Allocator, const N: usize, H: CorruptionHandler> Allocator for Quarantine<A, N, H> {
+    fn allocate(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.base.allocate(layout)
+    }
+
+    fn allocate_zeroed(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.base.allocate_zeroed(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: alloc::Layout) {
+        self.quarantine(ptr, layout);
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.move_block(ptr, old_layout, new_layout, false)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.move_block(ptr, old_layout, new_layout, true)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.move_block(ptr, old_layout, new_layout, false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Stats, System};
+    use crate::corruption::test::{recorded, recording};
+    use crate::quarantine::*;
+    use core::slice;
+
+    fn layout(size: usize) -> alloc::Layout {
+        alloc::Layout::from_size_align(size, 8).unwrap()
+    }
+
+    fn write_after_free(ptr: NonNull<u8>, layout: alloc::Layout, offset: isize) -> Corruption {
+        Corruption { kind: CorruptionKind::WriteAfterFree, ptr, layout, offset }
+    }
+
+    #[test]
+    fn reports_write_after_free_on_eviction() {
+        let quarantine = Quarantine::<_, 2, _>::new(Stats::new(System), recording());
+        let a = quarantine.allocate(layout(16)).unwrap().as_non_null_ptr();
+        let b = quarantine.allocate(layout(32)).unwrap().as_non_null_ptr();
+        let c = quarantine.allocate(layout(8)).unwrap().as_non_null_ptr();
+        unsafe { quarantine.deallocate(a, layout(16)); }
+        unsafe { quarantine.deallocate(b, layout(32)); }
+        assert!(unsafe { slice::from_raw_parts(a.as_ptr(), 16) }.iter().all(|&x| x == POISON_BYTE));
+        assert_eq!(quarantine.quarantined_len(), 2);
+        assert_eq!(quarantine.base().live_blocks(), 3);
+        unsafe { a.add(5).write(0); }
+        unsafe { quarantine.deallocate(c, layout(8)); }
+        assert_eq!(recorded(), [write_after_free(a, layout(16), 5)]);
+        assert_eq!(quarantine.quarantined_len(), 2);
+        assert_eq!(quarantine.base().live_blocks(), 2);
+        quarantine.flush();
+        assert_eq!(recorded(), []);
+        assert_eq!(quarantine.base().live_blocks(), 0);
+    }
+
+    #[test]
+    fn reports_write_after_free_on_flush() {
+        let quarantine = Quarantine::<_, 4, _>::new(Stats::new(System), recording());
+        let a = quarantine.allocate(layout(16)).unwrap().as_non_null_ptr();
+        let b = quarantine.allocate(layout(16)).unwrap().as_non_null_ptr();
+        unsafe { quarantine.deallocate(a, layout(16)); }
+        unsafe { quarantine.deallocate(b, layout(16)); }
+        unsafe { b.add(15).write(0); }
+        quarantine.flush();
+        assert_eq!(recorded(), [write_after_free(b, layout(16), 15)]);
+        assert_eq!(quarantine.quarantined_len(), 0);
+        assert_eq!(quarantine.base().live_blocks(), 0);
+    }
+
+    #[test]
+    fn zero_capacity_deallocates_immediately() {
+        let quarantine = Quarantine::<_, 0, _>::new(Stats::new(System), recording());
+        let ptr = quarantine.allocate(layout(16)).unwrap().as_non_null_ptr();
+        unsafe { quarantine.deallocate(ptr, layout(16)); }
+        assert_eq!(quarantine.quarantined_len(), 0);
+        assert_eq!(quarantine.base().live_blocks(), 0);
+        let ptr = quarantine.allocate(layout(16)).unwrap().as_non_null_ptr();
+        let ptr = unsafe { quarantine.grow(ptr, layout(16), layout(64)) }.unwrap().as_non_null_ptr();
+        assert_eq!(quarantine.base().live_blocks(), 1);
+        unsafe { quarantine.deallocate(ptr, layout(64)); }
+        assert_eq!(quarantine.base().live_blocks(), 0);
+        assert_eq!(recorded(), []);
+    }
+
+    #[test]
+    fn resizing_quarantines_old_block() {
+        let quarantine = Quarantine::<_, 4, _>::new(Stats::new(System), recording());
+        let a = quarantine.allocate(layout(16)).unwrap().as_non_null_ptr();
+        unsafe { a.write_bytes(1, 16); }
+        let b = unsafe { quarantine.grow(a, layout(16), layout(64)) }.unwrap().as_non_null_ptr();
+        assert_ne!(a, b);
+        assert!(unsafe { slice::from_raw_parts(b.as_ptr(), 16) }.iter().all(|&x| x == 1));
+        assert!(unsafe { slice::from_raw_parts(a.as_ptr(), 16) }.iter().all(|&x| x == POISON_BYTE));
+        let c = unsafe { quarantine.grow_zeroed(b, layout(64), layout(128)) }.unwrap().as_non_null_ptr();
+        let bytes = unsafe { slice::from_raw_parts(c.as_ptr(), 128) };
+        assert!(bytes[.. 16].iter().all(|&x| x == 1) && bytes[64 ..].iter().all(|&x| x == 0));
+        let d = unsafe { quarantine.shrink(c, layout(128), layout(8)) }.unwrap().as_non_null_ptr();
+        assert!(unsafe { slice::from_raw_parts(d.as_ptr(), 8) }.iter().all(|&x| x == 1));
+        assert_eq!(quarantine.quarantined_len(), 3);
+        assert_eq!(quarantine.base().live_blocks(), 4);
+        unsafe { quarantine.deallocate(d, layout(8)); }
+        quarantine.flush();
+        assert_eq!(quarantine.base().live_blocks(), 0);
+        assert_eq!(recorded(), []);
+    }
+
+    #[test]
+    fn drop_flushes() {
+        let stats = Stats::new(System);
+        let quarantine = Quarantine::<_, 4, _>::new(&stats, recording());
+        let a = quarantine.allocate(layout(16)).unwrap().as_non_null_ptr();
+        let b = quarantine.allocate(layout(16)).unwrap().as_non_null_ptr();
+        unsafe { quarantine.deallocate(a, layout(16)); }
+        unsafe { quarantine.deallocate(b, layout(16)); }
+        unsafe { a.write(0); }
+        assert_eq!(stats.live_blocks(), 2);
+        drop(quarantine);
+        assert_eq!(stats.live_blocks(), 0);
+        assert_eq!(recorded(), [write_after_free(a, layout(16), 0)]);
+    }
+}
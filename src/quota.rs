@@ -0,0 +1,210 @@
+use crate::base::*;
+use core::alloc::{self, AllocError, Allocator};
+use core::borrow::Borrow;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Live bytes and live allocations limits with current usage.
+///
+/// Bytes are counted from requested layouts sizes.
+/// A budget can be shared by several [`Quota`]s, e.g. as `Quota<A, &Budget>`.
+#[derive(Debug)]
+pub struct Budget {
+    max_bytes: usize,
+    max_allocations: usize,
+    bytes: AtomicUsize,
+    allocations: AtomicUsize,
+}
+
+impl Budget {
+    /// Pass `usize::MAX` to not limit bytes or allocations.
+    pub const fn new(max_bytes: usize, max_allocations: usize) -> Self {
+        Budget { max_bytes, max_allocations, bytes: AtomicUsize::new(0), allocations: AtomicUsize::new(0) }
+    }
+
+    pub fn max_bytes(&self) -> usize { self.max_bytes }
+
+    pub fn max_allocations(&self) -> usize { self.max_allocations }
+
+    pub fn live_bytes(&self) -> usize { self.bytes.load(Ordering::Relaxed) }
+
+    pub fn live_allocations(&self) -> usize { self.allocations.load(Ordering::Relaxed) }
+
+    fn acquire_one(counter: &AtomicUsize, max: usize, n: usize) -> bool {
+        counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| x.checked_add(n).filter(|&x| x <= max)).is_ok()
+    }
+
+    fn acquire(&self, bytes: usize, allocations: usize) -> Result<(), AllocError> {
+        if !Self::acquire_one(&self.bytes, self.max_bytes, bytes) { return Err(AllocError); }
+        if !Self::acquire_one(&self.allocations, self.max_allocations, allocations) {
+            self.bytes.fetch_sub(bytes, Ordering::Relaxed);
+            return Err(AllocError);
+        }
+        Ok(())
+    }
+
+    fn release_one(counter: &AtomicUsize, n: usize) {
+        let prev = counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| Some(x.saturating_sub(n))).unwrap();
+        debug_assert!(prev >= n, "releasing more than acquired");
+    }
+
+    fn release(&self, bytes: usize, allocations: usize) {
+        Self::release_one(&self.bytes, bytes);
+        Self::release_one(&self.allocations, allocations);
+    }
+}
+
+/// Fails to allocate, if the [`Budget`] would be exceeded.
+///
+/// The budget is charged with requested layouts sizes,
+/// and returned blocks are shortened to these sizes,
+/// so that deallocating a block gives back exactly the charged bytes, and not more.
+///
+/// Falls back, if spent, when the base allocator [allows](Fallbackable::allows_fallback) it.
+pub struct Quota<A: Allocator, B: Borrow<Budget> = Budget> {
+    budget: B,
+    base: A,
+}
+
+unsafe impl<A: NonUnwinding, B: Borrow<Budget>> NonUnwinding for Quota<A, B> { }
+
+impl<A: Allocator> Quota<A, Budget> {
+    /// Pass `usize::MAX` to not limit bytes or allocations.
+    pub const fn new(max_bytes: usize, max_allocations: usize, base: A) -> Self {
+        Quota { budget: Budget::new(max_bytes, max_allocations), base }
+    }
+}
+
+impl<A: Allocator, B: Borrow<Budget>> Quota<A, B> {
+    pub const fn with_budget(budget: B, base: A) -> Self {
+        Quota { budget, base }
+    }
+
+    pub fn budget(&self) -> &Budget { self.budget.borrow() }
+
+    pub fn base(&self) -> &A { &self.base }
+
+    fn allocate_with(
+        &self,
+        layout: alloc::Layout,
+        allocate: impl FnOnce(&A) -> Result<NonNull<[u8]>, AllocError>
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.budget().acquire(layout.size(), 1)?;
+        let res = allocate(&self.base);
+        if res.is_err() {
+            self.budget().release(layout.size(), 1);
+        }
+        res.map(|block| clamp(block, layout.size()))
+    }
+
+    fn grow_with(
+        &self,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout,
+        grow: impl FnOnce(&A) -> Result<NonNull<[u8]>, AllocError>
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let delta = new_layout.size() - old_layout.size();
+        self.budget().acquire(delta, 0)?;
+        let res = grow(&self.base);
+        if res.is_err() {
+            self.budget().release(delta, 0);
+        }
+        res.map(|block| clamp(block, new_layout.size()))
+    }
+}
+
+unsafe impl<A: Fallbackable, B: Borrow<Budget>> Fallbackable for Quota<A, B> {
+    unsafe fn has_allocated(&self, ptr: NonNull<u8>, layout: alloc::Layout) -> bool {
+        self.base.has_allocated(ptr, layout)
+    }
+
+    fn allows_fallback(&self, layout: alloc::Layout) -> bool {
+        self.base.allows_fallback(layout)
+    }
+}
+
+unsafe impl<A: Allocator, B: Borrow<Budget>> Allocator for Quota<A, B> {
+    fn allocate(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.allocate_with(layout, |base| base.allocate(layout))
+    }
+
+    fn allocate_zeroed(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.allocate_with(layout, |base| base.allocate_zeroed(layout))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: alloc::Layout) {
+        self.base.deallocate(ptr, layout);
+        self.budget().release(layout.size(), 1);
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.grow_with(old_layout, new_layout, |base| base.grow(ptr, old_layout, new_layout))
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.grow_with(old_layout, new_layout, |base| base.grow_zeroed(ptr, old_layout, new_layout))
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let block = self.base.shrink(ptr, old_layout, new_layout)?;
+        self.budget().release(old_layout.size() - new_layout.size(), 0);
+        Ok(clamp(block, new_layout.size()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::System;
+    use crate::quota::Quota;
+    use crate::slab::Slab;
+    use core::alloc::{self, Allocator};
+
+    #[test]
+    fn blocks_fit_only_counted_layouts() {
+        let slab = Slab::new(alloc::Layout::from_size_align(64, 8).unwrap(), 4096, System);
+        let quota = Quota::new(100, usize::MAX, &slab);
+        let layout = alloc::Layout::from_size_align(40, 8).unwrap();
+        let block = quota.allocate(layout).unwrap();
+        assert_eq!(block.len(), 40);
+        assert_eq!(quota.budget().live_bytes(), 40);
+        unsafe { quota.deallocate(block.as_non_null_ptr(), layout); }
+        assert_eq!(quota.budget().live_bytes(), 0);
+        assert_eq!(quota.budget().live_allocations(), 0);
+    }
+
+    #[test]
+    fn grow_and_shrink_are_accounted() {
+        let quota = Quota::new(100, 2, System);
+        let small = alloc::Layout::from_size_align(16, 8).unwrap();
+        let large = alloc::Layout::from_size_align(80, 8).unwrap();
+        let too_large = alloc::Layout::from_size_align(120, 8).unwrap();
+        let block = quota.allocate(small).unwrap();
+        let block = unsafe { quota.grow(block.as_non_null_ptr(), small, large) }.unwrap();
+        assert_eq!(block.len(), 80);
+        assert_eq!(quota.budget().live_bytes(), 80);
+        assert!(unsafe { quota.grow(block.as_non_null_ptr(), large, too_large) }.is_err());
+        assert_eq!(quota.budget().live_bytes(), 80);
+        assert!(quota.allocate(alloc::Layout::from_size_align(24, 8).unwrap()).is_err());
+        let block = unsafe { quota.shrink(block.as_non_null_ptr(), large, small) }.unwrap();
+        assert_eq!(block.len(), 16);
+        assert_eq!(quota.budget().live_bytes(), 16);
+        unsafe { quota.deallocate(block.as_non_null_ptr(), small); }
+        assert_eq!(quota.budget().live_bytes(), 0);
+        assert_eq!(quota.budget().live_allocations(), 0);
+    }
+}
@@ -0,0 +1,197 @@
+use crate::base::*;
+use core::alloc::{self, AllocError, Allocator};
+use core::cmp::min;
+use core::ptr::{self, NonNull};
+
+pub struct Segregator<Small: Allocator, Large: Allocator> {
+    threshold: alloc::Layout,
+    small: Small,
+    large: Large,
+}
+
+unsafe impl<Small: NonUnwinding, Large: NonUnwinding> NonUnwinding for Segregator<Small, Large> { }
+
+impl<Small: Allocator, Large: Allocator> Segregator<Small, Large> {
+    pub const fn new(threshold: alloc::Layout, small: Small, large: Large) -> Self {
+        Segregator { threshold, small, large }
+    }
+
+    fn is_small(&self, layout: alloc::Layout) -> bool {
+        layout.size() <= self.threshold.size() &&
+        layout.align() <= self.threshold.align()
+    }
+
+    fn clamp_small(&self, block: NonNull<[u8]>) -> NonNull<[u8]> {
+        clamp(block, self.threshold.size())
+    }
+
+    unsafe fn move_block(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout,
+        zeroed: bool,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let block = match (self.is_small(new_layout), zeroed) {
+            (true, false) => self.clamp_small(self.small.allocate(new_layout)?),
+            (true, true) => self.clamp_small(self.small.allocate_zeroed(new_layout)?),
+            (false, false) => self.large.allocate(new_layout)?,
+            (false, true) => self.large.allocate_zeroed(new_layout)?,
+        };
+        ptr::copy_nonoverlapping(ptr.as_ptr(), block.as_mut_ptr(), min(old_layout.size(), new_layout.size()));
+        if self.is_small(old_layout) {
+            self.small.deallocate(ptr, old_layout);
+        } else {
+            self.large.deallocate(ptr, old_layout);
+        }
+        Ok(block)
+    }
+}
+
+unsafe impl<Small: Fallbackable, Large: Fallbackable> Fallbackable for Segregator<Small, Large> {
+    unsafe fn has_allocated(&self, ptr: NonNull<u8>, layout: alloc::Layout) -> bool {
+        if self.is_small(layout) {
+            self.small.has_allocated(ptr, layout)
+        } else {
+            self.large.has_allocated(ptr, layout)
+        }
+    }
+
+    fn allows_fallback(&self, layout: alloc::Layout) -> bool {
+        if self.is_small(layout) {
+            self.small.allows_fallback(layout)
+        } else {
+            self.large.allows_fallback(layout)
+        }
+    }
+}
+
+unsafe impl<Small: Allocator, Large: Allocator> Allocator for Segregator<Small, Large> {
+    fn allocate(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if self.is_small(layout) {
+            Ok(self.clamp_small(self.small.allocate(layout)?))
+        } else {
+            self.large.allocate(layout)
+        }
+    }
+
+    fn allocate_zeroed(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if self.is_small(layout) {
+            Ok(self.clamp_small(self.small.allocate_zeroed(layout)?))
+        } else {
+            self.large.allocate_zeroed(layout)
+        }
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: alloc::Layout) {
+        if self.is_small(layout) {
+            self.small.deallocate(ptr, layout);
+        } else {
+            self.large.deallocate(ptr, layout);
+        }
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        match (self.is_small(old_layout), self.is_small(new_layout)) {
+            (true, true) => Ok(self.clamp_small(self.small.grow(ptr, old_layout, new_layout)?)),
+            (false, false) => self.large.grow(ptr, old_layout, new_layout),
+            _ => self.move_block(ptr, old_layout, new_layout, false),
+        }
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        match (self.is_small(old_layout), self.is_small(new_layout)) {
+            (true, true) => Ok(self.clamp_small(self.small.grow_zeroed(ptr, old_layout, new_layout)?)),
+            (false, false) => self.large.grow_zeroed(ptr, old_layout, new_layout),
+            _ => self.move_block(ptr, old_layout, new_layout, true),
+        }
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        match (self.is_small(old_layout), self.is_small(new_layout)) {
+            (true, true) => Ok(self.clamp_small(self.small.shrink(ptr, old_layout, new_layout)?)),
+            (false, false) => self.large.shrink(ptr, old_layout, new_layout),
+            _ => self.move_block(ptr, old_layout, new_layout, false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Stats, System};
+    use crate::segregator::Segregator;
+    use crate::slab::Slab;
+    use core::alloc::{self, Allocator};
+
+    fn layout(size: usize, align: usize) -> alloc::Layout {
+        alloc::Layout::from_size_align(size, align).unwrap()
+    }
+
+    #[test]
+    fn routes_by_threshold() {
+        let segregator = Segregator::new(layout(64, 8), Stats::new(System), Stats::new(System));
+        for (size, align, small) in [(64, 8, true), (64, 1, true), (65, 8, false), (8, 16, false), (1, 1, true)] {
+            let before = (segregator.small.live_blocks(), segregator.large.live_blocks());
+            let block = segregator.allocate(layout(size, align)).unwrap();
+            let after = (segregator.small.live_blocks(), segregator.large.live_blocks());
+            let expected = if small { (before.0 + 1, before.1) } else { (before.0, before.1 + 1) };
+            assert_eq!(after, expected, "size {size}, align {align}");
+            unsafe { segregator.deallocate(block.as_non_null_ptr(), layout(size, align)); }
+        }
+        assert_eq!(segregator.small.live_blocks(), 0);
+        assert_eq!(segregator.large.live_blocks(), 0);
+    }
+
+    #[test]
+    fn clamps_small_blocks_to_threshold() {
+        let segregator = Segregator::new(layout(64, 8), Slab::new(layout(128, 8), 4096, System), System);
+        let block = segregator.allocate(layout(16, 8)).unwrap();
+        assert_eq!(block.len(), 64);
+        let block = unsafe { segregator.grow(block.as_non_null_ptr(), layout(16, 8), layout(32, 8)) }.unwrap();
+        assert_eq!(block.len(), 64);
+        let block = unsafe { segregator.shrink(block.as_non_null_ptr(), layout(32, 8), layout(8, 8)) }.unwrap();
+        assert_eq!(block.len(), 64);
+        unsafe { segregator.deallocate(block.as_non_null_ptr(), layout(8, 8)); }
+        let block = segregator.allocate_zeroed(layout(64, 8)).unwrap();
+        assert_eq!(block.len(), 64);
+        unsafe { segregator.deallocate(block.as_non_null_ptr(), layout(64, 8)); }
+    }
+
+    #[test]
+    fn moves_blocks_across_threshold() {
+        let segregator = Segregator::new(layout(64, 8), Stats::new(System), Stats::new(System));
+        let block = segregator.allocate(layout(32, 8)).unwrap();
+        unsafe { block.as_mut_ptr().write_bytes(0xA5, 32); }
+        let block = unsafe { segregator.grow_zeroed(block.as_non_null_ptr(), layout(32, 8), layout(128, 8)) }.unwrap();
+        assert_eq!((segregator.small.live_blocks(), segregator.large.live_blocks()), (0, 1));
+        let bytes = unsafe { block.as_ref() };
+        assert!(bytes[.. 32].iter().all(|&x| x == 0xA5));
+        assert!(bytes[32 .. 128].iter().all(|&x| x == 0));
+        let block = unsafe { segregator.shrink(block.as_non_null_ptr(), layout(128, 8), layout(16, 8)) }.unwrap();
+        assert_eq!((segregator.small.live_blocks(), segregator.large.live_blocks()), (1, 0));
+        assert!(unsafe { block.as_ref() }[.. 16].iter().all(|&x| x == 0xA5));
+        let block = unsafe { segregator.grow(block.as_non_null_ptr(), layout(16, 8), layout(48, 8)) }.unwrap();
+        assert_eq!((segregator.small.live_blocks(), segregator.large.live_blocks()), (1, 0));
+        assert_eq!(segregator.small.snapshot().grows, 1);
+        let block = unsafe { segregator.grow(block.as_non_null_ptr(), layout(48, 8), layout(48, 64)) }.unwrap();
+        assert_eq!((segregator.small.live_blocks(), segregator.large.live_blocks()), (0, 1));
+        assert!(unsafe { block.as_ref() }[.. 16].iter().all(|&x| x == 0xA5));
+        unsafe { segregator.deallocate(block.as_non_null_ptr(), layout(48, 64)); }
+        assert_eq!(segregator.large.live_blocks(), 0);
+    }
+}
@@ -0,0 +1,409 @@
+use crate::base::*;
+use crate::spin_lock::SpinLock;
+use core::alloc::{self, AllocError, Allocator};
+use core::mem::{align_of, size_of};
+use core::ptr::{NonNull, null_mut};
+
+/// Slab header, placed at a slab start.
+struct SlabHeader {
+    next: *mut SlabHeader,
+    prev: *mut SlabHeader,
+    /// Freed objects list, linked through objects first words.
+    free: *mut u8,
+    /// The number of objects carved from the slab.
+    carved: usize,
+    /// The number of allocated objects.
+    used: usize,
+}
+
+struct Lists {
+    /// Slabs with free objects.
+    partial: *mut SlabHeader,
+    /// Slabs without free objects.
+    full: *mut SlabHeader,
+    /// The kept empty slab.
+    spare: *mut SlabHeader,
+    slabs: usize,
+}
+
+unsafe impl Send for Lists { }
+
+impl Lists {
+    unsafe fn push(head: &mut *mut SlabHeader, slab: *mut SlabHeader) {
+        (*slab).next = *head;
+        (*slab).prev = null_mut();
+        if !head.is_null() {
+            (**head).prev = slab;
+        }
+        *head = slab;
+    }
+
+    unsafe fn remove(head: &mut *mut SlabHeader, slab: *mut SlabHeader) {
+        let next = (*slab).next;
+        let prev = (*slab).prev;
+        if prev.is_null() {
+            *head = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+    }
+}
+
+/// Carves `slab_size`-bytes slabs requested from the base allocator into `layout` objects.
+///
+/// Objects are allocated from partially full slabs first.
+/// One slab with all objects freed is kept for reuse,
+/// and other ones are returned to the base allocator as soon as they are empty.
+///
+/// Slabs are aligned to their size, so an object slab is found in constant time.
+/// Layouts not fitting into `layout` are passed to the base allocator.
+pub struct Slab<A: Allocator> {
+    lists: SpinLock<Lists>,
+    layout: alloc::Layout,
+    slab_layout: alloc::Layout,
+    offset: usize,
+    stride: usize,
+    capacity: usize,
+    base: A,
+}
+
+impl<A: Allocator> Drop for Slab<A> {
+    fn drop(&mut self) {
+        self.release_spare();
+    }
+}
+
+unsafe impl<A: NonUnwinding> NonUnwinding for Slab<A> { }
+
+impl<A: Allocator> Slab<A> {
+    /// Creates a slab allocator.
+    ///
+    /// The `slab_size` should be a power of two, big enough for the slab header and one object.
+    pub const fn new(layout: alloc::Layout, slab_size: usize, base: A) -> Self {
+        assert!(slab_size.is_power_of_two());
+        let align = if layout.align() > align_of::<*mut u8>() { layout.align() } else { align_of::<*mut u8>() };
+        let size = if layout.size() > size_of::<*mut u8>() { layout.size() } else { size_of::<*mut u8>() };
+        let stride = size.next_multiple_of(align);
+        let offset = size_of::<SlabHeader>().next_multiple_of(align);
+        assert!(offset <= slab_size && stride <= slab_size - offset, "too small slab size");
+        let slab_layout = match alloc::Layout::from_size_align(slab_size, slab_size) {
+            Ok(slab_layout) => slab_layout,
+            Err(_) => panic!("invalid slab size"),
+        };
+        Slab {
+            lists: SpinLock::new(Lists { partial: null_mut(), full: null_mut(), spare: null_mut(), slabs: 0 }),
+            layout,
+            slab_layout,
+            offset,
+            stride,
+            capacity: (slab_size - offset) / stride,
+            base,
+        }
+    }
+
+    pub fn layout(&self) -> alloc::Layout { self.layout }
+
+    pub fn slab_layout(&self) -> alloc::Layout { self.slab_layout }
+
+    /// Returns the number of objects in one slab.
+    pub fn capacity(&self) -> usize { self.capacity }
+
+    pub fn base(&self) -> &A { &self.base }
+
+    /// Returns the number of slabs requested from the base allocator and not returned yet,
+    /// including the kept empty slab.
+    pub fn slabs(&self) -> usize {
+        self.lists.with(|lists| lists.slabs)
+    }
+
+    /// Returns the kept empty slab, if any, to the base allocator.
+    pub fn release_spare(&self) {
+        let spare = self.lists.with(|lists| {
+            let spare = lists.spare;
+            if !spare.is_null() {
+                lists.spare = null_mut();
+                lists.slabs -= 1;
+            }
+            spare
+        });
+        if spare.is_null() { return; }
+        unsafe { self.base.deallocate(NonNull::new_unchecked(spare as *mut u8), self.slab_layout); }
+    }
+
+    fn manages(&self, layout: alloc::Layout) -> bool {
+        layout.size() <= self.layout.size() && layout.align() <= self.layout.align()
+    }
+
+    fn slab(&self, ptr: NonNull<u8>) -> *mut SlabHeader {
+        ptr.as_ptr().map_addr(|x| x & !(self.slab_layout.size() - 1)) as *mut SlabHeader
+    }
+
+    /// Takes an object from the first partial slab, reusing the kept empty slab if there are no partial ones.
+    unsafe fn take_object(&self, lists: &mut Lists) -> Option<NonNull<u8>> {
+        if lists.partial.is_null() && !lists.spare.is_null() {
+            Lists::push(&mut lists.partial, lists.spare);
+            lists.spare = null_mut();
+        }
+        let slab = lists.partial;
+        if slab.is_null() { return None; }
+        let object = if !(*slab).free.is_null() {
+            let object = (*slab).free;
+            (*slab).free = (object as *mut *mut u8).read();
+            object
+        } else {
+            let object = (slab as *mut u8).add(self.offset + (*slab).carved * self.stride);
+            (*slab).carved += 1;
+            object
+        };
+        (*slab).used += 1;
+        if (*slab).used == self.capacity {
+            Lists::remove(&mut lists.partial, slab);
+            Lists::push(&mut lists.full, slab);
+        }
+        Some(NonNull::new_unchecked(object))
+    }
+
+    fn allocate_object(&self) -> Result<NonNull<u8>, AllocError> {
+        if let Some(object) = self.lists.with(|lists| unsafe { self.take_object(lists) }) {
+            return Ok(object);
+        }
+        // The base allocator is called without holding the lock, as in `deallocate_object`.
+        let slab = self.base.allocate(self.slab_layout)?.as_mut_ptr() as *mut SlabHeader;
+        unsafe { slab.write(SlabHeader { next: null_mut(), prev: null_mut(), free: null_mut(), carved: 0, used: 0 }); }
+        // Another thread could have linked a partial slab meanwhile. The new slab is linked anyway,
+        // and, being pushed first, it gives the object.
+        Ok(self.lists.with(|lists| unsafe {
+            Lists::push(&mut lists.partial, slab);
+            lists.slabs += 1;
+            self.take_object(lists).unwrap_unchecked()
+        }))
+    }
+
+    unsafe fn deallocate_object(&self, ptr: NonNull<u8>) {
+        let slab = self.slab(ptr);
+        let empty = self.lists.with(|lists| {
+            if (*slab).used == self.capacity {
+                Lists::remove(&mut lists.full, slab);
+                Lists::push(&mut lists.partial, slab);
+            }
+            (ptr.as_ptr() as *mut *mut u8).write((*slab).free);
+            (*slab).free = ptr.as_ptr();
+            (*slab).used -= 1;
+            if (*slab).used != 0 { return false; }
+            Lists::remove(&mut lists.partial, slab);
+            if lists.spare.is_null() {
+                lists.spare = slab;
+                return false;
+            }
+            lists.slabs -= 1;
+            true
+        });
+        if empty {
+            self.base.deallocate(NonNull::new_unchecked(slab as *mut u8), self.slab_layout);
+        }
+    }
+}
+
+unsafe impl<A: Fallbackable> Fallbackable for Slab<A> {
+    unsafe fn has_allocated(&self, ptr: NonNull<u8>, layout: alloc::Layout) -> bool {
+        if self.manages(layout) {
+            self.base.has_allocated(NonNull::new_unchecked(self.slab(ptr) as *mut u8), self.slab_layout)
+        } else {
+            self.base.has_allocated(ptr, layout)
+        }
+    }
+
+    fn allows_fallback(&self, layout: alloc::Layout) -> bool {
+        let layout = if self.manages(layout) { self.slab_layout } else { layout };
+        self.base.allows_fallback(layout)
+    }
+}
+
+unsafe impl<A: Allocator> Allocator for Slab<A> {
+    fn allocate(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if !self.manages(layout) {
+            return self.base.allocate(layout);
+        }
+        Ok(NonNull::slice_from_raw_parts(self.allocate_object()?, self.layout.size()))
+    }
+
+    fn allocate_zeroed(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if !self.manages(layout) {
+            return self.base.allocate_zeroed(layout);
+        }
+        let ptr = self.allocate_object()?;
+        unsafe { ptr.as_ptr().write_bytes(0, self.layout.size()); }
+        Ok(NonNull::slice_from_raw_parts(ptr, self.layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: alloc::Layout) {
+        if !self.manages(layout) {
+            return self.base.deallocate(ptr, layout);
+        }
+        self.deallocate_object(ptr);
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        match (self.manages(old_layout), self.manages(new_layout)) {
+            (false, false) => self.base.grow(ptr, old_layout, new_layout),
+            (true, true) => Ok(NonNull::slice_from_raw_parts(ptr, self.layout.size())),
+            _ => move_block(self, ptr, old_layout, new_layout, false),
+        }
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        match (self.manages(old_layout), self.manages(new_layout)) {
+            (false, false) => self.base.grow_zeroed(ptr, old_layout, new_layout),
+            (true, true) => {
+                ptr.add(old_layout.size()).write_bytes(0, self.layout.size() - old_layout.size());
+                Ok(NonNull::slice_from_raw_parts(ptr, self.layout.size()))
+            },
+            _ => move_block(self, ptr, old_layout, new_layout, true),
+        }
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        match (self.manages(old_layout), self.manages(new_layout)) {
+            (false, false) => self.base.shrink(ptr, old_layout, new_layout),
+            (true, true) => Ok(NonNull::slice_from_raw_parts(ptr, self.layout.size())),
+            _ => move_block(self, ptr, old_layout, new_layout, false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use crate::{Stats, System};
+    use crate::slab::Slab;
+    use core::alloc::{self, AllocError, Allocator};
+    use core::cell::Cell;
+    use core::ptr::{NonNull, null};
+    use std::thread_local;
+
+    const SLAB_SIZE: usize = 256;
+
+    fn layout() -> alloc::Layout {
+        alloc::Layout::from_size_align(64, 8).unwrap()
+    }
+
+    fn slab_of(ptr: NonNull<u8>) -> usize {
+        ptr.as_ptr() as usize & !(SLAB_SIZE - 1)
+    }
+
+    #[test]
+    fn prefers_partial_slabs_and_moves_full_ones() {
+        let stats = Stats::new(System);
+        let slab = Slab::new(layout(), SLAB_SIZE, &stats);
+        assert_eq!(slab.capacity(), 3);
+        let first = [(); 3].map(|_| slab.allocate(layout()).unwrap().as_non_null_ptr());
+        assert!(first.iter().all(|&x| slab_of(x) == slab_of(first[0])));
+        assert_eq!(slab.slabs(), 1);
+        let second = slab.allocate(layout()).unwrap().as_non_null_ptr();
+        assert_ne!(slab_of(second), slab_of(first[0]));
+        assert_eq!(slab.slabs(), 2);
+        unsafe { slab.deallocate(first[1], layout()); }
+        let reused = slab.allocate(layout()).unwrap().as_non_null_ptr();
+        assert_eq!(reused, first[1]);
+        let next = slab.allocate(layout()).unwrap().as_non_null_ptr();
+        assert_eq!(slab_of(next), slab_of(second));
+        assert_eq!(slab.slabs(), 2);
+        assert_eq!(stats.live_blocks(), 2);
+        unsafe {
+            for ptr in [first[0], reused, first[2], second, next] {
+                slab.deallocate(ptr, layout());
+            }
+        }
+        slab.release_spare();
+        assert_eq!(slab.slabs(), 0);
+        assert_eq!(stats.live_blocks(), 0);
+    }
+
+    #[test]
+    fn keeps_one_empty_slab() {
+        let stats = Stats::new(System);
+        {
+            let slab = Slab::new(layout(), SLAB_SIZE, &stats);
+            let objects = [(); 6].map(|_| slab.allocate(layout()).unwrap().as_non_null_ptr());
+            assert_eq!(slab.slabs(), 2);
+            for ptr in objects {
+                unsafe { slab.deallocate(ptr, layout()); }
+            }
+            assert_eq!(slab.slabs(), 1);
+            assert_eq!(stats.live_blocks(), 1);
+            let object = slab.allocate(layout()).unwrap().as_non_null_ptr();
+            assert_eq!(slab.slabs(), 1);
+            assert_eq!(stats.snapshot().allocations, 2);
+            unsafe { slab.deallocate(object, layout()); }
+            slab.release_spare();
+            assert_eq!(slab.slabs(), 0);
+            assert_eq!(stats.live_blocks(), 0);
+            let object = slab.allocate(layout()).unwrap().as_non_null_ptr();
+            unsafe { slab.deallocate(object, layout()); }
+            assert_eq!(stats.live_blocks(), 1);
+        }
+        assert_eq!(stats.live_blocks(), 0);
+    }
+
+    thread_local! {
+        static REENTERED: Cell<*const Slab<Reentrant>> = const { Cell::new(null()) };
+    }
+
+    /// Locks the registered slab allocator lists on every call,
+    /// so it spins forever if the slab allocator calls it under its lock.
+    struct Reentrant;
+
+    impl Reentrant {
+        fn reenter(&self) {
+            let slab = REENTERED.get();
+            if !slab.is_null() {
+                unsafe { (*slab).slabs(); }
+            }
+        }
+    }
+
+    unsafe impl Allocator for Reentrant {
+        fn allocate(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+            self.reenter();
+            System.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: alloc::Layout) {
+            self.reenter();
+            System.deallocate(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn calls_base_without_lock() {
+        let slab = Slab::new(layout(), SLAB_SIZE, Reentrant);
+        REENTERED.set(&raw const slab);
+        let objects = [(); 7].map(|_| slab.allocate(layout()).unwrap().as_non_null_ptr());
+        assert_eq!(slab.slabs(), 3);
+        for ptr in objects {
+            unsafe { slab.deallocate(ptr, layout()); }
+        }
+        slab.release_spare();
+        assert_eq!(slab.slabs(), 0);
+        REENTERED.set(null());
+    }
+}
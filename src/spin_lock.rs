@@ -0,0 +1,32 @@
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+pub(crate) struct SpinLock<T> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> { }
+
+impl<T> SpinLock<T> {
+    pub(crate) const fn new(data: T) -> Self {
+        SpinLock { locked: AtomicBool::new(false), data: UnsafeCell::new(data) }
+    }
+
+    pub(crate) fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        while self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            spin_loop();
+        }
+        let _guard = Unlock(&self.locked);
+        f(unsafe { &mut *self.data.get() })
+    }
+}
+
+struct Unlock<'a>(&'a AtomicBool);
+
+impl<'a> Drop for Unlock<'a> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
@@ -1,45 +1,314 @@
+use crate::abort::abort_with;
 use crate::base::*;
+use crate::buffer::{self, Buf};
+use crate::spin_lock::SpinLock;
 use core::alloc::{self, AllocError, Allocator};
-use core::mem::{MaybeUninit};
+use core::cell::Cell;
+use core::fmt::{self, Display, Formatter};
+use core::mem::{MaybeUninit, forget};
+use core::ops::Deref;
 use core::ptr::NonNull;
-use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 pub struct Stacked {
-    buf_ptr: AtomicPtr<u8>,
-    buf_len: usize,
-    allocated: AtomicUsize,
-    allocations_count: AtomicUsize,
+    core: Core<AtomicUsize, SpinLock<LeakPolicy>>,
+}
+
+/// Outstanding allocations.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Leaks {
+    pub allocations: usize,
+    /// Bytes between the buffer start and the top allocated block end.
+    pub bytes: usize,
+}
+
+impl Display for Leaks {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "memory leaks: {} allocations, {} bytes", self.allocations, self.bytes)
+    }
+}
+
+/// Defines what [`Stacked`] or [`LocalStacked`](crate::local_stacked::LocalStacked) does on drop,
+/// if there are outstanding allocations.
+#[derive(Debug, Copy, Clone)]
+pub enum LeakPolicy {
+    /// Panic, unless the allocator is dropped, because the [`with_buf`] or [`with_size`] closure unwinds.
+    ///
+    /// The crate cannot detect unwinding in other cases,
+    /// so dropping a leaking allocator while panicking aborts the process.
+    Panic,
+    /// Abort the process, regardless of the panic strategy.
+    Abort,
+    Ignore,
+    Handler(fn(Leaks)),
+}
+
+/// The allocated bytes or allocations counter, either atomic or not.
+pub(crate) trait Counter {
+    fn get(&self) -> usize;
+    fn set(&self, value: usize);
+    fn compare_exchange(&self, current: usize, new: usize) -> Result<usize, usize>;
+    fn fetch_update(&self, f: impl FnMut(usize) -> Option<usize>) -> Result<usize, usize>;
+}
+
+impl Counter for AtomicUsize {
+    fn get(&self) -> usize { self.load(Ordering::Relaxed) }
+
+    fn set(&self, value: usize) { self.store(value, Ordering::Relaxed); }
+
+    fn compare_exchange(&self, current: usize, new: usize) -> Result<usize, usize> {
+        AtomicUsize::compare_exchange(self, current, new, Ordering::Relaxed, Ordering::Relaxed)
+    }
+
+    fn fetch_update(&self, f: impl FnMut(usize) -> Option<usize>) -> Result<usize, usize> {
+        AtomicUsize::fetch_update(self, Ordering::Relaxed, Ordering::Relaxed, f)
+    }
+}
+
+impl Counter for Cell<usize> {
+    fn get(&self) -> usize { Cell::get(self) }
+
+    fn set(&self, value: usize) { Cell::set(self, value); }
+
+    fn compare_exchange(&self, current: usize, new: usize) -> Result<usize, usize> {
+        let value = Cell::get(self);
+        if value != current { return Err(value); }
+        Cell::set(self, new);
+        Ok(value)
+    }
+
+    fn fetch_update(&self, mut f: impl FnMut(usize) -> Option<usize>) -> Result<usize, usize> {
+        let value = Cell::get(self);
+        Cell::set(self, f(value).ok_or(value)?);
+        Ok(value)
+    }
+}
+
+/// The leak policy cell, either locked or not.
+pub(crate) trait PolicyCell {
+    fn get(&self) -> LeakPolicy;
+    fn set(&self, policy: LeakPolicy);
+}
+
+impl PolicyCell for SpinLock<LeakPolicy> {
+    fn get(&self) -> LeakPolicy { self.with(|x| *x) }
+
+    fn set(&self, policy: LeakPolicy) { self.with(|x| *x = policy); }
+}
+
+impl PolicyCell for Cell<LeakPolicy> {
+    fn get(&self) -> LeakPolicy { Cell::get(self) }
+
+    fn set(&self, policy: LeakPolicy) { Cell::set(self, policy); }
+}
+
+/// Bump allocator logic shared by [`Stacked`] and [`LocalStacked`](crate::local_stacked::LocalStacked),
+/// which differ only in the counters and the leak policy cell types.
+pub(crate) struct Core<C: Counter, P: PolicyCell> {
+    buf: Buf,
+    allocated: C,
+    allocations_count: C,
+    leak_policy: P,
+}
+
+/// Relaxes [`LeakPolicy::Panic`] to [`LeakPolicy::Ignore`], if dropped, i.e. if the allocator user unwinds.
+struct Unwinding<'a, C: Counter, P: PolicyCell>(&'a Core<C, P>);
+
+impl<'a, C: Counter, P: PolicyCell> Drop for Unwinding<'a, C, P> {
+    fn drop(&mut self) {
+        if let LeakPolicy::Panic = self.0.leak_policy() {
+            self.0.set_leak_policy(LeakPolicy::Ignore);
+        }
+    }
+}
+
+impl<C: Counter, P: PolicyCell> Core<C, P> {
+    pub(crate) const fn new(buf: Buf, allocated: C, allocations_count: C, leak_policy: P) -> Self {
+        Core { buf, allocated, allocations_count, leak_policy }
+    }
+
+    /// Calls `f`, relaxing the [`LeakPolicy::Panic`] policy, if `f` unwinds.
+    pub(crate) fn run<T>(&self, f: impl FnOnce() -> T) -> T {
+        let unwinding = Unwinding(self);
+        let res = f();
+        forget(unwinding);
+        res
+    }
+
+    /// Applies the leak policy, naming the allocator as `allocator` in the panic message.
+    pub(crate) fn finish(&self, allocator: &str) {
+        let Err(leaks) = self.leaks() else { return; };
+        match self.leak_policy() {
+            LeakPolicy::Panic => panic!("memory leaks in {allocator} allocator"),
+            LeakPolicy::Abort => abort_with(leaks),
+            LeakPolicy::Ignore => { },
+            LeakPolicy::Handler(handler) => handler(leaks),
+        }
+    }
+
+    pub(crate) fn leak_policy(&self) -> LeakPolicy {
+        self.leak_policy.get()
+    }
+
+    pub(crate) fn set_leak_policy(&self, policy: LeakPolicy) {
+        self.leak_policy.set(policy);
+    }
+
+    pub(crate) fn leaks(&self) -> Result<(), Leaks> {
+        let allocations = self.allocations_count.get();
+        if allocations == 0 { return Ok(()); }
+        Err(Leaks { allocations, bytes: self.allocated.get() })
+    }
+
+    /// Returns outstanding allocations, if any, and disarms the leak policy, if there are some.
+    pub(crate) fn try_finish(&self) -> Result<(), Leaks> {
+        let res = self.leaks();
+        if res.is_err() {
+            self.set_leak_policy(LeakPolicy::Ignore);
+        }
+        res
+    }
+
+    pub(crate) fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            allocated: self.allocated.get(),
+            allocations_count: self.allocations_count.get(),
+        }
+    }
+
+    pub(crate) unsafe fn rollback(&self, checkpoint: Checkpoint) {
+        self.allocated.set(checkpoint.allocated);
+        self.allocations_count.set(checkpoint.allocations_count);
+    }
+
+    pub(crate) fn has_allocated(&self, ptr: NonNull<u8>) -> bool {
+        self.buf.contains(ptr)
+    }
+
+    pub(crate) fn allocate(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let mut padding = MaybeUninit::uninit();
+        let allocated = self.allocated.fetch_update(|allocated| {
+            let ptr = unsafe { self.buf.ptr().add(allocated) };
+            let padding = padding.write((layout.align() - (ptr as usize) % layout.align()) % layout.align());
+            let size = padding.checked_add(layout.size())?;
+            if size > self.buf.len() - allocated { return None; }
+            Some(allocated + size)
+        }).map_err(|_| AllocError)?;
+        let ptr = unsafe { self.buf.ptr().add(allocated) };
+        let padding = unsafe { padding.assume_init() };
+        self.allocations_count.fetch_update(|allocations_count|
+            allocations_count.checked_add(1)
+        ).map_err(|_| AllocError)?;
+        let res = NonNull::slice_from_raw_parts(
+            unsafe { NonNull::new_unchecked(ptr.add(padding)) },
+            layout.size()
+        );
+        Ok(res)
+    }
+
+    pub(crate) unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: alloc::Layout) {
+        let start_offset = ptr.as_ptr().offset_from(self.buf.ptr()) as usize;
+        let end_offset = start_offset + layout.size();
+        let _ = self.allocated.compare_exchange(end_offset, start_offset);
+        let _ = self.allocations_count.fetch_update(|allocations_count| Some(allocations_count - 1));
+    }
+
+    pub(crate) unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout,
+        zeroed: bool,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if new_layout.align() > old_layout.align() { return Err(AllocError); }
+        let start_offset = ptr.as_ptr().offset_from(self.buf.ptr()) as usize;
+        if new_layout.size() > self.buf.len() - start_offset { return Err(AllocError); }
+        let old_end_offset = start_offset + old_layout.size();
+        let new_end_offset = start_offset + new_layout.size();
+        self.allocated.compare_exchange(old_end_offset, new_end_offset).map_err(|_| AllocError)?;
+        if zeroed {
+            ptr.as_ptr().add(old_layout.size()).write_bytes(0, new_layout.size() - old_layout.size());
+        }
+        Ok(NonNull::slice_from_raw_parts(ptr, new_layout.size()))
+    }
+
+    pub(crate) unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if new_layout.align() > old_layout.align() { return Err(AllocError); }
+        let start_offset = ptr.as_ptr().offset_from(self.buf.ptr()) as usize;
+        let old_end_offset = start_offset + old_layout.size();
+        let new_end_offset = start_offset + new_layout.size();
+        let size = match self.allocated.compare_exchange(old_end_offset, new_end_offset) {
+            Ok(_) => new_layout.size(),
+            Err(_) => old_layout.size(),
+        };
+        Ok(NonNull::slice_from_raw_parts(ptr, size))
+    }
 }
 
 impl Drop for Stacked {
     fn drop(&mut self) {
-        assert!(self.allocations_count.load(Ordering::Relaxed) == 0, "memory leaks in Stacked allocator");
+        self.core.finish("Stacked");
     }
 }
 
 unsafe impl NonUnwinding for Stacked { }
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Checkpoint {
+    allocated: usize,
+    allocations_count: usize,
+}
+
+/// A guard, which rolls back the `S` allocator to a checkpoint on drop.
+pub struct Frame<'a, S = Stacked> {
+    stacked: &'a S,
+    checkpoint: Checkpoint,
+    rollback: unsafe fn(&S, Checkpoint),
+}
+
+impl<'a, S> Frame<'a, S> {
+    /// # Safety
+    ///
+    /// Calling `rollback` with `stacked` and `checkpoint` should be safe after the frame is dropped.
+    pub(crate) unsafe fn new(stacked: &'a S, checkpoint: Checkpoint, rollback: unsafe fn(&S, Checkpoint)) -> Self {
+        Frame { stacked, checkpoint, rollback }
+    }
+}
+
+impl<'a, S> Drop for Frame<'a, S> {
+    fn drop(&mut self) {
+        unsafe { (self.rollback)(self.stacked, self.checkpoint); }
+    }
+}
+
+impl<'a, S> Deref for Frame<'a, S> {
+    type Target = S;
+
+    fn deref(&self) -> &S { self.stacked }
+}
+
 impl Stacked {
+    const fn new(buf: Buf) -> Self {
+        Stacked {
+            core: Core::new(buf, AtomicUsize::new(0), AtomicUsize::new(0), SpinLock::new(LeakPolicy::Panic)),
+        }
+    }
+
     pub const fn from_static_slice(
         buf: &'static mut [MaybeUninit<u8>],
     ) -> Self {
-        Stacked {
-            buf_ptr: AtomicPtr::new(buf.as_mut_ptr() as *mut u8),
-            buf_len: buf.len(),
-            allocated: AtomicUsize::new(0),
-            allocations_count: AtomicUsize::new(0),
-        }
+        Self::new(Buf::from_static_slice(buf))
     }
 
     pub const fn from_static_array<const BUF_LEN: usize>(
         buf: &'static mut [MaybeUninit<u8>; BUF_LEN],
     ) -> Self {
-        Stacked {
-            buf_ptr: AtomicPtr::new(buf.as_mut_ptr() as *mut u8),
-            buf_len: BUF_LEN,
-            allocated: AtomicUsize::new(0),
-            allocations_count: AtomicUsize::new(0),
-        }
+        Self::new(Buf::from_static_array(buf))
     }
 
     /// # Safety
@@ -55,62 +324,80 @@ impl Stacked {
         buf_len: usize,
         f: impl for<'a> FnOnce(&'a Stacked) -> T
     ) -> T {
-        let stacked = Stacked {
-            buf_ptr: AtomicPtr::new(buf_ptr.as_ptr() as *mut u8),
-            buf_len,
-            allocated: AtomicUsize::new(0),
-            allocations_count: AtomicUsize::new(0),
-        };
-        f(&stacked)
+        Self::with(Buf::new(buf_ptr, buf_len), f)
     }
 
-    unsafe fn grow_raw(
-        &self, 
-        ptr: NonNull<u8>, 
-        old_layout: alloc::Layout, 
-        new_layout: alloc::Layout,
-        zeroed: bool,
-    ) -> Result<NonNull<[u8]>, AllocError> {
-        if new_layout.align() > old_layout.align() { return Err(AllocError); }
-        let start_offset = ptr.as_ptr().offset_from(self.buf_ptr.load(Ordering::Relaxed)) as usize;
-        if new_layout.size() > self.buf_len - start_offset { return Err(AllocError); }
-        let old_end_offset = start_offset + old_layout.size();
-        let new_end_offset = start_offset + new_layout.size();
-        self.allocated.compare_exchange(old_end_offset, new_end_offset, Ordering::Relaxed, Ordering::Relaxed)
-            .map_err(|_| AllocError)?;
-        if zeroed {
-            ptr.as_ptr().add(old_layout.size()).write_bytes(0, new_layout.size() - old_layout.size());
-        }
-        Ok(NonNull::slice_from_raw_parts(ptr, new_layout.size()))
+    fn with<T>(buf: Buf, f: impl for<'a> FnOnce(&'a Stacked) -> T) -> T {
+        let stacked = Stacked::new(buf);
+        stacked.core.run(|| f(&stacked))
+    }
+
+    pub fn leak_policy(&self) -> LeakPolicy {
+        self.core.leak_policy()
+    }
+
+    /// Sets what to do on drop, if there are outstanding allocations.
+    /// The default policy is [`LeakPolicy::Panic`].
+    pub fn set_leak_policy(&self, policy: LeakPolicy) {
+        self.core.set_leak_policy(policy);
+    }
+
+    /// Returns outstanding allocations, if any, instead of applying the leak policy on drop.
+    ///
+    /// Returned leaks are considered handled by the caller,
+    /// so the [leak policy](Stacked::set_leak_policy) is set to [`LeakPolicy::Ignore`].
+    pub fn try_finish(&self) -> Result<(), Leaks> {
+        self.core.try_finish()
+    }
+
+    pub fn checkpoint(&self) -> Checkpoint {
+        self.core.checkpoint()
+    }
+
+    /// Resets the allocator to the state captured by `checkpoint`,
+    /// releasing all memory allocated after it at once.
+    ///
+    /// # Safety
+    ///
+    /// The `checkpoint` should be taken from this allocator.
+    ///
+    /// Blocks allocated after the `checkpoint` was taken should not be used or deallocated after the call.
+    ///
+    /// Blocks allocated before the `checkpoint` was taken should not be deallocated, grown or shrunk between
+    /// taking the `checkpoint` and the call, because growing the top block in place moves it past
+    /// the checkpoint, and the rollback would cut it off.
+    pub unsafe fn rollback(&self, checkpoint: Checkpoint) {
+        self.core.rollback(checkpoint);
+    }
+
+    /// Takes a checkpoint and returns a guard, which [rolls back](Stacked::rollback) to it on drop.
+    ///
+    /// # Safety
+    ///
+    /// Blocks allocated while the frame is alive should not be used or deallocated after it is dropped.
+    ///
+    /// Blocks allocated before the frame was created should not be deallocated, grown or shrunk while it is alive.
+    pub unsafe fn frame(&self) -> Frame<'_> {
+        Frame::new(self, self.checkpoint(), Stacked::rollback)
     }
 }
 
 pub fn with_size<const BUF_LEN: usize, T>(
     f: impl for<'a> FnOnce(&'a Stacked) -> T
 ) -> T {
-    let mut buf: [MaybeUninit<u8>; BUF_LEN] = [MaybeUninit::uninit(); BUF_LEN];
-    let buf_ptr = unsafe { NonNull::new_unchecked(buf.as_mut_ptr()) };
-    assert!((isize::MAX as usize) - BUF_LEN >= buf_ptr.as_ptr() as usize);
-    unsafe { Stacked::with_buf_raw(buf_ptr, BUF_LEN, f) }
+    buffer::with_size::<BUF_LEN, _>(|buf| Stacked::with(buf, f))
 }
 
 pub fn with_buf<T>(
     buf: &mut [MaybeUninit<u8>],
     f: impl for<'a> FnOnce(&'a Stacked) -> T
 ) -> T {
-    let buf_len = buf.len();
-    assert!(buf_len <= isize::MAX as usize && (isize::MAX as usize) - buf_len >= buf.as_ptr() as usize);
-    let buf_ptr = unsafe { NonNull::new_unchecked(buf.as_mut_ptr()) };
-    unsafe { Stacked::with_buf_raw(buf_ptr, buf_len, f) }
+    buffer::with_buf(buf, |buf| Stacked::with(buf, f))
 }
 
 unsafe impl Fallbackable for Stacked {
     unsafe fn has_allocated(&self, ptr: NonNull<u8>, _layout: alloc::Layout) -> bool {
-        if let Some(offset) = (ptr.as_ptr() as usize).checked_sub(self.buf_ptr.load(Ordering::Relaxed) as usize) {
-            offset < self.buf_len && self.buf_ptr.load(Ordering::Relaxed).add(offset) == ptr.as_ptr()
-        } else {
-            false
-        }
+        self.core.has_allocated(ptr)
     }
 
     fn allows_fallback(&self, _layout: alloc::Layout) -> bool {
@@ -120,31 +407,11 @@ unsafe impl Fallbackable for Stacked {
 
 unsafe impl Allocator for Stacked {
     fn allocate(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
-        let mut padding = MaybeUninit::uninit();
-        let allocated = self.allocated.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |allocated| {
-            let ptr = unsafe { self.buf_ptr.load(Ordering::Relaxed).add(allocated) };
-            let padding = padding.write((layout.align() - (ptr as usize) % layout.align()) % layout.align());
-            let size = padding.checked_add(layout.size())?;
-            if size > self.buf_len - allocated { return None; }
-            Some(allocated + size)
-        }).map_err(|_| AllocError)?;
-        let ptr = unsafe { self.buf_ptr.load(Ordering::Relaxed).add(allocated) };
-        let padding = unsafe { padding.assume_init() };
-        self.allocations_count.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |allocations_count|
-            allocations_count.checked_add(1)
-        ).map_err(|_| AllocError)?;
-        let res = NonNull::slice_from_raw_parts(
-            unsafe { NonNull::new_unchecked(ptr.add(padding)) },
-            layout.size()
-        );
-        Ok(res)
+        self.core.allocate(layout)
     }
 
     unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: alloc::Layout) {
-        let start_offset = ptr.as_ptr().offset_from(self.buf_ptr.load(Ordering::Relaxed)) as usize;
-        let end_offset = start_offset + layout.size();
-        let _ = self.allocated.compare_exchange(end_offset, start_offset, Ordering::Relaxed, Ordering::Relaxed);
-        self.allocations_count.fetch_sub(1, Ordering::Relaxed);
+        self.core.deallocate(ptr, layout);
     }
 
     unsafe fn grow(
@@ -153,7 +420,7 @@ unsafe impl Allocator for Stacked {
         old_layout: alloc::Layout, 
         new_layout: alloc::Layout
     ) -> Result<NonNull<[u8]>, AllocError> {
-        self.grow_raw(ptr, old_layout, new_layout, false)
+        self.core.grow(ptr, old_layout, new_layout, false)
     }
 
     unsafe fn grow_zeroed(
@@ -162,7 +429,7 @@ unsafe impl Allocator for Stacked {
         old_layout: alloc::Layout, 
         new_layout: alloc::Layout
     ) -> Result<NonNull<[u8]>, AllocError> {
-        self.grow_raw(ptr, old_layout, new_layout, true)
+        self.core.grow(ptr, old_layout, new_layout, true)
     }
 
     unsafe fn shrink(
@@ -171,14 +438,81 @@ unsafe impl Allocator for Stacked {
         old_layout: alloc::Layout, 
         new_layout: alloc::Layout
     ) -> Result<NonNull<[u8]>, AllocError> {
-        if new_layout.align() > old_layout.align() { return Err(AllocError); }
-        let start_offset = ptr.as_ptr().offset_from(self.buf_ptr.load(Ordering::Relaxed)) as usize;
-        let old_end_offset = start_offset + old_layout.size();
-        let new_end_offset = start_offset + new_layout.size();
-        let size = match self.allocated.compare_exchange(old_end_offset, new_end_offset, Ordering::Relaxed, Ordering::Relaxed) {
-            Ok(_) => new_layout.size(),
-            Err(_) => old_layout.size(),
-        };
-        Ok(NonNull::slice_from_raw_parts(ptr, size))
+        self.core.shrink(ptr, old_layout, new_layout)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use crate::stacked::{self, LeakPolicy};
+    use core::alloc::{self, Allocator};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn rollback_resets_allocated_and_count() {
+        stacked::with_size::<256, _>(|stacked| {
+            let layout = alloc::Layout::from_size_align(8, 8).unwrap();
+            let kept = stacked.allocate(layout).unwrap();
+            let checkpoint = stacked.checkpoint();
+            for _ in 0 .. 4 {
+                stacked.allocate(layout).unwrap();
+            }
+            assert_ne!(stacked.checkpoint(), checkpoint);
+            unsafe { stacked.rollback(checkpoint); }
+            assert_eq!(stacked.checkpoint(), checkpoint);
+            assert_eq!(checkpoint.allocations_count, 1);
+            unsafe { stacked.deallocate(kept.as_non_null_ptr(), layout); }
+            assert!(stacked.try_finish().is_ok());
+        });
+    }
+
+    #[test]
+    fn frame_rolls_back_on_drop() {
+        stacked::with_size::<256, _>(|stacked| {
+            let layout = alloc::Layout::from_size_align(16, 8).unwrap();
+            let checkpoint = stacked.checkpoint();
+            let reused = {
+                let frame = unsafe { stacked.frame() };
+                let block = frame.allocate(layout).unwrap();
+                frame.allocate(layout).unwrap();
+                block.as_non_null_ptr()
+            };
+            assert_eq!(stacked.checkpoint(), checkpoint);
+            let block = stacked.allocate(layout).unwrap();
+            assert_eq!(block.as_non_null_ptr(), reused);
+            unsafe { stacked.deallocate(block.as_non_null_ptr(), layout); }
+        });
+    }
+
+    #[test]
+    fn try_finish_disarms_leak_policy() {
+        static REPORTED: AtomicUsize = AtomicUsize::new(0);
+        stacked::with_size::<64, _>(|stacked| {
+            stacked.set_leak_policy(LeakPolicy::Handler(|leaks| REPORTED.store(leaks.allocations, Ordering::Relaxed)));
+            assert!(stacked.try_finish().is_ok());
+            assert!(matches!(stacked.leak_policy(), LeakPolicy::Handler(_)));
+            stacked.allocate(alloc::Layout::new::<u64>()).unwrap();
+            assert_eq!(stacked.try_finish().unwrap_err().allocations, 1);
+            assert!(matches!(stacked.leak_policy(), LeakPolicy::Ignore));
+        });
+        assert_eq!(REPORTED.load(Ordering::Relaxed), 0);
+        stacked::with_size::<64, _>(|stacked| {
+            stacked.set_leak_policy(LeakPolicy::Handler(|leaks| REPORTED.store(leaks.allocations, Ordering::Relaxed)));
+            stacked.allocate(alloc::Layout::new::<u64>()).unwrap();
+        });
+        assert_eq!(REPORTED.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn unwinding_does_not_panic_on_leaks() {
+        let res = std::panic::catch_unwind(|| {
+            stacked::with_size::<64, _>(|stacked| {
+                stacked.allocate(alloc::Layout::new::<u64>()).unwrap();
+                panic!("unwinding");
+            })
+        });
+        assert!(res.is_err());
     }
 }
@@ -0,0 +1,210 @@
+use crate::base::*;
+use crate::spin_lock::SpinLock;
+use core::alloc::{self, AllocError, Allocator};
+use core::ptr::NonNull;
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct Counters {
+    pub allocations: usize,
+    pub allocation_failures: usize,
+    pub deallocations: usize,
+    pub grows: usize,
+    pub grow_failures: usize,
+    pub shrinks: usize,
+    pub shrink_failures: usize,
+    pub live_bytes: usize,
+    pub peak_bytes: usize,
+    pub total_bytes: usize,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Counters {
+            allocations: 0,
+            allocation_failures: 0,
+            deallocations: 0,
+            grows: 0,
+            grow_failures: 0,
+            shrinks: 0,
+            shrink_failures: 0,
+            live_bytes: 0,
+            peak_bytes: 0,
+            total_bytes: 0,
+        }
+    }
+
+    fn add_live_bytes(&mut self, size: usize) {
+        self.live_bytes = self.live_bytes.wrapping_add(size);
+        self.total_bytes = self.total_bytes.saturating_add(size);
+        if self.live_bytes > self.peak_bytes {
+            self.peak_bytes = self.live_bytes;
+        }
+    }
+
+    fn sub_live_bytes(&mut self, size: usize) {
+        self.live_bytes = self.live_bytes.wrapping_sub(size);
+    }
+}
+
+/// Collects allocation statistics.
+///
+/// Byte counters are computed from requested layouts sizes.
+/// Returned blocks are shortened to requested sizes,
+/// otherwise a block could be deallocated with a bigger layout, fitting the whole block,
+/// and live bytes would drop below the real value.
+pub struct Stats<A: Allocator> {
+    counters: SpinLock<Counters>,
+    base: A,
+}
+
+unsafe impl<A: NonUnwinding> NonUnwinding for Stats<A> { }
+
+impl<A: Allocator> Stats<A> {
+    pub const fn new(base: A) -> Self {
+        Stats { counters: SpinLock::new(Counters::new()), base }
+    }
+
+    pub fn base(&self) -> &A { &self.base }
+
+    fn update<T>(&self, f: impl FnOnce(&mut Counters) -> T) -> T {
+        self.counters.with(f)
+    }
+
+    fn record_allocation(&self, layout: alloc::Layout, res: Result<NonNull<[u8]>, AllocError>) -> Result<NonNull<[u8]>, AllocError> {
+        self.update(|counters| if res.is_ok() {
+            counters.allocations += 1;
+            counters.add_live_bytes(layout.size());
+        } else {
+            counters.allocation_failures += 1;
+        });
+        res.map(|block| clamp(block, layout.size()))
+    }
+
+    fn record_grow(
+        &self,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout,
+        res: Result<NonNull<[u8]>, AllocError>
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.update(|counters| if res.is_ok() {
+            counters.grows += 1;
+            counters.add_live_bytes(new_layout.size() - old_layout.size());
+        } else {
+            counters.grow_failures += 1;
+        });
+        res.map(|block| clamp(block, new_layout.size()))
+    }
+
+    /// Returns the number of allocated and not yet deallocated blocks.
+    #[cfg(test)]
+    pub(crate) fn live_blocks(&self) -> usize {
+        let counters = self.snapshot();
+        counters.allocations - counters.deallocations
+    }
+
+    /// Returns a consistent copy of all counters.
+    pub fn snapshot(&self) -> Counters {
+        self.update(|counters| *counters)
+    }
+
+    /// Resets all counters except live bytes, which is also taken as a new peak.
+    pub fn reset(&self) {
+        self.update(|counters| {
+            let live_bytes = counters.live_bytes;
+            *counters = Counters::new();
+            counters.live_bytes = live_bytes;
+            counters.peak_bytes = live_bytes;
+        });
+    }
+}
+
+unsafe impl<A: Fallbackable> Fallbackable for Stats<A> {
+    unsafe fn has_allocated(&self, ptr: NonNull<u8>, layout: alloc::Layout) -> bool {
+        self.base.has_allocated(ptr, layout)
+    }
+
+    fn allows_fallback(&self, layout: alloc::Layout) -> bool {
+        self.base.allows_fallback(layout)
+    }
+}
+
+unsafe impl<A: Allocator> Allocator for Stats<A> {
+    fn allocate(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.record_allocation(layout, self.base.allocate(layout))
+    }
+
+    fn allocate_zeroed(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.record_allocation(layout, self.base.allocate_zeroed(layout))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: alloc::Layout) {
+        self.base.deallocate(ptr, layout);
+        self.update(|counters| {
+            counters.deallocations += 1;
+            counters.sub_live_bytes(layout.size());
+        });
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.record_grow(old_layout, new_layout, self.base.grow(ptr, old_layout, new_layout))
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.record_grow(old_layout, new_layout, self.base.grow_zeroed(ptr, old_layout, new_layout))
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let res = self.base.shrink(ptr, old_layout, new_layout);
+        self.update(|counters| if res.is_ok() {
+            counters.shrinks += 1;
+            counters.sub_live_bytes(old_layout.size() - new_layout.size());
+        } else {
+            counters.shrink_failures += 1;
+        });
+        res.map(|block| clamp(block, new_layout.size()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Stats, System};
+    use crate::slab::Slab;
+    use core::alloc::{self, Allocator};
+
+    #[test]
+    fn blocks_are_clamped_to_counted_sizes() {
+        let slab = Slab::new(alloc::Layout::from_size_align(64, 8).unwrap(), 4096, System);
+        let stats = Stats::new(&slab);
+        let small = alloc::Layout::from_size_align(16, 8).unwrap();
+        let block = stats.allocate(small).unwrap();
+        assert_eq!(block.len(), 16);
+        assert_eq!(stats.snapshot().live_bytes, 16);
+        let grown = alloc::Layout::from_size_align(32, 8).unwrap();
+        let block = unsafe { stats.grow(block.as_non_null_ptr(), small, grown) }.unwrap();
+        assert_eq!(block.len(), 32);
+        assert_eq!(stats.snapshot().live_bytes, 32);
+        let tiny = alloc::Layout::from_size_align(8, 8).unwrap();
+        let block = unsafe { stats.shrink(block.as_non_null_ptr(), grown, tiny) }.unwrap();
+        assert_eq!(block.len(), 8);
+        assert_eq!(stats.snapshot().live_bytes, 8);
+        unsafe { stats.deallocate(block.as_non_null_ptr(), tiny); }
+        let counters = stats.snapshot();
+        assert_eq!(counters.live_bytes, 0);
+        assert_eq!(counters.peak_bytes, 32);
+    }
+}
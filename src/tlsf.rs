@@ -0,0 +1,472 @@
+use crate::base::*;
+use crate::buffer::{self, Arena, ArenaState, Buf};
+use core::alloc::{self, AllocError, Allocator};
+use core::cmp::max;
+use core::mem::{MaybeUninit, size_of};
+use core::ptr::NonNull;
+
+/// Block header, placed before every block.
+struct Header {
+    /// The previous physical block offset.
+    prev: usize,
+    /// The block size, not including the header, with the `FREE` flag.
+    size: usize,
+}
+
+/// Free list links, placed at a free block start.
+struct Links {
+    next: usize,
+    prev: usize,
+}
+
+const ALIGN: usize = 2 * size_of::<usize>();
+
+const HEADER: usize = size_of::<Header>();
+
+const MIN_SIZE: usize = size_of::<Links>();
+
+const FREE: usize = 1;
+
+const NIL: usize = usize::MAX;
+
+const SL_LOG2: u32 = 4;
+
+const SL_COUNT: usize = 1 << SL_LOG2;
+
+const FL_SHIFT: u32 = SL_LOG2 + ALIGN.trailing_zeros();
+
+/// Sizes less than `SMALL` are split into second level lists linearly.
+const SMALL: usize = 1 << FL_SHIFT;
+
+const FL_COUNT: usize = (usize::BITS - FL_SHIFT + 1) as usize;
+
+fn round_up(size: usize, align: usize) -> Option<usize> {
+    Some(size.checked_add(align - 1)? & !(align - 1))
+}
+
+fn mapping(size: usize) -> (usize, usize) {
+    if size < SMALL {
+        (0, size / (SMALL / SL_COUNT))
+    } else {
+        let log2 = size.ilog2();
+        ((log2 - FL_SHIFT + 1) as usize, (size >> (log2 - SL_LOG2)) - SL_COUNT)
+    }
+}
+
+/// Returns the first list, which blocks all are not less than `size`.
+fn mapping_search(size: usize) -> Option<(usize, usize)> {
+    let size = if size < SMALL { size } else { size.checked_add((1 << (size.ilog2() - SL_LOG2)) - 1)? };
+    Some(mapping(size)).filter(|&(fl, _)| fl < FL_COUNT)
+}
+
+/// Returns the block size for a requested size.
+fn block_size(size: usize) -> Option<usize> {
+    Some(max(round_up(size, ALIGN)?, MIN_SIZE))
+}
+
+struct State {
+    fl_bitmap: usize,
+    sl_bitmaps: [u32; FL_COUNT],
+    /// Free lists heads, as offsets from the arena start.
+    heads: [[usize; SL_COUNT]; FL_COUNT],
+    free_bytes: usize,
+    used_bytes: usize,
+}
+
+impl State {
+    const fn new() -> Self {
+        State {
+            fl_bitmap: 0,
+            sl_bitmaps: [0; FL_COUNT],
+            heads: [[NIL; SL_COUNT]; FL_COUNT],
+            free_bytes: 0,
+            used_bytes: 0,
+        }
+    }
+
+    unsafe fn header(&self, arena: *mut u8, offset: usize) -> *mut Header {
+        arena.add(offset) as *mut Header
+    }
+
+    unsafe fn links(&self, arena: *mut u8, offset: usize) -> *mut Links {
+        arena.add(offset + HEADER) as *mut Links
+    }
+
+    unsafe fn size(&self, arena: *mut u8, offset: usize) -> usize {
+        (*self.header(arena, offset)).size & !FREE
+    }
+
+    unsafe fn set_size(&self, arena: *mut u8, offset: usize, size: usize) {
+        (*self.header(arena, offset)).size = size;
+    }
+
+    unsafe fn is_free(&self, arena: *mut u8, offset: usize) -> bool {
+        (*self.header(arena, offset)).size & FREE != 0
+    }
+
+    unsafe fn next(&self, arena: *mut u8, offset: usize) -> usize {
+        offset + HEADER + self.size(arena, offset)
+    }
+
+    unsafe fn insert(&mut self, arena: *mut u8, offset: usize) {
+        let size = self.size(arena, offset);
+        let (fl, sl) = mapping(size);
+        let head = self.heads[fl][sl];
+        self.links(arena, offset).write(Links { next: head, prev: NIL });
+        if head != NIL {
+            (*self.links(arena, head)).prev = offset;
+        }
+        self.heads[fl][sl] = offset;
+        self.sl_bitmaps[fl] |= 1 << sl;
+        self.fl_bitmap |= 1 << fl;
+        self.set_size(arena, offset, size | FREE);
+        self.free_bytes += size;
+    }
+
+    unsafe fn remove(&mut self, arena: *mut u8, offset: usize) {
+        let size = self.size(arena, offset);
+        let (fl, sl) = mapping(size);
+        let Links { next, prev } = self.links(arena, offset).read();
+        if prev == NIL {
+            self.heads[fl][sl] = next;
+            if next == NIL {
+                self.sl_bitmaps[fl] &= !(1 << sl);
+                if self.sl_bitmaps[fl] == 0 {
+                    self.fl_bitmap &= !(1 << fl);
+                }
+            }
+        } else {
+            (*self.links(arena, prev)).next = next;
+        }
+        if next != NIL {
+            (*self.links(arena, next)).prev = prev;
+        }
+        self.set_size(arena, offset, size);
+        self.free_bytes -= size;
+    }
+
+    fn find(&self, size: usize) -> Option<usize> {
+        let (fl, sl) = mapping_search(size)?;
+        let sl_map = self.sl_bitmaps[fl] & (!0 << sl);
+        let (fl, sl_map) = if sl_map != 0 {
+            (fl, sl_map)
+        } else {
+            let fl_map = self.fl_bitmap & (!0usize).checked_shl(fl as u32 + 1).unwrap_or(0);
+            if fl_map == 0 { return None; }
+            let fl = fl_map.trailing_zeros() as usize;
+            (fl, self.sl_bitmaps[fl])
+        };
+        Some(self.heads[fl][sl_map.trailing_zeros() as usize])
+    }
+
+    /// Splits a not listed block, so it has `size` bytes, and returns the rest block.
+    unsafe fn split(&self, arena: *mut u8, offset: usize, size: usize) -> Option<usize> {
+        let total = self.size(arena, offset);
+        if total < size + HEADER + MIN_SIZE { return None; }
+        let rest = offset + HEADER + size;
+        self.header(arena, rest).write(Header { prev: offset, size: total - size - HEADER });
+        (*self.header(arena, self.next(arena, rest))).prev = rest;
+        self.set_size(arena, offset, size);
+        Some(rest)
+    }
+
+    /// Merges a not listed block with the next one, if it is free.
+    unsafe fn merge_next(&mut self, arena: *mut u8, offset: usize) -> bool {
+        let next = self.next(arena, offset);
+        if !self.is_free(arena, next) { return false; }
+        self.remove(arena, next);
+        self.set_size(arena, offset, self.size(arena, offset) + HEADER + self.size(arena, next));
+        (*self.header(arena, self.next(arena, offset))).prev = offset;
+        true
+    }
+
+    /// Merges a not listed block with its free neighbours, and inserts it into a free list.
+    unsafe fn release(&mut self, arena: *mut u8, mut offset: usize) {
+        let prev = (*self.header(arena, offset)).prev;
+        if prev != NIL && self.is_free(arena, prev) {
+            self.remove(arena, prev);
+            self.set_size(arena, prev, self.size(arena, prev) + HEADER + self.size(arena, offset));
+            (*self.header(arena, self.next(arena, prev))).prev = prev;
+            offset = prev;
+        }
+        self.merge_next(arena, offset);
+        self.insert(arena, offset);
+    }
+
+    unsafe fn allocate(&mut self, arena: *mut u8, layout: alloc::Layout) -> Option<usize> {
+        let size = block_size(layout.size())?;
+        let request = if layout.align() <= ALIGN {
+            size
+        } else {
+            size.checked_add(layout.align())?.checked_add(HEADER + MIN_SIZE)?
+        };
+        let mut offset = self.find(request)?;
+        self.remove(arena, offset);
+        if layout.align() > ALIGN {
+            let payload = arena as usize + offset + HEADER;
+            let mut gap = round_up(payload, layout.align())? - payload;
+            if gap != 0 && gap < HEADER + MIN_SIZE {
+                gap = round_up(payload + HEADER + MIN_SIZE, layout.align())? - payload;
+            }
+            if gap != 0 {
+                let aligned = self.split(arena, offset, gap - HEADER).unwrap();
+                self.release(arena, offset);
+                offset = aligned;
+            }
+        }
+        if let Some(rest) = self.split(arena, offset, size) {
+            self.release(arena, rest);
+        }
+        self.used_bytes += self.size(arena, offset);
+        Some(offset)
+    }
+
+    unsafe fn deallocate(&mut self, arena: *mut u8, offset: usize) {
+        self.used_bytes -= self.size(arena, offset);
+        self.release(arena, offset);
+    }
+
+    /// Resizes a block in place, returns `false`, if it is impossible.
+    unsafe fn resize(&mut self, arena: *mut u8, offset: usize, size: usize) -> bool {
+        let old_size = self.size(arena, offset);
+        if size > old_size {
+            let next = self.next(arena, offset);
+            if !self.is_free(arena, next) || old_size + HEADER + self.size(arena, next) < size {
+                return false;
+            }
+            self.merge_next(arena, offset);
+        }
+        if let Some(rest) = self.split(arena, offset, size) {
+            self.release(arena, rest);
+        }
+        self.used_bytes = self.used_bytes - old_size + self.size(arena, offset);
+        true
+    }
+}
+
+impl ArenaState for State {
+    unsafe fn init(buf_ptr: *mut u8, buf_len: usize) -> (usize, Self) {
+        let mut state = State::new();
+        let arena = buf_ptr.align_offset(ALIGN);
+        if arena >= buf_len { return (0, state); }
+        let len = (buf_len - arena) & !(ALIGN - 1);
+        if len < 2 * HEADER + MIN_SIZE { return (0, state); }
+        let arena_ptr = buf_ptr.add(arena);
+        state.header(arena_ptr, 0).write(Header { prev: NIL, size: len - 2 * HEADER });
+        // The last block is an always used zero-sized sentinel, which stops merging.
+        state.header(arena_ptr, len - HEADER).write(Header { prev: 0, size: 0 });
+        state.insert(arena_ptr, 0);
+        (arena, state)
+    }
+}
+
+/// A two-level segregated fit allocator over a buffer.
+///
+/// Allocating and deallocating take constant time.
+/// Every block has a two words header.
+/// Requested sizes are rounded up to a free list size class,
+/// so a free block slightly larger than a request could be not found.
+pub struct Tlsf {
+    arena: Arena<State>,
+}
+
+unsafe impl NonUnwinding for Tlsf { }
+
+impl Tlsf {
+    const fn new(buf: Buf) -> Self {
+        Tlsf { arena: Arena::new(buf) }
+    }
+
+    pub const fn from_static_slice(
+        buf: &'static mut [MaybeUninit<u8>],
+    ) -> Self {
+        Self::new(Buf::from_static_slice(buf))
+    }
+
+    pub const fn from_static_array<const BUF_LEN: usize>(
+        buf: &'static mut [MaybeUninit<u8>; BUF_LEN],
+    ) -> Self {
+        Self::new(Buf::from_static_array(buf))
+    }
+
+    /// # Safety
+    ///
+    /// Arguments should satisfy [`Stacked::with_buf_raw`](crate::stacked::Stacked::with_buf_raw) requirements.
+    pub unsafe fn with_buf_raw<T>(
+        buf_ptr: NonNull<MaybeUninit<u8>>,
+        buf_len: usize,
+        f: impl for<'a> FnOnce(&'a Tlsf) -> T
+    ) -> T {
+        f(&Tlsf::new(Buf::new(buf_ptr, buf_len)))
+    }
+
+    /// Returns the total size of free blocks, not including headers.
+    pub fn free_bytes(&self) -> usize {
+        self.arena.with_state(|state, _| state.free_bytes)
+    }
+
+    /// Returns the total size of allocated blocks, not including headers.
+    pub fn used_bytes(&self) -> usize {
+        self.arena.with_state(|state, _| state.used_bytes)
+    }
+
+    unsafe fn offset(arena: *mut u8, ptr: NonNull<u8>) -> usize {
+        ptr.as_ptr().offset_from(arena) as usize - HEADER
+    }
+
+    unsafe fn block(state: &State, arena: *mut u8, offset: usize) -> NonNull<[u8]> {
+        let ptr = NonNull::new_unchecked(arena.add(offset + HEADER));
+        NonNull::slice_from_raw_parts(ptr, state.size(arena, offset))
+    }
+
+    unsafe fn resize(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout,
+        zeroed: bool,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let in_place = if (ptr.as_ptr() as usize) % new_layout.align() != 0 {
+            None
+        } else {
+            let size = block_size(new_layout.size()).ok_or(AllocError)?;
+            self.arena.with_state(|state, arena| {
+                let offset = Self::offset(arena, ptr);
+                if state.resize(arena, offset, size) { Some(Self::block(state, arena, offset)) } else { None }
+            })
+        };
+        if let Some(block) = in_place {
+            if zeroed {
+                ptr.add(old_layout.size()).write_bytes(0, block.len() - old_layout.size());
+            }
+            return Ok(block);
+        }
+        move_block(self, ptr, old_layout, new_layout, zeroed)
+    }
+}
+
+pub fn with_size<const BUF_LEN: usize, T>(
+    f: impl for<'a> FnOnce(&'a Tlsf) -> T
+) -> T {
+    buffer::with_size::<BUF_LEN, _>(|buf| f(&Tlsf::new(buf)))
+}
+
+pub fn with_buf<T>(
+    buf: &mut [MaybeUninit<u8>],
+    f: impl for<'a> FnOnce(&'a Tlsf) -> T
+) -> T {
+    buffer::with_buf(buf, |buf| f(&Tlsf::new(buf)))
+}
+
+unsafe impl Fallbackable for Tlsf {
+    unsafe fn has_allocated(&self, ptr: NonNull<u8>, _layout: alloc::Layout) -> bool {
+        self.arena.buf().contains(ptr)
+    }
+
+    fn allows_fallback(&self, _layout: alloc::Layout) -> bool {
+        true
+    }
+}
+
+unsafe impl Allocator for Tlsf {
+    fn allocate(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.arena.with_state(|state, arena| unsafe {
+            let offset = state.allocate(arena, layout)?;
+            Some(Self::block(state, arena, offset))
+        }).ok_or(AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, _layout: alloc::Layout) {
+        self.arena.with_state(|state, arena| state.deallocate(arena, Self::offset(arena, ptr)));
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old_layout, new_layout, false)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old_layout, new_layout, true)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old_layout, new_layout, false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::tlsf::{self, ALIGN, HEADER, SL_COUNT, SMALL, mapping, mapping_search};
+    use core::alloc::{self, Allocator};
+
+    fn layout(size: usize) -> alloc::Layout {
+        alloc::Layout::from_size_align(size, ALIGN).unwrap()
+    }
+
+    #[test]
+    fn class_mapping() {
+        assert_eq!(mapping(0), (0, 0));
+        assert_eq!(mapping(SMALL - 1), (0, SL_COUNT - 1));
+        assert_eq!(mapping(SMALL), (1, 0));
+        assert_eq!(mapping(2 * SMALL - 1), (1, SL_COUNT - 1));
+        assert_eq!(mapping(2 * SMALL), (2, 0));
+        assert_eq!(mapping_search(SMALL), Some((1, 0)));
+        assert_eq!(mapping_search(SMALL + 1), Some((1, 1)));
+        assert_eq!(mapping_search(2 * SMALL - 1), Some((2, 0)));
+        assert_eq!(mapping_search(usize::MAX), None);
+    }
+
+    #[test]
+    fn grows_in_place_into_next_free_block() {
+        tlsf::with_size::<4096, _>(|tlsf| {
+            let a = tlsf.allocate(layout(64)).unwrap();
+            let b = tlsf.allocate(layout(64)).unwrap();
+            let c = tlsf.allocate(layout(64)).unwrap();
+            let moved = unsafe { tlsf.grow(b.as_non_null_ptr(), layout(64), layout(96)) }.unwrap();
+            assert_ne!(moved.as_non_null_ptr(), b.as_non_null_ptr());
+            let grown = unsafe { tlsf.grow(a.as_non_null_ptr(), layout(64), layout(128)) }.unwrap();
+            assert_eq!(grown.as_non_null_ptr(), a.as_non_null_ptr());
+            // The rest of the merged block is too small to be split off.
+            assert_eq!(grown.len(), 64 + HEADER + 64);
+            assert_eq!(tlsf.used_bytes(), grown.len() + 96 + 64);
+            unsafe {
+                tlsf.deallocate(grown.as_non_null_ptr(), layout(128));
+                tlsf.deallocate(moved.as_non_null_ptr(), layout(96));
+                tlsf.deallocate(c.as_non_null_ptr(), layout(64));
+            }
+            assert_eq!(tlsf.used_bytes(), 0);
+        });
+    }
+
+    #[test]
+    fn coalesces_and_accounts_bytes() {
+        tlsf::with_size::<4096, _>(|tlsf| {
+            let total = tlsf.free_bytes();
+            assert_eq!(tlsf.used_bytes(), 0);
+            let blocks = [100, 64, 32].map(|size| (tlsf.allocate(layout(size)).unwrap(), size));
+            assert_eq!(tlsf.used_bytes(), 112 + 64 + 32);
+            assert_eq!(tlsf.free_bytes(), total - tlsf.used_bytes() - 3 * HEADER);
+            for index in [0, 2, 1] {
+                let (block, size) = blocks[index];
+                unsafe { tlsf.deallocate(block.as_non_null_ptr(), layout(size)); }
+            }
+            assert_eq!(tlsf.used_bytes(), 0);
+            // Headers of merged blocks are counted as free again.
+            assert_eq!(tlsf.free_bytes(), total);
+        });
+    }
+}
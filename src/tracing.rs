@@ -0,0 +1,378 @@
+use crate::base::*;
+use core::alloc::{self, AllocError, Allocator};
+use core::ptr::NonNull;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum TraceOp {
+    Allocate = 0,
+    AllocateZeroed = 1,
+    Deallocate = 2,
+    Grow = 3,
+    GrowZeroed = 4,
+    Shrink = 5,
+}
+
+impl TraceOp {
+    fn from_u8(op: u8) -> Option<TraceOp> {
+        match op {
+            0 => Some(TraceOp::Allocate),
+            1 => Some(TraceOp::AllocateZeroed),
+            2 => Some(TraceOp::Deallocate),
+            3 => Some(TraceOp::Grow),
+            4 => Some(TraceOp::GrowZeroed),
+            5 => Some(TraceOp::Shrink),
+            _ => None,
+        }
+    }
+
+    fn has_old_layout(self) -> bool {
+        matches!(self, TraceOp::Grow | TraceOp::GrowZeroed | TraceOp::Shrink)
+    }
+}
+
+/// A trace record.
+///
+/// Block ids are opaque non-zero numbers.
+/// Only zero-sized live blocks can share an id.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TraceRecord {
+    pub op: TraceOp,
+    /// The old layout for `grow`, `grow_zeroed`, and `shrink`.
+    pub old_layout: Option<alloc::Layout>,
+    pub layout: alloc::Layout,
+    /// The id of the block passed to the operation, or zero.
+    pub block: u64,
+    /// The id of the returned block, if the operation returns any.
+    pub result: Option<Result<u64, AllocError>>,
+    /// Nanoseconds, as returned by [`TraceSink::timestamp`].
+    pub timestamp: u64,
+}
+
+const RESULT_NONE: u8 = 0;
+const RESULT_OK: u8 = 1;
+const RESULT_FAILED: u8 = 2;
+
+impl TraceRecord {
+    /// The encoded record size in bytes.
+    pub const SIZE: usize = 44;
+
+    /// Encodes the record.
+    ///
+    /// The record layout is
+    /// `op: u8, result: u8, old_align_log2: u8, align_log2: u8,
+    /// old_size: u64, size: u64, block: u64, result_block: u64, timestamp: u64`,
+    /// all numbers are little-endian.
+    pub fn to_bytes(&self) -> [u8; TraceRecord::SIZE] {
+        let old_layout = self.old_layout.unwrap_or(alloc::Layout::new::<()>());
+        let (result, result_block) = match self.result {
+            None => (RESULT_NONE, 0),
+            Some(Ok(block)) => (RESULT_OK, block),
+            Some(Err(AllocError)) => (RESULT_FAILED, 0),
+        };
+        let mut bytes = [0; TraceRecord::SIZE];
+        bytes[0] = self.op as u8;
+        bytes[1] = result;
+        bytes[2] = old_layout.align().trailing_zeros() as u8;
+        bytes[3] = self.layout.align().trailing_zeros() as u8;
+        bytes[4 .. 12].copy_from_slice(&(old_layout.size() as u64).to_le_bytes());
+        bytes[12 .. 20].copy_from_slice(&(self.layout.size() as u64).to_le_bytes());
+        bytes[20 .. 28].copy_from_slice(&self.block.to_le_bytes());
+        bytes[28 .. 36].copy_from_slice(&result_block.to_le_bytes());
+        bytes[36 .. 44].copy_from_slice(&self.timestamp.to_le_bytes());
+        bytes
+    }
+
+    /// Decodes a record, returning `None` if it is malformed.
+    pub fn from_bytes(bytes: &[u8; TraceRecord::SIZE]) -> Option<TraceRecord> {
+        fn u64_at(bytes: &[u8; TraceRecord::SIZE], offset: usize) -> u64 {
+            u64::from_le_bytes(bytes[offset .. offset + 8].try_into().unwrap())
+        }
+
+        fn layout(size: u64, align_log2: u8) -> Option<alloc::Layout> {
+            let align = 1usize.checked_shl(align_log2.into())?;
+            alloc::Layout::from_size_align(size.try_into().ok()?, align).ok()
+        }
+
+        let op = TraceOp::from_u8(bytes[0])?;
+        let old_layout = layout(u64_at(bytes, 4), bytes[2])?;
+        let layout = layout(u64_at(bytes, 12), bytes[3])?;
+        let result = match bytes[1] {
+            RESULT_NONE => None,
+            RESULT_OK => Some(Ok(u64_at(bytes, 28))),
+            RESULT_FAILED => Some(Err(AllocError)),
+            _ => return None,
+        };
+        if result.is_none() != (op == TraceOp::Deallocate) { return None; }
+        Some(TraceRecord {
+            op,
+            old_layout: if op.has_old_layout() { Some(old_layout) } else { None },
+            layout,
+            block: u64_at(bytes, 20),
+            result,
+            timestamp: u64_at(bytes, 36),
+        })
+    }
+}
+
+pub trait TraceSink {
+    /// Returns current time in nanoseconds since an arbitrary moment.
+    fn timestamp(&self) -> u64;
+
+    fn write(&self, record: &[u8; TraceRecord::SIZE]);
+}
+
+impl<'a, T: TraceSink + ?Sized> TraceSink for &'a T {
+    fn timestamp(&self) -> u64 {
+        (*self).timestamp()
+    }
+
+    fn write(&self, record: &[u8; TraceRecord::SIZE]) {
+        (*self).write(record)
+    }
+}
+
+/// Writes trace records into a file descriptor,
+/// using the monotonic clock for timestamps.
+#[cfg(all(not(target_os="dos"), not(windows)))]
+#[derive(Debug, Copy, Clone)]
+pub struct FdTraceSink(pub libc::c_int);
+
+#[cfg(all(not(target_os="dos"), not(windows)))]
+impl TraceSink for FdTraceSink {
+    fn timestamp(&self) -> u64 {
+        let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &raw mut time); }
+        (time.tv_sec as u64).wrapping_mul(1_000_000_000).wrapping_add(time.tv_nsec as u64)
+    }
+
+    fn write(&self, record: &[u8; TraceRecord::SIZE]) {
+        let mut bytes = &record[..];
+        while !bytes.is_empty() {
+            let written = unsafe { libc::write(self.0, bytes.as_ptr() as _, bytes.len()) };
+            if written <= 0 { break; }
+            bytes = &bytes[written as usize ..];
+        }
+    }
+}
+
+fn block_id(ptr: NonNull<u8>) -> u64 {
+    ptr.as_ptr() as usize as u64
+}
+
+/// Writes a binary trace of every operation into the `S` sink.
+///
+/// Block ids are block addresses.
+pub struct Tracing<A: Allocator, S: TraceSink>(pub A, pub S);
+
+unsafe impl<A: NonUnwinding, S: TraceSink> NonUnwinding for Tracing<A, S> { }
+
+unsafe impl<A: Fallbackable, S: TraceSink> Fallbackable for Tracing<A, S> {
+    unsafe fn has_allocated(&self, ptr: NonNull<u8>, layout: alloc::Layout) -> bool {
+        self.0.has_allocated(ptr, layout)
+    }
+
+    fn allows_fallback(&self, layout: alloc::Layout) -> bool {
+        self.0.allows_fallback(layout)
+    }
+}
+
+impl<A: Allocator, S: TraceSink> Tracing<A, S> {
+    fn trace(
+        &self,
+        op: TraceOp,
+        ptr: Option<NonNull<u8>>,
+        old_layout: Option<alloc::Layout>,
+        layout: alloc::Layout,
+        timestamp: u64,
+        result: Result<NonNull<[u8]>, AllocError>,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.1.write(&TraceRecord {
+            op,
+            old_layout,
+            layout,
+            block: ptr.map_or(0, block_id),
+            result: Some(result.map(|x| block_id(x.as_non_null_ptr()))),
+            timestamp,
+        }.to_bytes());
+        result
+    }
+}
+
+unsafe impl<A: Allocator, S: TraceSink> Allocator for Tracing<A, S> {
+    fn allocate(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let timestamp = self.1.timestamp();
+        self.trace(TraceOp::Allocate, None, None, layout, timestamp, self.0.allocate(layout))
+    }
+
+    fn allocate_zeroed(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let timestamp = self.1.timestamp();
+        self.trace(TraceOp::AllocateZeroed, None, None, layout, timestamp, self.0.allocate_zeroed(layout))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: alloc::Layout) {
+        let timestamp = self.1.timestamp();
+        self.0.deallocate(ptr, layout);
+        self.1.write(&TraceRecord {
+            op: TraceOp::Deallocate,
+            old_layout: None,
+            layout,
+            block: block_id(ptr),
+            result: None,
+            timestamp,
+        }.to_bytes());
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let timestamp = self.1.timestamp();
+        let result = self.0.grow(ptr, old_layout, new_layout);
+        self.trace(TraceOp::Grow, Some(ptr), Some(old_layout), new_layout, timestamp, result)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let timestamp = self.1.timestamp();
+        let result = self.0.grow_zeroed(ptr, old_layout, new_layout);
+        self.trace(TraceOp::GrowZeroed, Some(ptr), Some(old_layout), new_layout, timestamp, result)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let timestamp = self.1.timestamp();
+        let result = self.0.shrink(ptr, old_layout, new_layout);
+        self.trace(TraceOp::Shrink, Some(ptr), Some(old_layout), new_layout, timestamp, result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use crate::System;
+    use crate::tracing::*;
+    use core::cell::{Cell, RefCell};
+    use std::vec::Vec;
+
+    fn layout(size: usize, align: usize) -> alloc::Layout {
+        alloc::Layout::from_size_align(size, align).unwrap()
+    }
+
+    fn records() -> [TraceRecord; 8] {
+        let record = |op, old_layout, block, result| TraceRecord {
+            op,
+            old_layout,
+            layout: layout(48, 16),
+            block,
+            result,
+            timestamp: 0x0102_0304_0506_0708,
+        };
+        [
+            record(TraceOp::Allocate, None, 0, Some(Ok(0x1000))),
+            record(TraceOp::Allocate, None, 0, Some(Err(AllocError))),
+            record(TraceOp::AllocateZeroed, None, 0, Some(Ok(0x2000))),
+            record(TraceOp::Deallocate, None, 0x1000, None),
+            record(TraceOp::Grow, Some(layout(8, 8)), 0x2000, Some(Ok(0x3000))),
+            record(TraceOp::GrowZeroed, Some(layout(16, 4096)), 0x3000, Some(Err(AllocError))),
+            record(TraceOp::Shrink, Some(layout(64, 1)), 0x3000, Some(Ok(0x3000))),
+            record(TraceOp::Shrink, Some(layout(64, 1)), 0x3000, Some(Err(AllocError))),
+        ]
+    }
+
+    #[test]
+    fn records_round_trip() {
+        for record in records() {
+            assert_eq!(TraceRecord::from_bytes(&record.to_bytes()), Some(record));
+        }
+    }
+
+    #[test]
+    fn encodes_documented_layout() {
+        let bytes = records()[4].to_bytes();
+        assert_eq!(&bytes[.. 4], &[TraceOp::Grow as u8, RESULT_OK, 3, 4]);
+        assert_eq!(&bytes[4 .. 12], &8u64.to_le_bytes());
+        assert_eq!(&bytes[12 .. 20], &48u64.to_le_bytes());
+        assert_eq!(&bytes[20 .. 28], &0x2000u64.to_le_bytes());
+        assert_eq!(&bytes[28 .. 36], &0x3000u64.to_le_bytes());
+        assert_eq!(&bytes[36 .. 44], &0x0102_0304_0506_0708u64.to_le_bytes());
+    }
+
+    #[test]
+    fn rejects_malformed_records() {
+        let valid = records()[0].to_bytes();
+        let malformed = |f: fn(&mut [u8; TraceRecord::SIZE])| {
+            let mut bytes = valid;
+            f(&mut bytes);
+            TraceRecord::from_bytes(&bytes)
+        };
+        assert!(TraceRecord::from_bytes(&valid).is_some());
+        assert_eq!(malformed(|bytes| bytes[0] = 6), None);
+        assert_eq!(malformed(|bytes| bytes[0] = u8::MAX), None);
+        assert_eq!(malformed(|bytes| bytes[1] = 3), None);
+        assert_eq!(malformed(|bytes| bytes[1] = RESULT_NONE), None);
+        assert_eq!(malformed(|bytes| bytes[0] = TraceOp::Deallocate as u8), None);
+        assert_eq!(malformed(|bytes| bytes[3] = 64), None);
+        assert_eq!(malformed(|bytes| bytes[12 .. 20].copy_from_slice(&u64::MAX.to_le_bytes())), None);
+        assert!(<&[u8; TraceRecord::SIZE]>::try_from(&valid[.. TraceRecord::SIZE - 1]).is_err());
+    }
+
+    #[derive(Default)]
+    struct VecSink {
+        time: Cell<u64>,
+        records: RefCell<Vec<TraceRecord>>,
+    }
+
+    impl TraceSink for VecSink {
+        fn timestamp(&self) -> u64 {
+            self.time.set(self.time.get() + 1);
+            self.time.get()
+        }
+
+        fn write(&self, record: &[u8; TraceRecord::SIZE]) {
+            self.records.borrow_mut().push(TraceRecord::from_bytes(record).unwrap());
+        }
+    }
+
+    #[test]
+    fn traces_every_operation() {
+        let tracing = Tracing(System, VecSink::default());
+        let small = layout(16, 8);
+        let large = layout(64, 8);
+        let a = tracing.allocate(small).unwrap().as_non_null_ptr();
+        let b = tracing.allocate_zeroed(small).unwrap().as_non_null_ptr();
+        let c = unsafe { tracing.grow(a, small, large) }.unwrap().as_non_null_ptr();
+        let d = unsafe { tracing.grow_zeroed(b, small, large) }.unwrap().as_non_null_ptr();
+        let e = unsafe { tracing.shrink(c, large, small) }.unwrap().as_non_null_ptr();
+        assert!(tracing.allocate(layout(isize::MAX as usize - 7, 8)).is_err());
+        unsafe { tracing.deallocate(d, large); }
+        unsafe { tracing.deallocate(e, small); }
+        let id = |ptr: NonNull<u8>| ptr.as_ptr() as usize as u64;
+        let expected = [
+            (TraceOp::Allocate, None, small, 0, Some(Ok(id(a)))),
+            (TraceOp::AllocateZeroed, None, small, 0, Some(Ok(id(b)))),
+            (TraceOp::Grow, Some(small), large, id(a), Some(Ok(id(c)))),
+            (TraceOp::GrowZeroed, Some(small), large, id(b), Some(Ok(id(d)))),
+            (TraceOp::Shrink, Some(large), small, id(c), Some(Ok(id(e)))),
+            (TraceOp::Allocate, None, layout(isize::MAX as usize - 7, 8), 0, Some(Err(AllocError))),
+            (TraceOp::Deallocate, None, large, id(d), None),
+            (TraceOp::Deallocate, None, small, id(e), None),
+        ];
+        let records = tracing.1.records.borrow();
+        assert_eq!(records.len(), expected.len());
+        for (i, (record, (op, old_layout, layout, block, result))) in records.iter().zip(expected).enumerate() {
+            assert_eq!(*record, TraceRecord { op, old_layout, layout, block, result, timestamp: i as u64 + 1 });
+        }
+    }
+}
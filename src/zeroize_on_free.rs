@@ -0,0 +1,159 @@
+use crate::base::*;
+use core::alloc::{self, AllocError, Allocator};
+use core::cmp::min;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{Ordering, compiler_fence};
+
+unsafe fn zeroize(ptr: NonNull<u8>, len: usize) {
+    for i in 0 .. len {
+        ptr.add(i).write_volatile(0);
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Wipes blocks before releasing them to the base allocator.
+///
+/// Returned blocks have exactly requested sizes, so all bytes, which could be written, are known.
+///
+/// Growing and shrinking always move a block and wipe the old one,
+/// because the base allocator could release the old block without wiping.
+/// A block moved out by [`Fallbacked`](crate::fallbacked::Fallbacked) is wiped too,
+/// because it is released with `deallocate`.
+pub struct ZeroizeOnFree<A: Allocator>(pub A);
+
+unsafe impl<A: NonUnwinding> NonUnwinding for ZeroizeOnFree<A> { }
+
+impl<A: Allocator> ZeroizeOnFree<A> {
+    unsafe fn move_block(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout,
+        zeroed: bool,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let block = if zeroed { self.0.allocate_zeroed(new_layout)? } else { self.0.allocate(new_layout)? };
+        ptr::copy_nonoverlapping(ptr.as_ptr(), block.as_mut_ptr(), min(old_layout.size(), new_layout.size()));
+        self.deallocate(ptr, old_layout);
+        Ok(clamp(block, new_layout.size()))
+    }
+}
+
+unsafe impl<A: Fallbackable> Fallbackable for ZeroizeOnFree<A> {
+    unsafe fn has_allocated(&self, ptr: NonNull<u8>, layout: alloc::Layout) -> bool {
+        self.0.has_allocated(ptr, layout)
+    }
+
+    fn allows_fallback(&self, layout: alloc::Layout) -> bool {
+        self.0.allows_fallback(layout)
+    }
+}
+
+unsafe impl<A: Allocator> Allocator for ZeroizeOnFree<A> {
+    fn allocate(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        Ok(clamp(self.0.allocate(layout)?, layout.size()))
+    }
+
+    fn allocate_zeroed(&self, layout: alloc::Layout) -> Result<NonNull<[u8]>, AllocError> {
+        Ok(clamp(self.0.allocate_zeroed(layout)?, layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: alloc::Layout) {
+        zeroize(ptr, layout.size());
+        self.0.deallocate(ptr, layout);
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.move_block(ptr, old_layout, new_layout, false)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.move_block(ptr, old_layout, new_layout, true)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: alloc::Layout,
+        new_layout: alloc::Layout
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.move_block(ptr, old_layout, new_layout, false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{System, ZeroizeOnFree};
+    use crate::fallbacked::Fallbacked;
+    use crate::stacked;
+    use core::alloc::{self, Allocator};
+    use core::ptr::NonNull;
+    use core::slice;
+
+    fn layout(size: usize) -> alloc::Layout {
+        alloc::Layout::from_size_align(size, 8).unwrap()
+    }
+
+    /// Reads bytes of a released block, which are still mapped, because the base is a stacked allocator.
+    unsafe fn bytes<'a>(ptr: NonNull<u8>, len: usize) -> &'a [u8] {
+        slice::from_raw_parts(ptr.as_ptr(), len)
+    }
+
+    #[test]
+    fn deallocate_wipes_block() {
+        stacked::with_size::<256, _>(|stacked| unsafe {
+            let zeroize = ZeroizeOnFree(stacked);
+            let block = zeroize.allocate(layout(16)).unwrap();
+            assert_eq!(block.len(), 16);
+            block.as_mut_ptr().write_bytes(0xA5, 16);
+            zeroize.deallocate(block.as_non_null_ptr(), layout(16));
+            assert!(bytes(block.as_non_null_ptr(), 16).iter().all(|&x| x == 0));
+        });
+    }
+
+    #[test]
+    fn grow_and_shrink_wipe_old_block() {
+        stacked::with_size::<256, _>(|stacked| unsafe {
+            let zeroize = ZeroizeOnFree(stacked);
+            let old = zeroize.allocate(layout(16)).unwrap().as_non_null_ptr();
+            old.as_ptr().write_bytes(0xA5, 16);
+            let grown = zeroize.grow(old, layout(16), layout(32)).unwrap();
+            assert_ne!(grown.as_non_null_ptr(), old);
+            assert_eq!(grown.len(), 32);
+            assert!(bytes(old, 16).iter().all(|&x| x == 0));
+            assert!(bytes(grown.as_non_null_ptr(), 16).iter().all(|&x| x == 0xA5));
+            let shrunk = zeroize.shrink(grown.as_non_null_ptr(), layout(32), layout(8)).unwrap();
+            assert_eq!(shrunk.len(), 8);
+            assert!(bytes(grown.as_non_null_ptr(), 32).iter().all(|&x| x == 0));
+            assert!(bytes(shrunk.as_non_null_ptr(), 8).iter().all(|&x| x == 0xA5));
+            let zeroed = zeroize.grow_zeroed(shrunk.as_non_null_ptr(), layout(8), layout(24)).unwrap();
+            assert!(bytes(shrunk.as_non_null_ptr(), 8).iter().all(|&x| x == 0));
+            assert_eq!(bytes(zeroed.as_non_null_ptr(), 24), &[[0xA5; 8], [0; 8], [0; 8]].concat()[..]);
+            zeroize.deallocate(zeroed.as_non_null_ptr(), layout(24));
+            assert!(stacked.try_finish().is_ok());
+        });
+    }
+
+    #[test]
+    fn block_moved_by_fallbacked_is_wiped() {
+        stacked::with_size::<64, _>(|stacked| unsafe {
+            let fallbacked = Fallbacked(ZeroizeOnFree(stacked), System);
+            let old = fallbacked.allocate(layout(32)).unwrap().as_non_null_ptr();
+            old.as_ptr().write_bytes(0xA5, 32);
+            let moved = fallbacked.grow(old, layout(32), layout(128)).unwrap();
+            assert!(bytes(old, 32).iter().all(|&x| x == 0));
+            assert!(bytes(moved.as_non_null_ptr(), 32).iter().all(|&x| x == 0xA5));
+            fallbacked.deallocate(moved.as_non_null_ptr(), layout(128));
+            assert!(stacked.try_finish().is_ok());
+        });
+    }
+}